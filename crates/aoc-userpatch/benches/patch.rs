@@ -0,0 +1,63 @@
+//! Compare writing injections in order to writing them in parallel.
+//!
+//! Run with `cargo bench --features rayon`.
+
+use aoc_userpatch::{load_patch_data, InstallOptions, PatchData, Patcher};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 200;
+
+/// Get the median time that `patcher` takes to patch `exe`.
+fn measure(patcher: &Patcher, exe: &[u8]) -> Duration {
+    let mut output = Vec::with_capacity(patcher.output_size());
+    let mut times: Vec<Duration> = (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            patcher.patch_into(exe, &mut output).unwrap();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[times.len() / 2]
+}
+
+fn compare(name: &str, data: &PatchData, options: &InstallOptions) {
+    let options = options.clone().verify_version(false);
+    let serial = Patcher::with_data(data, &options.clone().parallel(false)).unwrap();
+    let parallel = Patcher::with_data(data, &options).unwrap();
+    let exe = vec![0x90; 0x280000];
+    println!(
+        "{}: serial {:?}, parallel {:?}",
+        name,
+        measure(&serial, &exe),
+        measure(&parallel, &exe)
+    );
+}
+
+/// Build patch data with many large injections that do not overlap, so writing them dominates.
+fn large_patch_data() -> PatchData {
+    let mut archive = String::from("version 1.5\n");
+    let bytes = "90".repeat(4096);
+    for feature in 0..64 {
+        writeln!(archive, "feature \"Feature {}\"", feature).unwrap();
+        for injection in 0..8 {
+            let offset = (feature * 8 + injection) * 0x1800;
+            writeln!(archive, "inject {:#x} {}", offset, bytes).unwrap();
+        }
+    }
+    load_patch_data(archive.as_bytes()).unwrap()
+}
+
+fn main() {
+    compare(
+        "built-in, all optional features",
+        PatchData::builtin(),
+        &InstallOptions::all_optional(),
+    );
+    compare(
+        "512 injections of 4KB",
+        &large_patch_data(),
+        &InstallOptions::bare(),
+    );
+}
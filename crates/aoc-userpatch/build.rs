@@ -79,8 +79,11 @@ enum Patch {
     ///
     /// `padding` bytes are filled with NOPs after the newly inserted JMP.
     Jmp(u32, u32, u32),
-    /// A binary patch, (at_address, bytes)
-    Hex(u32, Vec<u8>),
+    /// A binary patch, (at_address, bytes, description)
+    ///
+    /// Only named hex patches have a description. It is serialized as the annotation of the
+    /// injection, after the bytes.
+    Hex(u32, Vec<u8>, Option<String>),
 }
 
 /// Read a NUL-terminated string from a byte slice.
@@ -125,11 +128,212 @@ fn decode_hex(hexa: &str) -> Vec<u8> {
     bytes
 }
 
+/// Build the annotation of a named hex patch: the bytes it writes, followed by its description.
+///
+/// Runs of whitespace in the description, including line breaks, are collapsed to a single space,
+/// because patch archives store each annotation on a single line.
+fn annotation(patch: &[u8], description: &str) -> String {
+    let bytes = patch
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let description = description.split_whitespace().collect::<Vec<_>>().join(" ");
+    format!("{} ; {}", bytes, description)
+}
+
 /// Check if a string contains only valid hexadecimal characters ([0-9A-Fa-f]).
 fn is_hex_string(string: &str) -> bool {
     string.chars().all(|c| char::is_ascii_hexdigit(&c))
 }
 
+/// Decode the hex string of a patch at an address.
+///
+/// Malformed patches are reported with their address and fail the build, so they never make it
+/// into the generated table.
+fn decode_hex_patch(addr: u32, patch: &str) -> Vec<u8> {
+    assert!(
+        !patch.is_empty() && patch.len() & 1 == 0 && is_hex_string(patch),
+        "malformed hex patch at {:#x}: {:?}",
+        addr,
+        patch
+    );
+    decode_hex(patch)
+}
+
+/// Get a human-readable description of a feature.
+///
+/// The installer does not contain descriptions, so they are maintained here by hand.
+fn describe_feature(name: &str) -> Option<&'static str> {
+    let description = match name {
+        "Pre-patch" => "Core fixes and features of UserPatch that are always installed.",
+        "Widescreen interface style" => {
+            "Use the widescreen interface, which shows more of the map at high resolutions."
+        }
+        "Left-aligned interface style" => {
+            "Use the left-aligned interface, with the command panel on the left."
+        }
+        "Windowed mode support" => "Allow running the game in a window. Does not work in Wine.",
+        "Port forwarding support" => {
+            "Use UPnP to forward the multiplayer ports on the router automatically."
+        }
+        "Darken mini-map red" => "Use a darker red for the red player on the mini-map.",
+        "Darken mini-map purple" => "Use a darker purple for the purple player on the mini-map.",
+        "Darken mini-map grey" => "Use a darker grey for the grey player on the mini-map.",
+        "Population caps to 1000" => "Allow population caps of up to 1000 instead of 200.",
+        "Snow/ice terrain removal" => "Replace snow and ice terrains with grass.",
+        "Enable water animation" => "Animate water terrain.",
+        "Precision scrolling system" => "Scroll the map by pixels instead of by half tiles.",
+        "Shift group appending" => {
+            "Hold shift while assigning a group hotkey to add units to the group."
+        }
+        "Keydown object hotkeys" => {
+            "Trigger object hotkeys when a key is pressed instead of when it is released."
+        }
+        "New save filename format" => {
+            "Use the new, more descriptive file name format for saved games."
+        }
+        "Multiple building queue" => "Queue units in multiple selected buildings at once.",
+        "Original patrol default" => "Use the original patrol delay.",
+        "Disable water movement" => "Do not animate the movement of water terrain.",
+        "Disable weather system" => "Do not show rain and snow effects.",
+        "Disable custom terrains" => "Do not load custom terrains from scenarios and ZR@ maps.",
+        "Disable terrain underwater" => "Do not show terrain underneath shallow water.",
+        "Numeric age display" => "Show ages as numbers instead of names in the score display.",
+        "Touch screen control" => "Handle touch screen input events.",
+        "Store Sx spec addresses" => "Store the addresses of spectated games.",
+        "Custom normal mouse" => "Use the custom normal mouse cursor.",
+        "Delink from system volume" => {
+            "Control the in-game volume separately from the system volume."
+        }
+        "Alternate chat box for wine" => {
+            "Use a chat box implementation that does not flicker in Wine."
+        }
+        "Lower quality environment" => {
+            "Use lower quality environment graphics for better performance."
+        }
+        "Restore 20fps for single player" => {
+            "Restore the original 20fps refresh rate in single player games."
+        }
+        "Disable extended hotkeys" => "Disable the additional hotkeys added by UserPatch.",
+        "Force new gameplay features" => {
+            "Enable the new gameplay features of UserPatch in all games."
+        }
+        "Ore resource amount display" => "Show the ore resource in the resources bar.",
+        "Disable multiplayer anti-cheat" => "Disable the multiplayer anti-cheat measures.",
+        "Default to background mode" => {
+            "Keep running the game in the background when switching to another window."
+        }
+        "Windowed fullscreen mode" => "Run the game in a borderless window that covers the screen.",
+        "Multiplayer single player speed" => "Run single player games at multiplayer speed.",
+        "Rms and Scx debug logging" => {
+            "Log debug information from random map scripts and scenarios."
+        }
+        "Change statistics font style" => "Use a different font style for the statistics display.",
+        "Background audio playback" => "Keep playing audio while the game is in the background.",
+        "Disable civilian attack switch" => "Disable the civilian attack switch.",
+        "Handle small farm selections" => "Handle selecting farms by their small 2x2 area.",
+        "Show rec/spec research events" => {
+            "Show research events when watching recorded or spectated games."
+        }
+        "Show rec/spec market events" => {
+            "Show market events when watching recorded or spectated games."
+        }
+        "Disable rec/spec score stats" => {
+            "Do not show score statistics when watching recorded or spectated games."
+        }
+        "Hidden civilization selection" => {
+            "Hide the civilizations that players select in the multiplayer lobby."
+        }
+        "Allow spectators by default" => "Allow spectators in multiplayer games by default.",
+        _ => return None,
+    };
+    Some(description)
+}
+
+/// Get the name of the `FeatureCategory` variant for a feature.
+fn categorize_feature(name: &str) -> &'static str {
+    match name {
+        "Pre-patch" => "Core",
+        "Widescreen interface style"
+        | "Left-aligned interface style"
+        | "Precision scrolling system"
+        | "Shift group appending"
+        | "Keydown object hotkeys"
+        | "Multiple building queue"
+        | "Numeric age display"
+        | "Touch screen control"
+        | "Custom normal mouse"
+        | "Disable extended hotkeys"
+        | "Ore resource amount display"
+        | "Change statistics font style" => "Interface",
+        "Darken mini-map red"
+        | "Darken mini-map purple"
+        | "Darken mini-map grey"
+        | "Snow/ice terrain removal"
+        | "Enable water animation"
+        | "Disable water movement"
+        | "Disable weather system"
+        | "Disable custom terrains"
+        | "Disable terrain underwater"
+        | "Lower quality environment" => "Graphics",
+        "Population caps to 1000"
+        | "Original patrol default"
+        | "Force new gameplay features"
+        | "Multiplayer single player speed"
+        | "Disable civilian attack switch"
+        | "Handle small farm selections" => "Gameplay",
+        "Port forwarding support"
+        | "Disable multiplayer anti-cheat"
+        | "Hidden civilization selection"
+        | "Allow spectators by default" => "Multiplayer",
+        "Store Sx spec addresses"
+        | "Show rec/spec research events"
+        | "Show rec/spec market events"
+        | "Disable rec/spec score stats" => "Spectating",
+        "Windowed mode support"
+        | "New save filename format"
+        | "Delink from system volume"
+        | "Alternate chat box for wine"
+        | "Restore 20fps for single player"
+        | "Default to background mode"
+        | "Windowed fullscreen mode"
+        | "Rms and Scx debug logging"
+        | "Background audio playback" => "System",
+        _ => "Other",
+    }
+}
+
+/// Get the features that must be enabled for a feature to work.
+///
+/// Like descriptions, these relationships are not in the installer and are maintained by hand.
+fn feature_requirements(name: &str) -> &'static [&'static str] {
+    match name {
+        "Windowed fullscreen mode" => &["Windowed mode support"],
+        _ => &[],
+    }
+}
+
+/// Get the features that cannot be enabled together with a feature.
+///
+/// Each conflict only needs to be listed on one of the two features.
+fn feature_conflicts(name: &str) -> &'static [&'static str] {
+    match name {
+        "Widescreen interface style" => &["Left-aligned interface style"],
+        "Snow/ice terrain removal" => &["Disable custom terrains"],
+        _ => &[],
+    }
+}
+
+/// Format a list of feature names as a `Cow` slice.
+fn feature_list(names: &[&str]) -> String {
+    let names: Vec<_> = names
+        .iter()
+        .map(|name| format!("Cow::Borrowed({:?})", name))
+        .collect();
+    format!("Cow::Borrowed(&[{}])", names.join(", "))
+}
+
 /// Find a list of hex code injections that the UserPatch installer does.
 fn find_injections(exe: &[u8]) -> Result<Vec<Feature>> {
     // Keeps track of 32 bit and 8 bit literal PUSH calls _only_. These are the instructions
@@ -187,8 +391,10 @@ fn find_injections(exe: &[u8]) -> Result<Vec<Feature>> {
                         stack_args.reverse();
                         let patch = read_c_str(exe, stack_args[1] - RDATA_BASE_ADDRESS);
                         let addr = stack_args[0];
-                        assert!(is_hex_string(&patch), "unexpected non-hex string");
-                        push_patch(&mut features, Patch::Hex(addr, decode_hex(&patch)));
+                        push_patch(
+                            &mut features,
+                            Patch::Hex(addr, decode_hex_patch(addr, &patch), None),
+                        );
                     }
                     // CreateBytePatch(address, byte_ptr, byte_len)
                     BYTE_PATCH_ADDRESS => {
@@ -196,7 +402,7 @@ fn find_injections(exe: &[u8]) -> Result<Vec<Feature>> {
                         let start = (stack_args[1] - DATA_BASE_ADDRESS) as usize;
                         let patch = &exe[start..start + stack_args[2] as usize];
                         let addr = stack_args[0];
-                        push_patch(&mut features, Patch::Hex(addr, patch.to_vec()));
+                        push_patch(&mut features, Patch::Hex(addr, patch.to_vec(), None));
                     }
                     // CreateHexPatch("Description of the patch", address, "HEXSTRING")
                     NAMED_HEX_PATCH_ADDRESS => {
@@ -206,8 +412,13 @@ fn find_injections(exe: &[u8]) -> Result<Vec<Feature>> {
                         stack_args.reverse();
                         let patch = read_c_str(exe, stack_args[1] - RDATA_BASE_ADDRESS);
                         let addr = stack_args[0];
-                        assert!(is_hex_string(&patch), "unexpected non-hex string");
-                        push_patch(&mut features, Patch::Hex(addr, decode_hex(&patch)));
+                        let description = Some(latest_string.trim())
+                            .filter(|description| !description.is_empty())
+                            .map(str::to_string);
+                        push_patch(
+                            &mut features,
+                            Patch::Hex(addr, decode_hex_patch(addr, &patch), description),
+                        );
                     }
                     // CreateJmpOrCallPatch(address, target, padding, is_jmp)
                     JMP_PATCH_ADDRESS => {
@@ -315,7 +526,7 @@ fn main() -> Result<()> {
         let bytes = to_addr.overflowing_sub(addr + 5).0.to_le_bytes();
         write!(
             f,
-            "    Injection({:#x}, &[{:#04X}, {:#04X}, {:#04X}, {:#04X}, {:#04X}",
+            "    Injection({:#x}, Cow::Borrowed(&[{:#04X}, {:#04X}, {:#04X}, {:#04X}, {:#04X}",
             addr, instr, bytes[0], bytes[1], bytes[2], bytes[3],
         )?;
         // Fill the rest up with NOPs, /((0x66){0,3} 0x90)*/
@@ -328,7 +539,7 @@ fn main() -> Result<()> {
             write!(f, ", 0x90")?;
             padding = padding.saturating_sub(4);
         }
-        writeln!(f, "]),\n")?;
+        writeln!(f, "]), None, None),\n")?;
         Ok(())
     }
 
@@ -337,16 +548,23 @@ fn main() -> Result<()> {
         for inject in &feature.patches {
             match inject {
                 Patch::Header(name) => writeln!(&mut patch_group, "    // {}", name)?,
-                Patch::Hex(addr, patch) => {
+                Patch::Hex(addr, patch, description) => {
                     write!(
                         &mut patch_group,
-                        "    Injection({:#x}, &[{:#04X}",
+                        "    Injection({:#x}, Cow::Borrowed(&[{:#04X}",
                         addr, patch[0]
                     )?;
                     for b in patch.iter().skip(1) {
                         write!(&mut patch_group, ", {:#04X}", b)?;
                     }
-                    writeln!(&mut patch_group, "]),")?;
+                    match description {
+                        Some(description) => writeln!(
+                            &mut patch_group,
+                            "]), None, Some(Cow::Borrowed({:?}))),",
+                            annotation(patch, description)
+                        )?,
+                        None => writeln!(&mut patch_group, "]), None, None),")?,
+                    }
                 }
                 Patch::Call(addr, to_addr, padding) => {
                     serialize_jmp_or_call(&mut patch_group, ASM_CALL, *addr, *to_addr, *padding)?
@@ -359,10 +577,17 @@ fn main() -> Result<()> {
         patch_definitions.push(patch_group);
         writeln!(
             &mut features_definition,
-            "    Feature {{ name: \"{}\", optional: {:?}, affects_sync: {:?}, patches: &PATCH_GROUP_{}, enabled: {:?} }},",
+            "    Feature {{ name: Cow::Borrowed(\"{}\"), description: {}, category: FeatureCategory::{}, optional: {:?}, affects_sync: {:?}, requires: {}, conflicts_with: {}, patches: Cow::Borrowed(&PATCH_GROUP_{}), enabled: {:?} }},",
             feature.name,
+            match describe_feature(&feature.name) {
+                Some(description) => format!("Some(Cow::Borrowed({:?}))", description),
+                None => "None".to_string(),
+            },
+            categorize_feature(&feature.name),
             feature.optional,
             feature.affects_sync,
+            feature_list(feature_requirements(&feature.name)),
+            feature_list(feature_conflicts(&feature.name)),
             patch_definitions.len() - 1,
             feature.enabled_by_default
         )?;
@@ -0,0 +1,161 @@
+use crate::patch::PreparedFeature;
+use crate::{InstallOptions, PatchError, Patcher};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// A run of bytes that installing UserPatch writes to the executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchChunk {
+    /// File offset of the first byte.
+    pub offset: usize,
+    /// The bytes of the patched executable at `offset`.
+    pub bytes: Vec<u8>,
+}
+
+/// Get the bytes that installing UserPatch with the given options writes, as a list of chunks.
+///
+/// The chunks are sorted by offset and do not overlap. Together they are much smaller than a
+/// patched executable, so they can be sent to clients that already have the original executable,
+/// which can then reconstruct the patched executable with [`apply_patch_chunks`].
+pub fn produce_patch(options: &InstallOptions) -> Result<Vec<PatchChunk>, PatchError> {
+    let patcher = Patcher::new(options)?;
+    let mut chunks: Vec<PatchChunk> = merged_ranges(&patcher.features)
+        .into_iter()
+        .map(|range| PatchChunk {
+            offset: range.start,
+            bytes: vec![0; range.len()],
+        })
+        .collect();
+    // Apply the injections in the same order as an install, so later features overwrite earlier
+    // ones in the same way.
+    for injection in patcher
+        .features
+        .iter()
+        .flat_map(|feature| feature.injections.iter())
+    {
+        let index = chunks
+            .iter()
+            .rposition(|chunk| chunk.offset <= injection.offset)
+            .expect("every injection is inside a chunk");
+        let chunk = &mut chunks[index];
+        let start = injection.offset - chunk.offset;
+        chunk.bytes[start..start + injection.bytes.len()].copy_from_slice(&injection.bytes);
+    }
+    Ok(chunks)
+}
+
+/// Get the file offsets of the bytes that installing UserPatch with the given options writes,
+/// without patching anything.
+///
+/// Overlapping and adjacent injections are merged, so the ranges are sorted and disjoint. These are
+/// the ranges of the chunks returned by [`produce_patch`].
+pub fn changed_ranges(options: &InstallOptions) -> Result<Vec<Range<usize>>, PatchError> {
+    Ok(merged_ranges(&Patcher::new(options)?.features))
+}
+
+/// Merge the ranges written by the injections of the features.
+fn merged_ranges(features: &[PreparedFeature]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<(usize, usize)> = features
+        .iter()
+        .flat_map(|feature| feature.injections.iter())
+        .map(|injection| (injection.offset, injection.offset + injection.bytes.len()))
+        .collect();
+    ranges.sort();
+
+    let mut merged: Vec<Range<usize>> = vec![];
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.end => last.end = last.end.max(end),
+            _ => merged.push(start..end),
+        }
+    }
+    merged
+}
+
+/// Reconstruct a patched executable from the original executable and the chunks returned by
+/// [`produce_patch`].
+///
+/// `buffer` is padded with zeroes to `output_size`, and the chunks are written on top. When
+/// `output_size` is the [`Patcher::output_size`] of the options that produced the chunks, the
+/// result is the same as installing with those options.
+pub fn apply_patch_chunks(
+    buffer: &mut Vec<u8>,
+    chunks: &[PatchChunk],
+    output_size: usize,
+) -> Result<(), PatchError> {
+    if buffer.len() > output_size {
+        return Err(PatchError::InputTooLarge {
+            size: buffer.len(),
+            max: output_size,
+        });
+    }
+    // Chunks may come from untrusted input, so the end offset may not fit in a usize.
+    if let Some(chunk) = chunks.iter().find(|chunk| {
+        !matches!(chunk.offset.checked_add(chunk.bytes.len()), Some(end) if end <= output_size)
+    }) {
+        return Err(PatchError::OffsetOutOfBounds {
+            offset: chunk.offset,
+            len: chunk.bytes.len(),
+        });
+    }
+
+    buffer.resize(output_size, 0);
+    for chunk in chunks {
+        buffer[chunk.offset..chunk.offset + chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install_into;
+    use crate::patch::PATCHED_SIZE;
+
+    #[test]
+    fn reconstruct_from_chunks() {
+        let options = InstallOptions::default().verify_version(false);
+        let chunks = produce_patch(&options).unwrap();
+        assert!(chunks
+            .windows(2)
+            .all(|pair| pair[0].offset + pair[0].bytes.len() < pair[1].offset));
+        assert!(chunks.iter().map(|chunk| chunk.bytes.len()).sum::<usize>() < PATCHED_SIZE / 4);
+        assert_eq!(
+            changed_ranges(&options).unwrap(),
+            chunks
+                .iter()
+                .map(|chunk| chunk.offset..chunk.offset + chunk.bytes.len())
+                .collect::<Vec<_>>()
+        );
+
+        let original: Vec<u8> = (0..0x200000).map(|i| (i % 251) as u8).collect();
+        let mut buffer = original.clone();
+        apply_patch_chunks(&mut buffer, &chunks, PATCHED_SIZE).unwrap();
+        assert_eq!(buffer, install_into(&original, &options));
+
+        let required = Patcher::new(&options).unwrap().required_size();
+        let options = options.output_size(required);
+        let mut buffer = original[..required].to_vec();
+        apply_patch_chunks(&mut buffer, &produce_patch(&options).unwrap(), required).unwrap();
+        assert_eq!(buffer, install_into(&original[..required], &options));
+
+        let chunk = PatchChunk {
+            offset: PATCHED_SIZE - 1,
+            bytes: vec![0; 2],
+        };
+        assert!(matches!(
+            apply_patch_chunks(&mut original.clone(), &[chunk], PATCHED_SIZE),
+            Err(PatchError::OffsetOutOfBounds { .. })
+        ));
+        let chunk = PatchChunk {
+            offset: usize::MAX,
+            bytes: vec![0; 2],
+        };
+        assert!(matches!(
+            apply_patch_chunks(&mut original.clone(), &[chunk], PATCHED_SIZE),
+            Err(PatchError::OffsetOutOfBounds { .. })
+        ));
+    }
+}
@@ -0,0 +1,619 @@
+use crate::patch::{annotated_len, Feature, FeatureCategory, Injection, BUILTIN_PATCH_DATA};
+use crate::{InstallOptions, PatchError, Patcher};
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io::{self, BufRead, Write},
+};
+
+/// A set of features and injections for a single UserPatch version.
+///
+/// The data embedded in this crate is available through [`PatchData::builtin`]. Other versions can
+/// be loaded from a patch archive using [`load_patch_data`], and installed with
+/// [`PatchData::patcher`]. To maintain patch data for a modified UserPatch, write the built-in data
+/// to an archive with [`PatchData::write_archive`], edit it, and load it at runtime.
+#[derive(Debug, Clone)]
+pub struct PatchData {
+    pub(crate) version: Cow<'static, str>,
+    pub(crate) features: Cow<'static, [Feature]>,
+}
+
+impl PatchData {
+    /// Get the patch data that is embedded in this crate.
+    ///
+    /// This is the default table: it is used by [`install_into`](crate::install_into),
+    /// [`Patcher::new`] and the other functions that do not take patch data.
+    pub fn builtin() -> &'static PatchData {
+        &BUILTIN_PATCH_DATA
+    }
+
+    /// Get the UserPatch version that this data describes.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Get the features that can be installed.
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    /// Describe all features in this patch data.
+    pub fn catalog(&self) -> Vec<FeatureCatalogEntry> {
+        self.features
+            .iter()
+            .map(|feature| FeatureCatalogEntry {
+                id: feature.id(),
+                name: feature.name.to_string(),
+                description: feature.description.as_ref().map(|d| d.to_string()),
+                category: feature.category,
+                requires: feature
+                    .requires
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+                conflicts_with: feature
+                    .conflicts_with
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+                optional: feature.optional,
+                affects_sync: feature.affects_sync,
+                enabled_by_default: feature.enabled(),
+            })
+            .collect()
+    }
+
+    /// Prepare to install this patch data with the given options.
+    pub fn patcher(&self, options: &InstallOptions) -> Result<Patcher, PatchError> {
+        Patcher::with_data(self, options)
+    }
+
+    /// Write this patch data as a patch archive with a single version, in the format that
+    /// [`load_patch_archive`] reads.
+    ///
+    /// Every value is written on a single line, so this fails with
+    /// [`io::ErrorKind::InvalidInput`] if a name, description or annotation contains a line break,
+    /// or if a description or annotation starts or ends with whitespace, which would be lost.
+    pub fn write_archive(&self, mut writer: impl Write) -> io::Result<()> {
+        for feature in self.features.iter() {
+            check_archive_value(&feature.name, false)?;
+            for name in feature.requires.iter().chain(feature.conflicts_with.iter()) {
+                check_archive_value(name, false)?;
+            }
+            if let Some(description) = &feature.description {
+                check_archive_value(description, true)?;
+            }
+            for Injection(_, _, _, annotation) in feature.patches.iter() {
+                if let Some(annotation) = annotation {
+                    check_archive_value(annotation, true)?;
+                }
+            }
+        }
+
+        writeln!(writer, "version {}", self.version)?;
+        for feature in self.features.iter() {
+            write!(writer, "feature ")?;
+            if feature.optional {
+                write!(writer, "optional ")?;
+            }
+            if feature.affects_sync {
+                write!(writer, "affects_sync ")?;
+            }
+            if feature.optional && feature.enabled {
+                write!(writer, "enabled ")?;
+            }
+            writeln!(writer, "\"{}\"", feature.name)?;
+            if let Some(description) = &feature.description {
+                writeln!(writer, "description {}", description)?;
+            }
+            writeln!(writer, "category {}", feature.category.name())?;
+            for name in feature.requires.iter() {
+                writeln!(writer, "requires \"{}\"", name)?;
+            }
+            for name in feature.conflicts_with.iter() {
+                writeln!(writer, "conflicts \"{}\"", name)?;
+            }
+            for Injection(addr, bytes, expected, annotation) in feature.patches.iter() {
+                write!(writer, "inject {:#x} {}", addr, encode_hex(bytes))?;
+                if let Some(expected) = expected {
+                    write!(writer, " expect {}", encode_hex(expected))?;
+                }
+                writeln!(writer)?;
+                if let Some(annotation) = annotation {
+                    writeln!(writer, "annotate {}", annotation)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Install this patch data into a buffer containing a 1.0c executable.
+    pub fn install_into(&self, exe_buffer: &[u8], options: &InstallOptions) -> Vec<u8> {
+        self.patcher(options)
+            .and_then(|patcher| patcher.patch(exe_buffer))
+            .unwrap_or_else(|err| panic!("could not install UserPatch: {}", err))
+    }
+}
+
+/// Install patch data from a table into a buffer containing a 1.0c executable.
+///
+/// This is the same as [`install_into`](crate::install_into), but takes the table to install
+/// instead of using [`PatchData::builtin`], for example one loaded with [`load_patch_data`].
+///
+/// # Panics
+///
+/// Panics if the executable cannot be patched. Use [`PatchData::patcher`] to handle errors.
+pub fn install_into_with_table(
+    exe_buffer: &[u8],
+    options: &InstallOptions,
+    table: &PatchData,
+) -> Vec<u8> {
+    table.install_into(exe_buffer, options)
+}
+
+/// Check that a value can be written to a patch archive and read back unchanged. Values that are
+/// not quoted lose surrounding whitespace when they are read.
+fn check_archive_value(value: &str, trimmed: bool) -> io::Result<()> {
+    if value.contains(&['\n', '\r'][..]) || (trimmed && value.trim() != value) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot write {:?} to a patch archive", value),
+        ));
+    }
+    Ok(())
+}
+
+/// Describes a single feature, for documentation and tooling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureCatalogEntry {
+    /// Stable identifier of the feature, see [`Feature::id`].
+    pub id: String,
+    /// Display name of the feature.
+    pub name: String,
+    /// Description of the feature, if the patch data contains one.
+    pub description: Option<String>,
+    /// The area of the game that the feature changes.
+    pub category: FeatureCategory,
+    /// Names of the features that must be enabled for this feature to work.
+    pub requires: Vec<String>,
+    /// Names of the features that cannot be enabled together with this feature.
+    pub conflicts_with: Vec<String>,
+    /// Can the feature be turned off?
+    pub optional: bool,
+    /// Does the feature affect game sync?
+    pub affects_sync: bool,
+    /// Is the feature enabled when it is not configured?
+    pub enabled_by_default: bool,
+}
+
+/// Describe all features in the built-in patch data.
+pub fn feature_catalog() -> Vec<FeatureCatalogEntry> {
+    PatchData::builtin().catalog()
+}
+
+/// Failed to load patch data.
+#[derive(Debug)]
+pub enum LoadPatchDataError {
+    /// Could not read the archive.
+    Io(io::Error),
+    /// A line in the archive could not be parsed.
+    Syntax {
+        /// Line number, starting at 1.
+        line: usize,
+        /// What was wrong with the line.
+        message: String,
+    },
+    /// The archive does not contain any versions.
+    Empty,
+    /// The archive does not contain the requested version.
+    MissingVersion(String),
+}
+
+impl Display for LoadPatchDataError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadPatchDataError::Io(err) => write!(f, "Could not read patch data: {}", err),
+            LoadPatchDataError::Syntax { line, message } => {
+                write!(f, "Invalid patch data on line {}: {}", line, message)
+            }
+            LoadPatchDataError::Empty => write!(f, "Patch data archive is empty"),
+            LoadPatchDataError::MissingVersion(version) => {
+                write!(f, "Patch data archive does not contain version {}", version)
+            }
+        }
+    }
+}
+
+impl Error for LoadPatchDataError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadPatchDataError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadPatchDataError {
+    fn from(err: io::Error) -> Self {
+        LoadPatchDataError::Io(err)
+    }
+}
+
+/// Encode a list of byte values as a hexadecimal string.
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode a hexadecimal string to a list of byte values.
+fn decode_hex(hexa: &str) -> Option<Vec<u8>> {
+    hexa.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => {
+                let high = char::from(*high).to_digit(16)?;
+                let low = char::from(*low).to_digit(16)?;
+                Some((high * 16 + low) as u8)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parse a feature name in double quotes.
+fn parse_quoted(input: &str) -> Option<&str> {
+    let input = input.trim();
+    if input.len() >= 2 && input.starts_with('"') && input.ends_with('"') {
+        Some(&input[1..input.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// Load all versions from a patch archive.
+///
+/// A patch archive is a text file that lists UserPatch versions, their features, and the
+/// injections that belong to each feature. Versions are listed from oldest to newest:
+///
+/// ```text
+/// # Comments start with a hash.
+/// version 1.5
+/// feature optional affects_sync enabled "Feature name"
+/// description An optional description of the feature.
+/// category graphics
+/// requires "Other feature"
+/// conflicts "Another feature"
+/// inject 0x7a5000 9090
+/// inject 0x7a5010 c3 expect 55
+/// annotate c3 ; ret
+/// ```
+///
+/// Each `feature` line may contain the `optional`, `affects_sync` and `enabled` flags, followed by
+/// the name of the feature. Non-optional features are always enabled. A `category` line sets the
+/// [`FeatureCategory`] of the feature, using its lowercase name. `requires` and `conflicts` lines
+/// list features that must or must not be enabled together with the feature, see
+/// [`crate::validate`]. An `inject` line may end with the original bytes that are expected at the
+/// offset, which are checked when installing with strict context checking enabled. An `annotate`
+/// line describes the preceding injection, see
+/// [`InjectionInfo::annotation`](crate::InjectionInfo::annotation); the bytes it lists must match
+/// the length of the injection.
+pub fn load_patch_archive(reader: impl BufRead) -> Result<Vec<PatchData>, LoadPatchDataError> {
+    let mut versions: Vec<PatchData> = vec![];
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        let syntax_error = |message: &str| LoadPatchDataError::Syntax {
+            line: index + 1,
+            message: message.to_string(),
+        };
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, rest) = match line.find(char::is_whitespace) {
+            Some(split) => (&line[..split], line[split..].trim()),
+            None => (line, ""),
+        };
+        match keyword {
+            "version" => {
+                if rest.is_empty() {
+                    return Err(syntax_error("expected a version number"));
+                }
+                versions.push(PatchData {
+                    version: Cow::Owned(rest.to_string()),
+                    features: Cow::Owned(vec![]),
+                });
+            }
+            "feature" => {
+                let data = versions
+                    .last_mut()
+                    .ok_or_else(|| syntax_error("feature outside of a version"))?;
+                let name_start = rest
+                    .find('"')
+                    .ok_or_else(|| syntax_error("expected a quoted feature name"))?;
+                let name = parse_quoted(&rest[name_start..])
+                    .ok_or_else(|| syntax_error("expected a quoted feature name"))?;
+                let mut feature = Feature {
+                    name: Cow::Owned(name.to_string()),
+                    description: None,
+                    category: FeatureCategory::Other,
+                    optional: false,
+                    affects_sync: false,
+                    requires: Cow::Owned(vec![]),
+                    conflicts_with: Cow::Owned(vec![]),
+                    enabled: false,
+                    patches: Cow::Owned(vec![]),
+                };
+                for flag in rest[..name_start].split_whitespace() {
+                    match flag {
+                        "optional" => feature.optional = true,
+                        "affects_sync" => feature.affects_sync = true,
+                        "enabled" => feature.enabled = true,
+                        _ => return Err(syntax_error("unknown feature flag")),
+                    }
+                }
+                if !feature.optional {
+                    feature.enabled = true;
+                }
+                data.features.to_mut().push(feature);
+            }
+            "description" => {
+                let feature = versions
+                    .last_mut()
+                    .and_then(|data| data.features.to_mut().last_mut())
+                    .ok_or_else(|| syntax_error("description outside of a feature"))?;
+                feature.description = Some(Cow::Owned(rest.to_string()));
+            }
+            "category" => {
+                let feature = versions
+                    .last_mut()
+                    .and_then(|data| data.features.to_mut().last_mut())
+                    .ok_or_else(|| syntax_error("category outside of a feature"))?;
+                feature.category = FeatureCategory::from_name(rest)
+                    .ok_or_else(|| syntax_error("unknown feature category"))?;
+            }
+            "requires" | "conflicts" => {
+                let feature = versions
+                    .last_mut()
+                    .and_then(|data| data.features.to_mut().last_mut())
+                    .ok_or_else(|| syntax_error("relationship outside of a feature"))?;
+                let name = parse_quoted(rest)
+                    .ok_or_else(|| syntax_error("expected a quoted feature name"))?;
+                let list = if keyword == "requires" {
+                    &mut feature.requires
+                } else {
+                    &mut feature.conflicts_with
+                };
+                list.to_mut().push(Cow::Owned(name.to_string()));
+            }
+            "inject" => {
+                let feature = versions
+                    .last_mut()
+                    .and_then(|data| data.features.to_mut().last_mut())
+                    .ok_or_else(|| syntax_error("injection outside of a feature"))?;
+                let mut parts = rest.split_whitespace();
+                let addr = parts
+                    .next()
+                    .filter(|addr| addr.starts_with("0x"))
+                    .and_then(|addr| u32::from_str_radix(&addr[2..], 16).ok())
+                    .ok_or_else(|| syntax_error("expected a hexadecimal address"))?;
+                let bytes = parts
+                    .next()
+                    .and_then(decode_hex)
+                    .ok_or_else(|| syntax_error("expected hexadecimal bytes"))?;
+                let expected = match parts.next() {
+                    Some("expect") => Some(
+                        parts
+                            .next()
+                            .and_then(decode_hex)
+                            .ok_or_else(|| syntax_error("expected hexadecimal bytes"))?,
+                    ),
+                    Some(_) => return Err(syntax_error("unexpected data after injection")),
+                    None => None,
+                };
+                if parts.next().is_some() {
+                    return Err(syntax_error("unexpected data after injection"));
+                }
+                feature.patches.to_mut().push(Injection(
+                    addr,
+                    Cow::Owned(bytes),
+                    expected.map(Cow::Owned),
+                    None,
+                ));
+            }
+            "annotate" => {
+                let injection = versions
+                    .last_mut()
+                    .and_then(|data| data.features.to_mut().last_mut())
+                    .and_then(|feature| feature.patches.to_mut().last_mut())
+                    .ok_or_else(|| syntax_error("annotation outside of an injection"))?;
+                match annotated_len(rest) {
+                    Some(len) if len == injection.1.len() => (),
+                    Some(_) => return Err(syntax_error("annotation length does not match")),
+                    None => return Err(syntax_error("annotation must start with bytes")),
+                }
+                injection.3 = Some(Cow::Owned(rest.to_string()));
+            }
+            _ => return Err(syntax_error("unknown keyword")),
+        }
+    }
+    Ok(versions)
+}
+
+/// Load the newest version from a patch archive.
+///
+/// See [`load_patch_archive`] for the archive format.
+pub fn load_patch_data(reader: impl BufRead) -> Result<PatchData, LoadPatchDataError> {
+    load_patch_archive(reader)?
+        .pop()
+        .ok_or(LoadPatchDataError::Empty)
+}
+
+/// Load a specific version from a patch archive.
+///
+/// See [`load_patch_archive`] for the archive format.
+pub fn load_patch_data_version(
+    reader: impl BufRead,
+    version: &str,
+) -> Result<PatchData, LoadPatchDataError> {
+    load_patch_archive(reader)?
+        .into_iter()
+        .find(|data| data.version() == version)
+        .ok_or_else(|| LoadPatchDataError::MissingVersion(version.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARCHIVE: &str = r#"
+# Test archive
+version 1.4
+feature "Pre-patch"
+inject 0x400000 90
+
+version 1.5
+feature "Pre-patch"
+inject 0x400000 9090
+feature optional enabled "Some new feature"
+description Does something new.
+category gameplay
+requires "Pre-patch"
+conflicts "Removed feature"
+inject 0x400010 c3 expect 00
+annotate c3 ; ret
+"#;
+
+    #[test]
+    fn load_versions() {
+        let data = load_patch_data(ARCHIVE.as_bytes()).unwrap();
+        assert_eq!(data.version(), "1.5");
+        assert_eq!(data.features().len(), 2);
+        let feature = &data.features()[1];
+        assert_eq!(feature.name, "Some new feature");
+        assert_eq!(feature.description.as_deref(), Some("Does something new."));
+        assert_eq!(feature.category, FeatureCategory::Gameplay);
+        assert_eq!(&feature.requires[..], &["Pre-patch"]);
+        assert_eq!(&feature.conflicts_with[..], &["Removed feature"]);
+        assert_eq!(data.features()[0].category, FeatureCategory::Other);
+        assert!(feature.optional);
+        assert!(feature.enabled());
+
+        let patched =
+            data.install_into(&[0u8; 0x20], &InstallOptions::bare().verify_version(false));
+        assert_eq!(&patched[0..2], &[0x90, 0x90]);
+        assert_eq!(patched[0x10], 0xC3);
+
+        let strict = InstallOptions::bare()
+            .verify_version(false)
+            .strict_context(true);
+        assert!(data.patcher(&strict).unwrap().patch(&[0u8; 0x20]).is_ok());
+        assert_eq!(
+            data.patcher(&strict).unwrap().patch(&[1u8; 0x20]),
+            Err(PatchError::ContextMismatch { offset: 0x10 })
+        );
+
+        let old = load_patch_data_version(ARCHIVE.as_bytes(), "1.4").unwrap();
+        assert_eq!(old.features().len(), 1);
+        assert!(load_patch_data_version(ARCHIVE.as_bytes(), "1.6").is_err());
+
+        let catalog = data.catalog();
+        assert_eq!(catalog[0].id, "pre-patch");
+        assert!(!catalog[0].optional);
+        assert_eq!(catalog[1].id, "some-new-feature");
+        assert_eq!(
+            catalog[1].description.as_deref(),
+            Some("Does something new.")
+        );
+        assert!(catalog[1].enabled_by_default);
+
+        let injection = feature.injections().next().unwrap();
+        assert_eq!(injection.address, 0x400010);
+        assert_eq!(injection.expected, Some(&[0u8][..]));
+        assert_eq!(injection.annotation, Some("c3 ; ret"));
+        assert_eq!(injection.annotated_len(), Some(1));
+    }
+
+    #[test]
+    fn write_builtin_archive() {
+        let builtin = PatchData::builtin();
+        let mut archive = vec![];
+        builtin.write_archive(&mut archive).unwrap();
+        let loaded = load_patch_data(&archive[..]).unwrap();
+        assert_eq!(loaded.version(), builtin.version());
+        assert_eq!(loaded.catalog(), builtin.catalog());
+        for (loaded, builtin) in loaded.features().iter().zip(builtin.features()) {
+            assert!(loaded.injections().eq(builtin.injections()));
+        }
+
+        let options = InstallOptions::default().verify_version(false);
+        let exe = vec![0x90; 0x200000];
+        assert!(loaded.install_into(&exe, &options) == builtin.install_into(&exe, &options));
+    }
+
+    #[test]
+    fn install_with_table() {
+        let data = load_patch_data(ARCHIVE.as_bytes()).unwrap();
+        let options = InstallOptions::bare().verify_version(false);
+        let exe = [0u8; 0x20];
+        assert_eq!(
+            install_into_with_table(&exe, &options, &data),
+            data.install_into(&exe, &options)
+        );
+
+        let options = InstallOptions::default().verify_version(false);
+        let exe = vec![0x90; 0x200000];
+        assert!(
+            install_into_with_table(&exe, &options, PatchData::builtin())
+                == crate::install_into(&exe, &options)
+        );
+    }
+
+    #[test]
+    fn write_unusual_values() {
+        let archive = "version 1.5\nfeature \"Say \"hi\"\"\nrequires \"a \"b\"\"\ninject 0x10 90\n";
+        let data = load_patch_data(archive.as_bytes()).unwrap();
+        let mut written = vec![];
+        data.write_archive(&mut written).unwrap();
+        let loaded = load_patch_data(&written[..]).unwrap();
+        assert_eq!(loaded.catalog(), data.catalog());
+        assert_eq!(loaded.features()[0].name, "Say \"hi\"");
+
+        let mut features = data.features().to_vec();
+        features[0].description = Some(Cow::Borrowed("First line\nsecond line"));
+        let data = PatchData {
+            version: Cow::Borrowed("1.5"),
+            features: Cow::Owned(features),
+        };
+        let err = data.write_archive(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut features = data.features().to_vec();
+        features[0].description = None;
+        features[0].patches.to_mut()[0].3 = Some(Cow::Borrowed("90 ; nop "));
+        let data = PatchData {
+            version: Cow::Borrowed("1.5"),
+            features: Cow::Owned(features),
+        };
+        let err = data.write_archive(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reject_bad_lines() {
+        let err = load_patch_data("version 1.5\ninject 0x400000 90".as_bytes()).unwrap_err();
+        assert!(matches!(err, LoadPatchDataError::Syntax { line: 2, .. }));
+        let err = load_patch_data("version 1.5\nfeature \"x\"\ninject 0x400000 9".as_bytes())
+            .unwrap_err();
+        assert!(matches!(err, LoadPatchDataError::Syntax { line: 3, .. }));
+        let err = load_patch_data(
+            "version 1.5\nfeature \"x\"\ninject 0x400000 90\nannotate 90 90 ; nop".as_bytes(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, LoadPatchDataError::Syntax { line: 4, .. }));
+        assert!(matches!(
+            load_patch_data("".as_bytes()),
+            Err(LoadPatchDataError::Empty)
+        ));
+    }
+}
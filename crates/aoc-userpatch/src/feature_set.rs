@@ -0,0 +1,193 @@
+use crate::{enabled_features, get_available_features, Feature, InstallOptions, UnknownFeature};
+
+/// A compact set of optional features, with one bit per optional feature.
+///
+/// Bits are assigned to the optional features of the built-in patch data in the order of
+/// [`get_available_features`], so the first optional feature is bit 0. A set can be stored or sent
+/// as a single `u64` with [`FeatureSet::bits`], and two sets can be compared or diffed cheaply.
+/// The bits are only meaningful between builds of this crate with the same patch data; use
+/// [`crate::SyncProfile`] to check that two players have compatible patch data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FeatureSet(u64);
+
+/// Get the optional features of the built-in patch data, in bit order.
+fn optional_features() -> impl Iterator<Item = &'static Feature> {
+    get_available_features()
+        .iter()
+        .filter(|feature| feature.optional)
+}
+
+/// Get the bit of an optional feature by its name.
+fn feature_bit(name: &str) -> Option<u64> {
+    optional_features()
+        .position(|feature| feature.name == name)
+        .map(|index| 1 << index)
+}
+
+/// Get the bits of all optional features.
+fn all_bits() -> u64 {
+    optional_features().fold(0, |bits, _| bits << 1 | 1)
+}
+
+impl FeatureSet {
+    /// Create a set without any features.
+    pub const fn empty() -> Self {
+        FeatureSet(0)
+    }
+
+    /// Create a set with all optional features.
+    pub fn all() -> Self {
+        FeatureSet(all_bits())
+    }
+
+    /// Create a set from its bits. Returns `None` if a bit does not belong to an optional feature.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        if bits & !all_bits() == 0 {
+            Some(FeatureSet(bits))
+        } else {
+            None
+        }
+    }
+
+    /// Get the bits of the set.
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Check if the set contains no features.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Get the number of features in the set.
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Check if the set contains a feature by its name. Returns false if the name is not an
+    /// optional feature.
+    pub fn contains(self, name: &str) -> bool {
+        matches!(feature_bit(name), Some(bit) if self.0 & bit != 0)
+    }
+
+    /// Add or remove a feature by its name. Returns an error if the name is not an optional
+    /// feature.
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), UnknownFeature> {
+        let bit = feature_bit(name).ok_or_else(|| UnknownFeature(name.to_string()))?;
+        if enabled {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+        Ok(())
+    }
+
+    /// Get the features that are in either set.
+    pub fn union(self, other: Self) -> Self {
+        FeatureSet(self.0 | other.0)
+    }
+
+    /// Get the features that are in both sets.
+    pub fn intersection(self, other: Self) -> Self {
+        FeatureSet(self.0 & other.0)
+    }
+
+    /// Get the features that are in this set, but not in `other`.
+    pub fn difference(self, other: Self) -> Self {
+        FeatureSet(self.0 & !other.0)
+    }
+
+    /// Get the features that are in exactly one of the sets.
+    pub fn symmetric_difference(self, other: Self) -> Self {
+        FeatureSet(self.0 ^ other.0)
+    }
+
+    /// Get the features in the set, in bit order.
+    pub fn features(self) -> Vec<&'static Feature> {
+        optional_features()
+            .enumerate()
+            .filter(|(index, _)| self.0 & 1 << index != 0)
+            .map(|(_, feature)| feature)
+            .collect()
+    }
+}
+
+impl From<&InstallOptions> for FeatureSet {
+    /// Get the optional features that are enabled by the options.
+    fn from(options: &InstallOptions) -> Self {
+        let mut set = FeatureSet::empty();
+        for feature in enabled_features(options) {
+            if feature.optional {
+                set.set(&feature.name, true)
+                    .expect("enabled features are in the built-in patch data");
+            }
+        }
+        set
+    }
+}
+
+impl From<FeatureSet> for InstallOptions {
+    /// Create install options that enable exactly the optional features in the set.
+    ///
+    /// Only one interface style can be selected, so if the set contains more than one interface
+    /// style feature, the last one wins.
+    fn from(set: FeatureSet) -> Self {
+        let mut options = InstallOptions::bare();
+        let (enabled, disabled): (Vec<_>, Vec<_>) =
+            optional_features().partition(|feature| set.contains(&feature.name));
+        // Disable features first, so disabling an interface style does not undo the selected one.
+        for (feature, enabled) in disabled
+            .into_iter()
+            .map(|feature| (feature, false))
+            .chain(enabled.into_iter().map(|feature| (feature, true)))
+        {
+            options
+                .set_feature(&feature.name, enabled)
+                .expect("optional features can be set by name");
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_options() {
+        assert!(optional_features().count() <= 64);
+        for options in &[
+            InstallOptions::bare(),
+            InstallOptions::default(),
+            InstallOptions::competitive(),
+            InstallOptions::all_optional(),
+        ] {
+            let set = FeatureSet::from(options);
+            assert_eq!(FeatureSet::from(&InstallOptions::from(set)), set);
+            assert_eq!(FeatureSet::from_bits(set.bits()), Some(set));
+        }
+
+        let bare = FeatureSet::from(&InstallOptions::bare());
+        let all = FeatureSet::from(&InstallOptions::all_optional());
+        let diff = all.difference(bare);
+        assert!(!diff.is_empty());
+        assert_eq!(bare.intersection(all), bare);
+        assert_eq!(bare.union(all), all);
+        assert_eq!(all.symmetric_difference(bare), diff);
+        assert!(diff
+            .features()
+            .iter()
+            .all(|feature| !bare.contains(&feature.name)));
+
+        let mut set = FeatureSet::empty();
+        set.set("Multiple building queue", true).unwrap();
+        assert!(set.contains("Multiple building queue"));
+        assert_eq!(set.len(), 1);
+        assert_eq!(
+            set.set("Not a feature", true),
+            Err(UnknownFeature("Not a feature".to_string()))
+        );
+        assert_eq!(FeatureSet::from_bits(!0), None);
+        assert_eq!(FeatureSet::all().len(), optional_features().count());
+    }
+}
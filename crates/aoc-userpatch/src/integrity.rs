@@ -0,0 +1,346 @@
+use crate::patch::{
+    builtin_features, configure_features, injection_offset, toggle, Feature, Injection, Toggle,
+    PATCHED_SIZE,
+};
+use crate::{InstallOptions, PatchError, Patcher};
+use std::ops::Range;
+
+/// The state of a single injection region in an executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionState {
+    /// The region contains the same bytes as the clean executable.
+    Vanilla,
+    /// The region contains the bytes that UserPatch writes.
+    Patched,
+    /// The region contains neither the original bytes nor the patched bytes, for example because
+    /// an install was interrupted halfway through writing it.
+    Corrupt,
+}
+
+/// The state of the bytes at an injection site.
+#[derive(Debug, Clone)]
+pub struct RegionStatus {
+    /// Name of the feature that this injection belongs to.
+    pub feature: String,
+    /// Offset of the region in the executable file.
+    pub offset: usize,
+    /// Size of the region in bytes.
+    pub len: usize,
+    /// What the region currently contains.
+    pub state: RegionState,
+}
+
+/// Describes which parts of an executable have been patched.
+#[derive(Debug, Clone)]
+pub struct IntegrityReport {
+    /// Status of each injection region that an install with the checked options would write.
+    pub regions: Vec<RegionStatus>,
+}
+
+impl IntegrityReport {
+    /// Does the executable look like it was never patched?
+    pub fn is_vanilla(&self) -> bool {
+        self.regions
+            .iter()
+            .all(|region| region.state == RegionState::Vanilla)
+    }
+
+    /// Does the executable look like a complete install with the checked options?
+    pub fn is_patched(&self) -> bool {
+        self.regions
+            .iter()
+            .all(|region| region.state == RegionState::Patched)
+    }
+
+    /// Is the executable in some in-between state that needs to be repaired?
+    pub fn needs_repair(&self) -> bool {
+        !self.is_vanilla() && !self.is_patched()
+    }
+
+    /// Iterate over the regions that are neither vanilla nor patched.
+    pub fn corrupt_regions(&self) -> impl Iterator<Item = &RegionStatus> {
+        self.regions
+            .iter()
+            .filter(|region| region.state == RegionState::Corrupt)
+    }
+}
+
+/// Read a byte from an executable, treating bytes past the end of the file as 0.
+///
+/// Patched executables are padded with zeroes, so a missing byte is equivalent to a 0 byte.
+fn byte_at(buffer: &[u8], offset: usize) -> u8 {
+    buffer.get(offset).copied().unwrap_or(0)
+}
+
+/// Check which injection regions in an executable have been written.
+///
+/// `buffer` is the executable to check, `baseline` is a clean 1.0c executable, and `options` are
+/// the install options that were (or will be) used to patch the executable. Each region that an
+/// install with these options would write is classified as vanilla, patched, or corrupt, so that a
+/// repair tool can tell a half-written executable apart from a clean or a completely patched one.
+///
+/// Regions where the patched bytes are identical to the original bytes are reported as patched.
+/// Returns an error if the baseline cannot be patched with the options, for example because it is
+/// too large or the options reject overlapping injections.
+pub fn integrity_check(
+    buffer: &[u8],
+    options: &InstallOptions,
+    baseline: &[u8],
+) -> Result<IntegrityReport, PatchError> {
+    let features = configure_features(builtin_features(), options);
+    // Injections from different features may overlap, so compare against the final result of an
+    // install instead of the individual injections. The baseline is trusted to be a clean 1.0c
+    // executable, so it is not verified.
+    let expected = Patcher::new(&options.clone().verify_version(false))?.patch(baseline)?;
+
+    let mut regions = vec![];
+    for feature in features.iter().filter(|feature| feature.enabled()) {
+        let Feature { name, patches, .. } = feature;
+        for Injection(addr, patch, ..) in patches.iter() {
+            let offset = match injection_offset(*addr, PATCHED_SIZE) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            let range = offset..offset + patch.len();
+            let state = if range
+                .clone()
+                .all(|i| byte_at(buffer, i) == byte_at(&expected, i))
+            {
+                RegionState::Patched
+            } else if range
+                .clone()
+                .all(|i| byte_at(buffer, i) == byte_at(baseline, i))
+            {
+                RegionState::Vanilla
+            } else {
+                RegionState::Corrupt
+            };
+            regions.push(RegionStatus {
+                feature: name.to_string(),
+                offset,
+                len: patch.len(),
+                state,
+            });
+        }
+    }
+
+    Ok(IntegrityReport { regions })
+}
+
+/// Whether a feature appears to be installed in an executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureState {
+    /// All of the feature's injections are present.
+    Enabled,
+    /// None of the feature's injections are present.
+    Disabled,
+    /// The feature cannot be detected, because its injections do not change any bytes, or are
+    /// overwritten by other features, or are only partially present.
+    Unknown,
+}
+
+/// The detected state of a single feature.
+#[derive(Debug, Clone)]
+pub struct DetectedFeature {
+    /// Name of the feature.
+    pub feature: String,
+    /// Whether the feature is installed.
+    pub state: FeatureState,
+}
+
+/// Detect the state of each feature, returning the features alongside their state.
+fn detect(
+    buffer: &[u8],
+    baseline: &[u8],
+) -> Result<Vec<(&'static Feature, FeatureState)>, PatchError> {
+    for input in &[buffer, baseline] {
+        if input.len() > PATCHED_SIZE {
+            return Err(PatchError::InputTooLarge {
+                size: input.len(),
+                max: PATCHED_SIZE,
+            });
+        }
+    }
+
+    let features = builtin_features();
+    let ranges: Vec<(usize, Range<usize>)> = features
+        .iter()
+        .enumerate()
+        .flat_map(|(index, feature)| {
+            feature
+                .patches
+                .iter()
+                .filter_map(move |Injection(addr, patch, ..)| {
+                    let offset = injection_offset(*addr, PATCHED_SIZE)?;
+                    Some((index, offset..offset + patch.len()))
+                })
+        })
+        .collect();
+    // Injections that overlap with another feature's injections may have been overwritten, so they
+    // cannot be used to detect a feature.
+    let is_shared = |index: usize, range: &Range<usize>| {
+        ranges.iter().any(|(other_index, other)| {
+            *other_index != index && other.start < range.end && range.start < other.end
+        })
+    };
+
+    let detected = features
+        .iter()
+        .enumerate()
+        .map(|(index, feature)| {
+            let mut present = 0;
+            let mut missing = 0;
+            for Injection(addr, patch, ..) in feature.patches.iter() {
+                let offset = match injection_offset(*addr, PATCHED_SIZE) {
+                    Some(offset) => offset,
+                    None => continue,
+                };
+                let range = offset..offset + patch.len();
+                if is_shared(index, &range) {
+                    continue;
+                }
+                let bytes = range.clone().zip(patch.iter());
+                if bytes.clone().all(|(i, byte)| byte_at(baseline, i) == *byte) {
+                    // This injection does not change anything.
+                    continue;
+                }
+                if bytes.clone().all(|(i, byte)| byte_at(buffer, i) == *byte) {
+                    present += 1;
+                } else {
+                    missing += 1;
+                }
+            }
+            let state = match (present, missing) {
+                (0, 0) => FeatureState::Unknown,
+                (_, 0) => FeatureState::Enabled,
+                (0, _) => FeatureState::Disabled,
+                _ => FeatureState::Unknown,
+            };
+            (feature, state)
+        })
+        .collect();
+    Ok(detected)
+}
+
+/// Detect which features are installed in an executable.
+///
+/// `buffer` is the executable to check and `baseline` is a clean 1.0c executable.
+pub fn detect_features(buffer: &[u8], baseline: &[u8]) -> Result<Vec<DetectedFeature>, PatchError> {
+    Ok(detect(buffer, baseline)?
+        .into_iter()
+        .map(|(feature, state)| DetectedFeature {
+            feature: feature.name.to_string(),
+            state,
+        })
+        .collect())
+}
+
+/// Install options that were reconstructed from a patched executable.
+#[derive(Debug, Clone)]
+pub struct ReconstructedOptions {
+    /// The best guess for the options that were used to patch the executable.
+    pub options: InstallOptions,
+    /// Names of optional features whose state could not be detected or cannot be reproduced with
+    /// `InstallOptions`. For features that could not be detected, `options` uses their default
+    /// state.
+    pub uncertain: Vec<String>,
+}
+
+/// Reconstruct the install options that were used to patch an executable.
+///
+/// `patched` is the patched executable and `baseline` is a clean 1.0c executable.
+pub fn reconstruct_options(
+    patched: &[u8],
+    baseline: &[u8],
+) -> Result<ReconstructedOptions, PatchError> {
+    let mut options = InstallOptions::bare();
+    let mut uncertain = vec![];
+    for (feature, state) in detect(patched, baseline)? {
+        if !feature.optional {
+            continue;
+        }
+        match (toggle(&feature.name), state) {
+            (Toggle::Unknown, _) => (),
+            (Toggle::Unsupported, FeatureState::Disabled) => (),
+            (Toggle::Unsupported, _) => uncertain.push(feature.name.to_string()),
+            (toggle, FeatureState::Unknown) => {
+                toggle.apply(&mut options, feature.enabled());
+                uncertain.push(feature.name.to_string());
+            }
+            (toggle, state) => toggle.apply(&mut options, state == FeatureState::Enabled),
+        }
+    }
+    Ok(ReconstructedOptions { options, uncertain })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install_into;
+
+    #[test]
+    fn classify_regions() {
+        let baseline = vec![0x90; 0x200000];
+        let options = InstallOptions::bare().verify_version(false);
+
+        let report = integrity_check(&baseline, &options, &baseline).unwrap();
+        assert!(report.is_vanilla());
+
+        let patched = install_into(&baseline, &options);
+        let report = integrity_check(&patched, &options, &baseline).unwrap();
+        assert!(report.is_patched());
+        assert!(!report.needs_repair());
+
+        // Simulate an install that crashed halfway through writing a region.
+        let first = report
+            .regions
+            .iter()
+            .find(|region| {
+                region.len > 1
+                    && patched[region.offset] != baseline[0]
+                    && patched[region.offset + region.len - 1] != baseline[0]
+            })
+            .expect("expected at least one multi-byte injection");
+        let mut broken = patched.clone();
+        broken[first.offset] = baseline[0];
+        let report = integrity_check(&broken, &options, &baseline).unwrap();
+        assert!(report.needs_repair());
+        assert!(report
+            .corrupt_regions()
+            .any(|region| region.offset == first.offset));
+
+        let oversized = vec![0x90; PATCHED_SIZE + 1];
+        assert!(matches!(
+            integrity_check(&patched, &options, &oversized),
+            Err(PatchError::InputTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn reconstruct_from_patched() {
+        let baseline = vec![0x90; 0x200000];
+        let options = InstallOptions::default().verify_version(false);
+        let patched = install_into(&baseline, &options);
+
+        let reconstructed = reconstruct_options(&patched, &baseline).unwrap();
+        for feature in builtin_features().iter().filter(|feature| feature.optional) {
+            let toggle = toggle(&feature.name);
+            if let Toggle::Unknown | Toggle::Unsupported = toggle {
+                continue;
+            }
+            if reconstructed
+                .uncertain
+                .iter()
+                .any(|name| *name == feature.name)
+            {
+                continue;
+            }
+            assert_eq!(
+                toggle.is_enabled(&reconstructed.options),
+                toggle.is_enabled(&options),
+                "feature '{}'",
+                feature.name
+            );
+        }
+    }
+}
@@ -1,14 +1,56 @@
+mod chunks;
+mod data;
+mod feature_set;
+mod integrity;
 mod patch;
+mod preflight;
+mod prepare;
+mod sync;
+mod uninstall;
+mod version;
 
-pub use patch::install_into;
+pub use chunks::{apply_patch_chunks, changed_ranges, produce_patch, PatchChunk};
+pub use data::{
+    feature_catalog, install_into_with_table, load_patch_archive, load_patch_data,
+    load_patch_data_version, FeatureCatalogEntry, LoadPatchDataError, PatchData,
+};
+pub use feature_set::FeatureSet;
+pub use integrity::{
+    detect_features, integrity_check, reconstruct_options, DetectedFeature, FeatureState,
+    IntegrityReport, ReconstructedOptions, RegionState, RegionStatus,
+};
+pub use patch::{
+    describe_options, enabled_features, features_by_category, get_available_features, get_feature,
+    get_feature_ignore_case, install_file, install_into, install_into_buf, install_into_checked,
+    install_into_owned, install_to_writer, install_with_progress, AppliedFeature, ApplyReport,
+    Feature, FeatureCategory, InjectionInfo, InstallProgress, Patcher,
+};
+use patch::{toggle, Toggle, PATCHED_SIZE};
+pub use preflight::{
+    overlapping_injections, preflight, validate, FeatureConflict, Overlap, PreflightReport,
+};
+pub use prepare::{checksum, prepare};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     error::Error,
     fmt::{self, Display, Formatter},
     str::FromStr,
 };
+pub use sync::{sync_affecting_features, SyncProfile, SyncProfileDiff};
+pub use uninstall::{
+    install_into_with_journal, revert_feature, uninstall_from, InstallJournal, UninstallError,
+};
+pub use version::{check_version, detect_version, is_patched, ExeVersion};
 
 /// Interface style.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 pub enum InterfaceStyle {
     /// Use the left-aligned interface style.
     LeftAligned,
@@ -32,6 +74,141 @@ impl Display for ParseInterfaceStyleError {
 
 impl Error for ParseInterfaceStyleError {}
 
+/// The name passed to [`InstallOptions::set_feature`] is not an optional feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownFeature(pub String);
+
+impl Display for UnknownFeature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown optional feature \"{}\"", self.0)
+    }
+}
+
+impl Error for UnknownFeature {}
+
+/// Failed to load install options from a configuration file.
+#[cfg(feature = "config")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The file is not a map of feature names to booleans.
+    Parse(String),
+    /// The file references a feature that is not an optional feature.
+    UnknownFeature(UnknownFeature),
+}
+
+#[cfg(feature = "config")]
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse(message) => write!(f, "Invalid install options: {}", message),
+            ConfigError::UnknownFeature(err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "config")]
+impl Error for ConfigError {}
+
+#[cfg(feature = "config")]
+impl From<UnknownFeature> for ConfigError {
+    fn from(err: UnknownFeature) -> Self {
+        ConfigError::UnknownFeature(err)
+    }
+}
+
+/// Failed to install UserPatch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The input executable is larger than a patched executable, so it cannot be a 1.0c
+    /// executable.
+    InputTooLarge {
+        /// Size of the input in bytes.
+        size: usize,
+        /// Maximum supported size in bytes.
+        max: usize,
+    },
+    /// An injection would write outside of the patched executable.
+    OffsetOutOfBounds {
+        /// File offset of the injection.
+        offset: usize,
+        /// Size of the injection in bytes.
+        len: usize,
+    },
+    /// Patching was cancelled.
+    Cancelled,
+    /// The original bytes at an injection do not match the bytes expected by the patch data.
+    ContextMismatch {
+        /// File offset of the injection.
+        offset: usize,
+    },
+    /// The checksum of the input executable does not match the expected checksum.
+    ChecksumMismatch {
+        /// The expected SHA-256 checksum.
+        expected: [u8; 32],
+        /// The SHA-256 checksum of the input.
+        actual: [u8; 32],
+    },
+    /// The input executable is not a supported version.
+    UnsupportedVersion {
+        /// Why the executable is not supported.
+        reason: &'static str,
+    },
+    /// UserPatch is already installed in the input executable.
+    AlreadyPatched,
+    /// Two features write to the same bytes, and overlaps are rejected. See
+    /// [`InstallOptions::reject_overlaps`].
+    OverlappingInjections(Overlap),
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::InputTooLarge { size, max } => write!(
+                f,
+                "Input is too large: got {} bytes, expected at most {}",
+                size, max
+            ),
+            PatchError::OffsetOutOfBounds { offset, len } => write!(
+                f,
+                "Injection of {} bytes at {:#x} is out of bounds",
+                len, offset
+            ),
+            PatchError::Cancelled => write!(f, "Patching was cancelled"),
+            PatchError::ContextMismatch { offset } => write!(
+                f,
+                "Unexpected original bytes at {:#x}, this is probably not a 1.0c executable",
+                offset
+            ),
+            PatchError::ChecksumMismatch { expected, actual } => {
+                let hex = |bytes: &[u8; 32]| -> String {
+                    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+                };
+                write!(
+                    f,
+                    "Checksum mismatch: expected {}, got {}",
+                    hex(expected),
+                    hex(actual)
+                )
+            }
+            PatchError::UnsupportedVersion { reason } => {
+                write!(f, "Unsupported executable version: {}", reason)
+            }
+            PatchError::AlreadyPatched => write!(f, "UserPatch is already installed"),
+            PatchError::OverlappingInjections(Overlap {
+                first,
+                second,
+                offset,
+            }) => write!(
+                f,
+                "Features \"{}\" and \"{}\" both write to {:#x}",
+                first, second, offset
+            ),
+        }
+    }
+}
+
+impl Error for PatchError {}
+
 impl FromStr for InterfaceStyle {
     type Err = ParseInterfaceStyleError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -45,7 +222,12 @@ impl FromStr for InterfaceStyle {
 }
 
 /// UserPatch installation options.
+///
+/// With the `serde` feature, the options serialize as a map of the named options. Missing options
+/// fall back to their default values when deserializing, so configuration files written by older
+/// versions of this crate still load.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
 pub struct InstallOptions {
     /// Interface style.
     interface_style: InterfaceStyle,
@@ -134,6 +316,26 @@ pub struct InstallOptions {
     spec_market_events: bool,
     /// Show rec/spec score statistics.
     spec_score_stats: bool,
+
+    /// Features that are enabled or disabled by name, for features that do not have an option.
+    feature_overrides: BTreeMap<String, bool>,
+
+    /// Check that the original bytes match the patch data before patching.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    strict_context: bool,
+    /// Check that the input is an unpatched 1.0c executable before patching.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    verify_version: bool,
+    /// Fail if the injections of two features overlap.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    reject_overlaps: bool,
+    /// Size of the patched executable.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    output_size: usize,
+    /// Write injections in parallel.
+    #[cfg(feature = "rayon")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    parallel: bool,
 }
 
 impl InstallOptions {
@@ -182,8 +384,190 @@ impl InstallOptions {
             spec_research_events: false,
             spec_market_events: false,
             spec_score_stats: false,
+            feature_overrides: BTreeMap::new(),
+            strict_context: false,
+            verify_version: true,
+            reject_overlaps: false,
+            output_size: PATCHED_SIZE,
+            #[cfg(feature = "rayon")]
+            parallel: true,
+        }
+    }
+
+    /// Get install options with the defaults of the original UserPatch installer.
+    ///
+    /// Every optional feature that can be controlled by `InstallOptions` is enabled if the
+    /// installer enables it by default. Unlike [`InstallOptions::default`], this does not depend
+    /// on the platform.
+    pub fn recommended() -> Self {
+        let mut options = Self::bare();
+        for feature in get_available_features().iter().filter(|f| f.optional) {
+            toggle(&feature.name).apply(&mut options, feature.enabled());
+        }
+        options
+    }
+
+    /// Get install options for competitive multiplayer games.
+    ///
+    /// This is [`InstallOptions::recommended`] with every optional feature that affects game sync
+    /// turned off, so all players using these options have the same [`SyncProfile`]. For example,
+    /// the weather system, the multiplayer anti-cheat measures, and the civilian attack switch
+    /// behave like in the original game.
+    pub fn competitive() -> Self {
+        let mut options = Self::recommended();
+        for feature in sync_affecting_features().iter().filter(|f| f.optional) {
+            options
+                .set_feature(&feature.name, false)
+                .expect("built-in features can be toggled");
+        }
+        options
+    }
+
+    /// Get install options with every optional feature enabled.
+    ///
+    /// Only one interface style can be installed, so this uses the widescreen interface style.
+    /// Features that are not otherwise supported by this crate, like "Windowed fullscreen mode",
+    /// are enabled as well.
+    pub fn all_optional() -> Self {
+        let mut options = Self::bare();
+        for feature in get_available_features().iter().filter(|f| f.optional) {
+            if let Toggle::InterfaceStyle(_) = toggle(&feature.name) {
+                continue;
+            }
+            options
+                .set_feature(&feature.name, true)
+                .expect("built-in features can be toggled");
+        }
+        options.interface_style = InterfaceStyle::Widescreen;
+        options
+    }
+
+    /// Check that the original bytes at each injection match the bytes expected by the patch data
+    /// before patching.
+    ///
+    /// This catches patching a slightly different executable, and reports the offset where it
+    /// differs. Only injections for which the patch data contains the original bytes are checked;
+    /// the built-in patch data does not contain any, so this only has an effect with patch data
+    /// loaded from an archive.
+    pub fn strict_context(self, strict_context: bool) -> Self {
+        Self {
+            strict_context,
+            ..self
         }
     }
+
+    /// Check that the input looks like an unpatched 1.0c executable before patching. This is
+    /// enabled by default.
+    ///
+    /// Patching a different executable, or one that already has UserPatch installed, produces a
+    /// broken executable. The check uses [`check_version`] and [`is_patched`]. Disable it to
+    /// experiment with other executables.
+    pub fn verify_version(self, verify_version: bool) -> Self {
+        Self {
+            verify_version,
+            ..self
+        }
+    }
+
+    /// Get a stable hash of the enabled features that affect game sync.
+    ///
+    /// Two players can play together if their signatures are equal. See [`SyncProfile::signature`].
+    pub fn sync_signature(&self) -> [u8; 32] {
+        SyncProfile::from_options(self).signature()
+    }
+
+    /// Fail with [`PatchError::OverlappingInjections`] if the injections of two enabled features
+    /// write to the same bytes.
+    ///
+    /// Some built-in features deliberately replace code that another feature injected, so this is
+    /// disabled by default. It is useful to catch conflicts in custom patch data. Use
+    /// [`overlapping_injections`] to list the overlaps instead.
+    pub fn reject_overlaps(self, reject_overlaps: bool) -> Self {
+        Self {
+            reject_overlaps,
+            ..self
+        }
+    }
+
+    /// Set the size of the patched executable. The default is 3MB, like the original UserPatch
+    /// installer.
+    ///
+    /// The executable is padded with zeroes to this size. Creating a [`Patcher`] fails with
+    /// [`PatchError::OffsetOutOfBounds`] if an injection does not fit, and patching fails with
+    /// [`PatchError::InputTooLarge`] if the input is larger. Use [`Patcher::required_size`] to
+    /// find the smallest size that fits all injections.
+    pub fn output_size(self, output_size: usize) -> Self {
+        Self {
+            output_size,
+            ..self
+        }
+    }
+
+    /// Write the injections that do not overlap any other injection in parallel. This is enabled
+    /// by default, and only available with the `rayon` feature.
+    ///
+    /// The output is the same either way. Injections are only written in parallel when no
+    /// progress is reported, so [`Patcher::patch_with_progress`] always writes them in order.
+    #[cfg(feature = "rayon")]
+    pub fn parallel(self, parallel: bool) -> Self {
+        Self { parallel, ..self }
+    }
+
+    /// Enable or disable an optional feature by its name, like "Multiple building queue".
+    ///
+    /// Features that have an option, like "Multiple building queue", are toggled by changing that
+    /// option, so this is equivalent to setting the option directly. Other features are enabled or
+    /// disabled regardless of the options. Returns an error if the name does not belong to an
+    /// optional feature in the built-in patch data.
+    pub fn set_feature(&mut self, name: &str, enabled: bool) -> Result<(), UnknownFeature> {
+        let feature = get_feature(name)
+            .filter(|feature| feature.optional)
+            .ok_or_else(|| UnknownFeature(name.to_string()))?;
+        match toggle(&feature.name) {
+            Toggle::Unsupported | Toggle::Unknown => {
+                self.feature_overrides
+                    .insert(feature.name.to_string(), enabled);
+            }
+            toggle => toggle.apply(self, enabled),
+        }
+        Ok(())
+    }
+
+    /// Load install options from a TOML file that maps feature names to whether they are enabled:
+    ///
+    /// ```toml
+    /// "Multiple building queue" = true
+    /// "Darken mini-map grey" = false
+    /// ```
+    ///
+    /// The features are applied on top of the default options with
+    /// [`InstallOptions::set_feature`], so features that are not in the file keep their default
+    /// state. Returns an error if a name is not an optional feature, for example because the
+    /// feature was removed.
+    #[cfg(feature = "config")]
+    pub fn from_toml_str(config: &str) -> Result<Self, ConfigError> {
+        let features = toml::from_str(config).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        Self::from_feature_map(features)
+    }
+
+    /// Load install options from a JSON object that maps feature names to whether they are
+    /// enabled. See [`InstallOptions::from_toml_str`].
+    #[cfg(feature = "config")]
+    pub fn from_json_str(config: &str) -> Result<Self, ConfigError> {
+        let features =
+            serde_json::from_str(config).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        Self::from_feature_map(features)
+    }
+
+    /// Apply a map of feature names to the default options.
+    #[cfg(feature = "config")]
+    fn from_feature_map(features: BTreeMap<String, bool>) -> Result<Self, ConfigError> {
+        let mut options = Self::default();
+        for (name, enabled) in features {
+            options.set_feature(&name, enabled)?;
+        }
+        Ok(options)
+    }
 }
 
 impl Default for InstallOptions {
@@ -253,6 +637,130 @@ impl Default for InstallOptions {
             spec_research_events: false,
             spec_market_events: false,
             spec_score_stats: true,
+            feature_overrides: BTreeMap::new(),
+            strict_context: false,
+            verify_version: true,
+            reject_overlaps: false,
+            output_size: PATCHED_SIZE,
+            #[cfg(feature = "rayon")]
+            parallel: true,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the names of the optional features that are enabled by the options.
+    fn enabled_optional_features(options: &InstallOptions) -> Vec<String> {
+        enabled_features(options)
+            .into_iter()
+            .filter(|feature| feature.optional)
+            .map(|feature| feature.name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn option_presets() {
+        let recommended = enabled_optional_features(&InstallOptions::recommended());
+        for feature in get_available_features().iter().filter(|f| f.optional) {
+            match toggle(&feature.name) {
+                Toggle::Unsupported => assert!(!recommended.contains(&feature.name.to_string())),
+                _ => assert_eq!(
+                    recommended.contains(&feature.name.to_string()),
+                    feature.enabled(),
+                    "{}",
+                    feature.name
+                ),
+            }
+        }
+
+        let competitive = enabled_optional_features(&InstallOptions::competitive());
+        let sync_names = sync_affecting_features()
+            .iter()
+            .map(|feature| feature.name.to_string())
+            .collect::<Vec<_>>();
+        assert!(competitive.iter().all(|name| !sync_names.contains(name)));
+        let unsynced = |names: &[String]| {
+            names
+                .iter()
+                .filter(|name| !sync_names.contains(name))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(unsynced(&competitive), unsynced(&recommended));
+
+        let all = enabled_optional_features(&InstallOptions::all_optional());
+        let optional = get_available_features()
+            .iter()
+            .filter(|feature| feature.optional && feature.name != "Left-aligned interface style")
+            .map(|feature| feature.name.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(all, optional);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_options() {
+        let mut options = InstallOptions::bare();
+        options
+            .set_feature("Windowed fullscreen mode", true)
+            .unwrap();
+        options.multiple_queue = true;
+        let json = serde_json::to_value(&options).unwrap();
+        assert_eq!(json["interface_style"], "centered");
+        assert_eq!(json["multiple_queue"], true);
+        assert_eq!(json["feature_overrides"]["Windowed fullscreen mode"], true);
+        let loaded: InstallOptions = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded.sync_signature(), options.sync_signature());
+        assert_eq!(describe_options(&loaded), describe_options(&options));
+
+        let loaded: InstallOptions =
+            serde_json::from_str(r#"{ "interface_style": "left-aligned" }"#).unwrap();
+        assert_eq!(loaded.interface_style, InterfaceStyle::LeftAligned);
+        assert_eq!(
+            describe_options(&loaded),
+            describe_options(&InstallOptions {
+                interface_style: InterfaceStyle::LeftAligned,
+                ..InstallOptions::default()
+            })
+        );
+        assert!(loaded.verify_version);
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn load_config_files() {
+        let options = InstallOptions::from_toml_str(
+            r#"
+            "Multiple building queue" = false
+            "Windowed fullscreen mode" = true
+            "#,
+        )
+        .unwrap();
+        let mut expected = InstallOptions::default();
+        expected
+            .set_feature("Multiple building queue", false)
+            .unwrap();
+        expected
+            .set_feature("Windowed fullscreen mode", true)
+            .unwrap();
+        assert_eq!(describe_options(&options), describe_options(&expected));
+
+        let options = InstallOptions::from_json_str(
+            r#"{ "Multiple building queue": false, "Windowed fullscreen mode": true }"#,
+        )
+        .unwrap();
+        assert_eq!(describe_options(&options), describe_options(&expected));
+
+        assert_eq!(
+            InstallOptions::from_toml_str(r#""Removed feature" = true"#).unwrap_err(),
+            ConfigError::UnknownFeature(UnknownFeature("Removed feature".to_string()))
+        );
+        assert!(matches!(
+            InstallOptions::from_json_str(r#"{ "Multiple building queue": "yes" }"#),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+}
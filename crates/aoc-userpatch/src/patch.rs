@@ -1,14 +1,90 @@
 #![allow(clippy::unreadable_literal)]
-use crate::{InstallOptions, InterfaceStyle};
-use std::{fmt, str};
+use crate::preflight::find_overlaps;
+use crate::{
+    check_version, checksum, is_patched, InstallJournal, InstallOptions, InterfaceStyle, PatchData,
+    PatchError,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read, Write},
+    ops::Range,
+    path::Path,
+    str,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The area of the game that a feature changes, for grouping features in a user interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FeatureCategory {
+    /// Features that are always installed.
+    Core,
+    /// The user interface and controls.
+    Interface,
+    /// Graphics and terrain.
+    Graphics,
+    /// Game rules and unit behaviour.
+    Gameplay,
+    /// Multiplayer lobbies and networking.
+    Multiplayer,
+    /// Recorded games and spectating.
+    Spectating,
+    /// Windows, sound, and files.
+    System,
+    /// Features that are not categorized.
+    Other,
+}
+
+impl FeatureCategory {
+    /// All categories, in the order they should be displayed.
+    pub const ALL: [FeatureCategory; 8] = [
+        FeatureCategory::Core,
+        FeatureCategory::Interface,
+        FeatureCategory::Graphics,
+        FeatureCategory::Gameplay,
+        FeatureCategory::Multiplayer,
+        FeatureCategory::Spectating,
+        FeatureCategory::System,
+        FeatureCategory::Other,
+    ];
+
+    /// Get the lowercase name of the category, as used in patch archives.
+    pub fn name(self) -> &'static str {
+        match self {
+            FeatureCategory::Core => "core",
+            FeatureCategory::Interface => "interface",
+            FeatureCategory::Graphics => "graphics",
+            FeatureCategory::Gameplay => "gameplay",
+            FeatureCategory::Multiplayer => "multiplayer",
+            FeatureCategory::Spectating => "spectating",
+            FeatureCategory::System => "system",
+            FeatureCategory::Other => "other",
+        }
+    }
+
+    /// Find a category by its lowercase name.
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|category| category.name() == name)
+    }
+}
 
 #[derive(Clone)]
 pub struct Feature {
-    pub name: &'static str,
+    pub name: Cow<'static, str>,
+    pub description: Option<Cow<'static, str>>,
+    pub category: FeatureCategory,
     pub optional: bool,
     pub affects_sync: bool,
-    enabled: bool,
-    patches: &'static [Injection],
+    /// Names of the features that must be enabled for this feature to work.
+    pub requires: Cow<'static, [Cow<'static, str>]>,
+    /// Names of the features that cannot be enabled together with this feature.
+    pub conflicts_with: Cow<'static, [Cow<'static, str>]>,
+    pub(crate) enabled: bool,
+    pub(crate) patches: Cow<'static, [Injection]>,
 }
 
 impl fmt::Debug for Feature {
@@ -38,10 +114,101 @@ impl Feature {
     pub fn enabled(&self) -> bool {
         self.enabled
     }
+
+    /// Iterate over the injections of this feature.
+    pub fn injections(&self) -> impl Iterator<Item = InjectionInfo<'_>> {
+        self.patches.iter().map(
+            |Injection(address, bytes, expected, annotation)| InjectionInfo {
+                address: *address,
+                bytes,
+                expected: expected.as_deref(),
+                annotation: annotation.as_deref(),
+            },
+        )
+    }
+
+    /// Get the total number of bytes that the injections of this feature write.
+    pub fn patch_len(&self) -> usize {
+        self.patches.iter().map(|injection| injection.1.len()).sum()
+    }
+
+    /// Get the virtual address and length in bytes of each injection of this feature.
+    pub fn offsets(&self) -> Vec<(u32, usize)> {
+        self.patches
+            .iter()
+            .map(|Injection(address, bytes, _, _)| (*address, bytes.len()))
+            .collect()
+    }
+
+    /// Get a stable identifier for this feature, derived from its name.
+    ///
+    /// For example, "Darken mini-map red" has the id "darken-mini-map-red".
+    pub fn id(&self) -> String {
+        let mut id = String::with_capacity(self.name.len());
+        for word in self
+            .name
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            if !id.is_empty() {
+                id.push('-');
+            }
+            id.push_str(&word.to_ascii_lowercase());
+        }
+        id
+    }
+}
+
+/// Describes a patch as an offset and a hexadecimal string, optionally with the original bytes that
+/// are expected at that offset before patching, and an annotation.
+#[derive(Clone)]
+pub(crate) struct Injection(
+    pub(crate) u32,
+    pub(crate) Cow<'static, [u8]>,
+    pub(crate) Option<Cow<'static, [u8]>>,
+    pub(crate) Option<Cow<'static, str>>,
+);
+
+/// A view of a single injection of a feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InjectionInfo<'a> {
+    /// Virtual address that the injection is written to.
+    pub address: u32,
+    /// The bytes that are written.
+    pub bytes: &'a [u8],
+    /// The original bytes that are expected at the address, if the patch data contains them.
+    pub expected: Option<&'a [u8]>,
+    /// A description of the bytes that are written, if the patch data contains one.
+    ///
+    /// Annotations list the bytes in hexadecimal, followed by a semicolon and a free-form
+    /// description, like disassembler output: `e9 fb 0f 00 00 ; jmp 0x7a6000`.
+    pub annotation: Option<&'a str>,
 }
 
-/// Describes a patch as an offset and a hexadecimal string.
-struct Injection(u32, &'static [u8]);
+impl InjectionInfo<'_> {
+    /// Get the number of bytes that the annotation lists.
+    ///
+    /// Returns `None` if there is no annotation, or if it does not start with a list of bytes.
+    pub fn annotated_len(&self) -> Option<usize> {
+        self.annotation.and_then(annotated_len)
+    }
+}
+
+/// Count the bytes listed at the start of an injection annotation.
+pub(crate) fn annotated_len(annotation: &str) -> Option<usize> {
+    let bytes = match annotation.find(';') {
+        Some(end) => &annotation[..end],
+        None => annotation,
+    };
+    let mut len = 0;
+    for byte in bytes.split_whitespace() {
+        if byte.len() != 2 || !byte.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        len += 1;
+    }
+    Some(len)
+}
 
 /// Overwrite bytes in buffer at an offset.
 fn apply_patch(buffer: &mut [u8], offset: usize, patch: &[u8]) {
@@ -51,104 +218,919 @@ fn apply_patch(buffer: &mut [u8], offset: usize, patch: &[u8]) {
 
 include!(concat!(env!("OUT_DIR"), "/injections.rs"));
 
-fn configure_features(options: &InstallOptions) -> Vec<Feature> {
-    FEATURES
+/// Get the features from the built-in patch data.
+pub(crate) fn builtin_features() -> &'static [Feature] {
+    &FEATURES
+}
+
+/// The patch data that is embedded in this crate.
+pub(crate) static BUILTIN_PATCH_DATA: PatchData = PatchData {
+    version: Cow::Borrowed("1.5"),
+    features: Cow::Borrowed(&FEATURES),
+};
+
+/// Get the features that can be installed.
+pub fn get_available_features() -> &'static [Feature] {
+    PatchData::builtin().features()
+}
+
+/// Find a feature that can be installed by its exact name, like "Multiple building queue".
+pub fn get_feature(name: &str) -> Option<&'static Feature> {
+    get_available_features()
+        .iter()
+        .find(|feature| feature.name == name)
+}
+
+/// Find a feature that can be installed by its name, ignoring ASCII case.
+pub fn get_feature_ignore_case(name: &str) -> Option<&'static Feature> {
+    get_available_features()
+        .iter()
+        .find(|feature| feature.name.eq_ignore_ascii_case(name))
+}
+
+/// Get the features that installing with the given options applies, in the order they are applied.
+///
+/// This includes the features that are always installed. Nothing is patched, so this is cheap
+/// enough to preview the effect of the options before installing.
+pub fn enabled_features(options: &InstallOptions) -> Vec<&'static Feature> {
+    let features = get_available_features();
+    configure_features(features, options)
+        .iter()
+        .zip(features)
+        .filter(|(configured, _)| configured.enabled())
+        .map(|(_, feature)| feature)
+        .collect()
+}
+
+/// Group the features that can be installed by their category.
+pub fn features_by_category() -> HashMap<FeatureCategory, Vec<&'static Feature>> {
+    let mut categories: HashMap<_, Vec<_>> = HashMap::new();
+    for feature in get_available_features() {
+        categories
+            .entry(feature.category)
+            .or_default()
+            .push(feature);
+    }
+    categories
+}
+
+/// Accessors for a boolean field of `InstallOptions`.
+#[derive(Clone, Copy)]
+pub(crate) struct OptionField {
+    get: fn(&InstallOptions) -> bool,
+    get_mut: for<'a> fn(&'a mut InstallOptions) -> &'a mut bool,
+}
+
+/// Create the accessors for a boolean field of `InstallOptions`.
+macro_rules! field {
+    ($name:ident) => {
+        OptionField {
+            get: |options| options.$name,
+            get_mut: |options| &mut options.$name,
+        }
+    };
+}
+
+/// Describes how an optional UserPatch feature is controlled by `InstallOptions`.
+#[derive(Clone, Copy)]
+pub(crate) enum Toggle {
+    /// The feature is enabled when the named option is true.
+    Option(&'static str, OptionField),
+    /// The feature turns something off, so it is enabled when the named option is false.
+    Inverted(&'static str, OptionField),
+    /// The feature is enabled when a particular interface style is selected.
+    InterfaceStyle(InterfaceStyle),
+    /// The feature is not supported by this crate and is never enabled.
+    Unsupported,
+    /// The feature is not known to this crate, so it keeps its default state from the patch data.
+    Unknown,
+}
+
+impl Toggle {
+    /// Change the options so that the feature is enabled or disabled.
+    ///
+    /// Does nothing for features that cannot be controlled by `InstallOptions`.
+    pub(crate) fn apply(self, options: &mut InstallOptions, enabled: bool) {
+        match self {
+            Toggle::Option(_, field) => *(field.get_mut)(options) = enabled,
+            Toggle::Inverted(_, field) => *(field.get_mut)(options) = !enabled,
+            Toggle::InterfaceStyle(style) => {
+                if enabled {
+                    options.interface_style = style;
+                } else if options.interface_style == style {
+                    options.interface_style = InterfaceStyle::Centered;
+                }
+            }
+            Toggle::Unsupported | Toggle::Unknown => (),
+        }
+    }
+
+    /// Check if the feature should be enabled for the given options.
+    pub(crate) fn is_enabled(self, options: &InstallOptions) -> bool {
+        match self {
+            Toggle::Option(_, field) => (field.get)(options),
+            Toggle::Inverted(_, field) => !(field.get)(options),
+            Toggle::InterfaceStyle(style) => options.interface_style == style,
+            Toggle::Unsupported => false,
+            Toggle::Unknown => unreachable!("unknown features do not depend on options"),
+        }
+    }
+}
+
+/// Find the option that controls an optional feature.
+pub(crate) fn toggle(name: &str) -> Toggle {
+    match name {
+        "Widescreen interface style" => Toggle::InterfaceStyle(InterfaceStyle::Widescreen),
+        "Left-aligned interface style" => Toggle::InterfaceStyle(InterfaceStyle::LeftAligned),
+        "Windowed mode support" => Toggle::Option("windowed mode", field!(windowed_mode)),
+        "Port forwarding support" => Toggle::Option("upnp", field!(upnp)),
+        "Darken mini-map red" => Toggle::Option("alternate red", field!(alternate_red)),
+        "Darken mini-map purple" => Toggle::Option("alternate purple", field!(alternate_purple)),
+        "Darken mini-map grey" => Toggle::Option("alternate gray", field!(alternate_gray)),
+        "Population caps to 1000" => {
+            Toggle::Option("extend population caps", field!(extend_population_caps))
+        }
+        "Snow/ice terrain removal" => {
+            Toggle::Option("replace snow with grass", field!(replace_snow_with_grass))
+        }
+        "Enable water animation" => Toggle::Option("water animation", field!(water_animation)),
+        "Precision scrolling system" => {
+            Toggle::Option("precision scrolling", field!(precision_scrolling))
+        }
+        "Shift group appending" => Toggle::Option("shift group append", field!(shift_group_append)),
+        "Keydown object hotkeys" => Toggle::Option("keydown hotkeys", field!(keydown_hotkeys)),
+        "New save filename format" => Toggle::Option("savegame format", field!(savegame_format)),
+        "Multiple building queue" => Toggle::Option("multiple queue", field!(multiple_queue)),
+        "Original patrol default" => {
+            Toggle::Option("original patrol delay", field!(original_patrol_delay))
+        }
+        "Disable water movement" => Toggle::Inverted("water movement", field!(water_movement)),
+        "Disable weather system" => Toggle::Inverted("weather system", field!(weather_system)),
+        "Disable custom terrains" => Toggle::Inverted("custom terrains", field!(custom_terrains)),
+        "Disable terrain underwater" => {
+            Toggle::Inverted("terrain underwater", field!(terrain_underwater))
+        }
+        "Numeric age display" => Toggle::Option("numeric age display", field!(numeric_age_display)),
+        "Touch screen control" => {
+            Toggle::Option("touch screen control", field!(touch_screen_control))
+        }
+        "Store Sx spec addresses" => {
+            Toggle::Option("store spec addresses", field!(store_spec_addresses))
+        }
+        "Custom normal mouse" => Toggle::Option("normal mouse", field!(normal_mouse)),
+        "Delink from system volume" => Toggle::Option("delink volume", field!(delink_volume)),
+        "Alternate chat box for wine" => Toggle::Option("wine chatbox", field!(wine_chatbox)),
+        "Lower quality environment" => {
+            Toggle::Option("low quality environment", field!(low_quality_environment))
+        }
+        "Restore 20fps for single player" => Toggle::Option("low fps", field!(low_fps)),
+        "Disable extended hotkeys" => {
+            Toggle::Inverted("extended hotkeys", field!(extended_hotkeys))
+        }
+        "Force new gameplay features" => {
+            Toggle::Option("force gameplay features", field!(force_gameplay_features))
+        }
+        "Ore resource amount display" => {
+            Toggle::Option("display ore resource", field!(display_ore_resource))
+        }
+        "Disable multiplayer anti-cheat" => {
+            Toggle::Inverted("multiplayer anti cheat", field!(multiplayer_anti_cheat))
+        }
+        "Default to background mode" => {
+            Toggle::Option("default background mode", field!(default_background_mode))
+        }
+        "Windowed fullscreen mode" => Toggle::Unsupported,
+        "Multiplayer single player speed" => {
+            Toggle::Option("sp at multiplayer speed", field!(sp_at_multiplayer_speed))
+        }
+        "Rms and Scx debug logging" => Toggle::Option("debug logging", field!(debug_logging)),
+        "Change statistics font style" => {
+            Toggle::Option("statistics font style", field!(statistics_font_style))
+        }
+        "Background audio playback" => Toggle::Option(
+            "background audio playback",
+            field!(background_audio_playback),
+        ),
+        "Disable civilian attack switch" => {
+            Toggle::Option("civilian attack switch", field!(civilian_attack_switch))
+        }
+        "Handle small farm selections" => Toggle::Option(
+            "handle small farm selections",
+            field!(handle_small_farm_selections),
+        ),
+        "Show rec/spec research events" => {
+            Toggle::Option("spec research events", field!(spec_research_events))
+        }
+        "Show rec/spec market events" => {
+            Toggle::Option("spec market events", field!(spec_market_events))
+        }
+        "Disable rec/spec score stats" => {
+            Toggle::Inverted("spec score stats", field!(spec_score_stats))
+        }
+        "Hidden civilization selection" => Toggle::Unsupported,
+        "Allow spectators by default" => Toggle::Unsupported,
+        _ => Toggle::Unknown,
+    }
+}
+
+/// Enable or disable the optional features according to the options.
+///
+/// The order of the features is preserved, because it determines the order they are applied in.
+pub(crate) fn configure_features(features: &[Feature], options: &InstallOptions) -> Vec<Feature> {
+    features
         .iter()
         .cloned()
         .map(|mut f| {
             if !f.optional {
                 return f;
             }
-            f.enable(match f.name {
-                "Widescreen interface style" => {
-                    options.interface_style == InterfaceStyle::Widescreen
-                }
-                "Left-aligned interface style" => {
-                    options.interface_style == InterfaceStyle::LeftAligned
-                }
-                "Windowed mode support" => options.windowed_mode,
-                "Port forwarding support" => options.upnp,
-                "Darken mini-map red" => options.alternate_red,
-                "Darken mini-map purple" => options.alternate_purple,
-                "Darken mini-map grey" => options.alternate_gray,
-                "Population caps to 1000" => options.extend_population_caps,
-                "Snow/ice terrain removal" => options.replace_snow_with_grass,
-                "Enable water animation" => options.water_animation,
-                "Precision scrolling system" => options.precision_scrolling,
-                "Shift group appending" => options.shift_group_append,
-                "Keydown object hotkeys" => options.keydown_hotkeys,
-                "New save filename format" => options.savegame_format,
-                "Multiple building queue" => options.multiple_queue,
-                "Original patrol default" => options.original_patrol_delay,
-                "Disable water movement" => !options.water_movement,
-                "Disable weather system" => !options.weather_system,
-                "Disable custom terrains" => !options.custom_terrains,
-                "Disable terrain underwater" => !options.terrain_underwater,
-                "Numeric age display" => options.numeric_age_display,
-                "Touch screen control" => options.touch_screen_control,
-                "Store Sx spec addresses" => options.store_spec_addresses,
-                "Custom normal mouse" => options.normal_mouse,
-                "Delink from system volume" => options.delink_volume,
-                "Alternate chat box for wine" => options.wine_chatbox,
-                "Lower quality environment" => options.low_quality_environment,
-                "Restore 20fps for single player" => options.low_fps,
-                "Disable extended hotkeys" => !options.extended_hotkeys,
-                "Force new gameplay features" => options.force_gameplay_features,
-                "Ore resource amount display" => options.display_ore_resource,
-                "Disable multiplayer anti-cheat" => !options.multiplayer_anti_cheat,
-                "Default to background mode" => options.default_background_mode,
-                "Windowed fullscreen mode" => false,
-                "Multiplayer single player speed" => options.sp_at_multiplayer_speed,
-                "Rms and Scx debug logging" => options.debug_logging,
-                "Change statistics font style" => options.statistics_font_style,
-                "Background audio playback" => options.background_audio_playback,
-                "Disable civilian attack switch" => options.civilian_attack_switch,
-                "Handle small farm selections" => options.handle_small_farm_selections,
-                "Show rec/spec research events" => options.spec_research_events,
-                "Show rec/spec market events" => options.spec_market_events,
-                "Disable rec/spec score stats" => !options.spec_score_stats,
-                "Hidden civilization selection" => false,
-                "Allow spectators by default" => false,
-                _ => unreachable!(f.name),
-            });
+            if let Some(&enabled) = options.feature_overrides.get(&*f.name) {
+                f.enable(enabled);
+                return f;
+            }
+            match toggle(&f.name) {
+                Toggle::Unknown => (),
+                toggle => f.enable(toggle.is_enabled(options)),
+            }
             f
         })
         .collect()
 }
 
-/// Install UserPatch 1.5 into a buffer containing a 1.0c executable.
-pub fn install_into(exe_buffer: &[u8], options: &InstallOptions) -> Vec<u8> {
-    let features = configure_features(options);
+/// Describe what each optional feature will do with the given options, in a human-readable way.
+///
+/// Some features are stored in an inverted sense, like "Disable water movement", which is enabled
+/// when the `water_movement` option is false. This lists the effective state of the option
+/// alongside the state of the feature, for example:
+///
+/// ```text
+/// water movement: enabled (feature 'Disable water movement' disabled)
+/// ```
+pub fn describe_options(options: &InstallOptions) -> Vec<String> {
+    let on_off = |enabled: bool| if enabled { "enabled" } else { "disabled" };
+
+    FEATURES
+        .iter()
+        .filter(|f| f.optional)
+        .map(|f| {
+            let toggle = toggle(&f.name);
+            let feature_state = match (toggle, options.feature_overrides.get(&*f.name)) {
+                (_, Some(&enabled)) => on_off(enabled),
+                (Toggle::Unknown, None) => on_off(f.enabled),
+                (toggle, None) => on_off(toggle.is_enabled(options)),
+            };
+            match toggle {
+                Toggle::Option(label, field) | Toggle::Inverted(label, field) => format!(
+                    "{}: {} (feature '{}' {})",
+                    label,
+                    on_off((field.get)(options)),
+                    f.name,
+                    feature_state
+                ),
+                Toggle::InterfaceStyle(_) => format!(
+                    "interface style: {} (feature '{}' {})",
+                    match options.interface_style {
+                        InterfaceStyle::LeftAligned => "left",
+                        InterfaceStyle::Centered => "center",
+                        InterfaceStyle::Widescreen => "wide",
+                    },
+                    f.name,
+                    feature_state
+                ),
+                Toggle::Unsupported => {
+                    format!("feature '{}' {} (not supported)", f.name, feature_state)
+                }
+                Toggle::Unknown => format!("feature '{}' {} (default)", f.name, feature_state),
+            }
+        })
+        .collect()
+}
+
+/// The default size of a patched executable.
+pub(crate) const PATCHED_SIZE: usize = 3 * 1024 * 1024;
+
+/// The address where the original sections of the executable are loaded in memory.
+const IMAGE_BASE: usize = 0x400000;
+/// The address where the section added by UserPatch is loaded in memory.
+pub(crate) const UP_SECTION_ADDRESS: usize = 0x7A5000;
+/// The difference between addresses in memory and file offsets in the section added by UserPatch.
+pub(crate) const UP_SECTION_DELTA: usize = 0x512000;
+
+/// Find the location in the file that an injection address refers to.
+///
+/// Most injections use file offsets, but some use addresses in memory space. Returns `None` if
+/// the address is neither a file offset nor an address in one of the mapped sections.
+pub(crate) fn injection_offset(addr: u32, buffer_len: usize) -> Option<usize> {
+    let addr = addr as usize;
+    if addr < buffer_len {
+        Some(addr)
+    } else if addr < IMAGE_BASE {
+        None
+    } else if addr < UP_SECTION_ADDRESS {
+        Some(addr - IMAGE_BASE)
+    } else {
+        Some(addr - UP_SECTION_DELTA)
+    }
+}
+
+/// The injections for a single feature, with their offsets resolved.
+pub(crate) struct PreparedFeature {
+    /// Name of the feature.
+    pub(crate) name: Cow<'static, str>,
+    /// Stable identifier of the feature, see [`Feature::id`].
+    pub(crate) id: String,
+    /// The injections of the feature.
+    pub(crate) injections: Vec<PreparedInjection>,
+}
+
+/// An injection with its offset resolved.
+pub(crate) struct PreparedInjection {
+    /// File offset of the injection.
+    pub(crate) offset: usize,
+    /// Bytes to write.
+    pub(crate) bytes: Cow<'static, [u8]>,
+    /// Original bytes that are expected at the offset, if known.
+    pub(crate) expected: Option<Cow<'static, [u8]>>,
+}
+
+/// Progress of an install, reported after each feature is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstallProgress<'a> {
+    /// Stable identifier of the feature that was applied, see [`Feature::id`].
+    pub feature_id: &'a str,
+    /// Human-readable name of the feature that was applied, like "Multiple building queue".
+    pub feature_name: &'a str,
+    /// Number of bytes that the feature's injections wrote.
+    pub bytes_written: usize,
+    /// Number of features that have been applied so far, including this one.
+    pub features_done: usize,
+    /// Number of features that will be applied in total.
+    pub features_total: usize,
+}
+
+/// A feature that was applied by [`Patcher::patch_with_report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedFeature {
+    /// Stable identifier of the feature, see [`Feature::id`].
+    pub id: String,
+    /// Name of the feature.
+    pub name: String,
+    /// File offsets of the bytes that the feature's injections wrote, in the order they were
+    /// written.
+    pub ranges: Vec<Range<usize>>,
+}
+
+/// Describes what an install did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// The features that were applied, in the order they were applied. Features that are applied
+    /// later may overwrite bytes written by earlier features.
+    pub applied: Vec<AppliedFeature>,
+    /// Names of the optional features that were not applied because the install options disable
+    /// them.
+    pub skipped: Vec<String>,
+}
+
+/// Applies UserPatch to executables, with a fixed set of install options.
+///
+/// The injections for the enabled features are collected and validated once when the `Patcher`
+/// is created, so it's cheaper to use a single `Patcher` than to call [`install_into`] many times
+/// when patching many executables with the same options. A `Patcher` is `Send` and `Sync`, so it
+/// can be shared between threads to patch many executables in parallel.
+///
+/// The output is reproducible: it only depends on the input executable and the install options.
+/// The executable is padded with zeroes, and features are always applied in the same order, so
+/// patching the same executable with the same options produces byte-identical results.
+///
+/// Features are applied in the order they appear in the patch data, which is the order of
+/// [`get_available_features`], and the injections of each feature are applied in the order they
+/// are listed. This does not depend on how the options were built: setting the same features in a
+/// different order produces the same output. Where injections overlap, the one applied last wins.
+///
+/// With the `rayon` feature, injections that do not overlap any other injection are written in
+/// parallel, and the overlapping ones are written afterwards in the order above, so the output is
+/// the same. This can be turned off with `InstallOptions::parallel`.
+pub struct Patcher {
+    pub(crate) features: Vec<PreparedFeature>,
+    /// Names of the optional features that are disabled by the options.
+    skipped: Vec<String>,
+    /// Whether to check the original bytes before patching.
+    pub(crate) strict_context: bool,
+    /// Whether to check that the input is an unpatched 1.0c executable.
+    verify_version: bool,
+    /// Size of the patched executable.
+    output_size: usize,
+    /// How the injections are split up when they are written in parallel.
+    #[cfg(feature = "rayon")]
+    parallel: Option<ParallelPlan>,
+}
+
+/// The injections of a [`Patcher`], split up by whether they can be written in parallel. Each
+/// injection is referred to by the index of its feature and its index in the feature.
+#[cfg(feature = "rayon")]
+#[derive(Default)]
+struct ParallelPlan {
+    /// Injections that do not overlap any other injection, sorted by offset.
+    disjoint: Vec<(usize, usize)>,
+    /// Injections that overlap another injection, in the order they are applied.
+    overlapping: Vec<(usize, usize)>,
+}
+
+#[cfg(feature = "rayon")]
+impl ParallelPlan {
+    fn new(features: &[PreparedFeature]) -> Self {
+        let mut ranges: Vec<(usize, usize, (usize, usize))> = features
+            .iter()
+            .enumerate()
+            .flat_map(|(feature_index, feature)| {
+                feature
+                    .injections
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, injection)| {
+                        let end = injection.offset + injection.bytes.len();
+                        (injection.offset, end, (feature_index, index))
+                    })
+            })
+            .collect();
+        ranges.sort();
+
+        // Group injections whose ranges overlap. A group with a single injection is disjoint.
+        let mut plan = ParallelPlan::default();
+        let mut group = vec![];
+        let mut group_end = 0;
+        for (start, end, index) in ranges {
+            if !group.is_empty() && start >= group_end {
+                plan.add_group(&mut group);
+            }
+            group_end = if group.is_empty() {
+                end
+            } else {
+                group_end.max(end)
+            };
+            group.push(index);
+        }
+        plan.add_group(&mut group);
+        plan.overlapping.sort();
+        plan
+    }
+
+    fn add_group(&mut self, group: &mut Vec<(usize, usize)>) {
+        match group.len() {
+            0 => {}
+            1 => self.disjoint.push(group[0]),
+            _ => self.overlapping.extend_from_slice(group),
+        }
+        group.clear();
+    }
+}
+
+impl Patcher {
+    /// Prepare to install UserPatch with the given options.
+    pub fn new(options: &InstallOptions) -> Result<Self, PatchError> {
+        Self::with_data(PatchData::builtin(), options)
+    }
+
+    /// Prepare to install UserPatch from custom patch data with the given options.
+    pub fn with_data(data: &PatchData, options: &InstallOptions) -> Result<Self, PatchError> {
+        let (features, skipped): (Vec<_>, Vec<_>) = configure_features(data.features(), options)
+            .into_iter()
+            .partition(|feature| feature.enabled());
+        let skipped = skipped
+            .into_iter()
+            .map(|feature| feature.name.to_string())
+            .collect();
+        let features = features
+            .into_iter()
+            .map(|feature| {
+                let injections = feature
+                    .patches
+                    .iter()
+                    .map(|Injection(addr, patch, expected, _)| {
+                        let offset = match injection_offset(*addr, PATCHED_SIZE) {
+                            Some(offset) if offset + patch.len() <= options.output_size => offset,
+                            offset => {
+                                return Err(PatchError::OffsetOutOfBounds {
+                                    offset: offset.unwrap_or(*addr as usize),
+                                    len: patch.len(),
+                                })
+                            }
+                        };
+                        Ok(PreparedInjection {
+                            offset,
+                            bytes: patch.clone(),
+                            expected: expected.clone(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(PreparedFeature {
+                    id: feature.id(),
+                    name: feature.name,
+                    injections,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if options.reject_overlaps {
+            if let Some(overlap) = find_overlaps(&features).into_iter().next() {
+                return Err(PatchError::OverlappingInjections(overlap));
+            }
+        }
+
+        Ok(Self {
+            skipped,
+            strict_context: options.strict_context,
+            verify_version: options.verify_version,
+            output_size: options.output_size,
+            #[cfg(feature = "rayon")]
+            parallel: if options.parallel {
+                Some(ParallelPlan::new(&features))
+            } else {
+                None
+            },
+            features,
+        })
+    }
+
+    /// Get the size of the executables that this patcher produces.
+    pub fn output_size(&self) -> usize {
+        self.output_size
+    }
+
+    /// Get the smallest output size that fits all injections of the enabled features.
+    pub fn required_size(&self) -> usize {
+        self.features
+            .iter()
+            .flat_map(|feature| feature.injections.iter())
+            .map(|injection| injection.offset + injection.bytes.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Check that the original bytes at each injection match the expected bytes, if the patch data
+    /// has them. Bytes past the end of `buffer` are zero, like the padding of a patched executable.
+    pub(crate) fn check_context(&self, buffer: &[u8]) -> Result<(), PatchError> {
+        for feature in &self.features {
+            for injection in &feature.injections {
+                if let Some(expected) = &injection.expected {
+                    let offset = injection.offset;
+                    let actual = (offset..offset + expected.len())
+                        .map(|index| buffer.get(index).copied().unwrap_or(0));
+                    if !actual.eq(expected.iter().copied()) {
+                        return Err(PatchError::ContextMismatch { offset });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable.
+    pub fn patch(&self, exe_buffer: &[u8]) -> Result<Vec<u8>, PatchError> {
+        self.patch_cancellable(exe_buffer, &AtomicBool::new(false))
+    }
 
-    let mut extended_buffer = exe_buffer.to_vec();
-    let three_megs = 3 * 1024 * 1024;
-    extended_buffer.extend(&vec![0; three_megs - exe_buffer.len()]);
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable, stopping early when the
+    /// `cancel` flag is set.
+    ///
+    /// The flag is checked before applying each feature. If it is set, this returns
+    /// [`PatchError::Cancelled`].
+    pub fn patch_cancellable(
+        &self,
+        exe_buffer: &[u8],
+        cancel: &AtomicBool,
+    ) -> Result<Vec<u8>, PatchError> {
+        let mut output = Vec::with_capacity(self.output_size);
+        self.patch_into_cancellable(exe_buffer, &mut output, cancel)?;
+        Ok(output)
+    }
+
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable, writing the patched
+    /// executable into `output`.
+    ///
+    /// `output` is cleared first, and only grows if its capacity is too small, so reusing the same
+    /// buffer for multiple installs avoids allocating. If patching fails, the contents of `output`
+    /// are unspecified.
+    pub fn patch_into(&self, exe_buffer: &[u8], output: &mut Vec<u8>) -> Result<(), PatchError> {
+        self.patch_into_cancellable(exe_buffer, output, &AtomicBool::new(false))
+    }
+
+    /// Like [`Patcher::patch_into`], but stops early when the `cancel` flag is set, like
+    /// [`Patcher::patch_cancellable`].
+    pub fn patch_into_cancellable(
+        &self,
+        exe_buffer: &[u8],
+        output: &mut Vec<u8>,
+        cancel: &AtomicBool,
+    ) -> Result<(), PatchError> {
+        self.apply(exe_buffer, output, cancel, None)
+    }
+
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable, and record the bytes that
+    /// were overwritten so the install can be undone with [`crate::uninstall_from`].
+    pub fn patch_with_journal(
+        &self,
+        exe_buffer: &[u8],
+    ) -> Result<(Vec<u8>, InstallJournal), PatchError> {
+        let output = self.patch(exe_buffer)?;
+        Ok((output, InstallJournal::record(self, exe_buffer)))
+    }
+
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable, and compute the SHA-256
+    /// checksum of the patched executable.
+    ///
+    /// Since the output is reproducible, the checksum can be used to verify a patched executable
+    /// that was distributed to other machines. See [`crate::checksum`].
+    pub fn patch_with_checksum(
+        &self,
+        exe_buffer: &[u8],
+    ) -> Result<(Vec<u8>, [u8; 32]), PatchError> {
+        let output = self.patch(exe_buffer)?;
+        let checksum = checksum(&output);
+        Ok((output, checksum))
+    }
+
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable, and describe which
+    /// features were applied and where they wrote.
+    pub fn patch_with_report(
+        &self,
+        exe_buffer: &[u8],
+    ) -> Result<(Vec<u8>, ApplyReport), PatchError> {
+        let output = self.patch(exe_buffer)?;
+        let applied = self
+            .features
+            .iter()
+            .map(|feature| AppliedFeature {
+                id: feature.id.clone(),
+                name: feature.name.to_string(),
+                ranges: feature
+                    .injections
+                    .iter()
+                    .map(|injection| injection.offset..injection.offset + injection.bytes.len())
+                    .collect(),
+            })
+            .collect();
+        let report = ApplyReport {
+            applied,
+            skipped: self.skipped.clone(),
+        };
+        Ok((output, report))
+    }
+
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable, calling `progress` after
+    /// each feature is applied.
+    pub fn patch_with_progress(
+        &self,
+        exe_buffer: &[u8],
+        progress: &mut dyn FnMut(InstallProgress),
+    ) -> Result<Vec<u8>, PatchError> {
+        let mut output = Vec::with_capacity(self.output_size);
+        self.apply(
+            exe_buffer,
+            &mut output,
+            &AtomicBool::new(false),
+            Some(progress),
+        )?;
+        Ok(output)
+    }
+
+    /// Write the patched executable into `output`, reporting progress after each feature if
+    /// `progress` is set.
+    fn apply(
+        &self,
+        exe_buffer: &[u8],
+        output: &mut Vec<u8>,
+        cancel: &AtomicBool,
+        progress: Option<&mut dyn FnMut(InstallProgress)>,
+    ) -> Result<(), PatchError> {
+        self.check_input(exe_buffer)?;
+        output.clear();
+        output.extend_from_slice(exe_buffer);
+        self.apply_injections(output, cancel, progress)
+    }
+
+    /// Install UserPatch 1.5 into a buffer containing a 1.0c executable, replacing its contents
+    /// with the patched executable.
+    ///
+    /// Unlike the other methods, this does not copy the input, so only one executable is held in
+    /// memory. If patching fails, the contents of `buffer` are unspecified.
+    pub fn patch_in_place(&self, buffer: &mut Vec<u8>) -> Result<(), PatchError> {
+        self.check_input(buffer)?;
+        self.apply_injections(buffer, &AtomicBool::new(false), None)
+    }
+
+    /// Check that an executable can be patched.
+    pub(crate) fn check_input(&self, exe_buffer: &[u8]) -> Result<(), PatchError> {
+        if exe_buffer.len() > self.output_size {
+            return Err(PatchError::InputTooLarge {
+                size: exe_buffer.len(),
+                max: self.output_size,
+            });
+        }
+
+        if self.verify_version {
+            check_version(exe_buffer)?;
+            if is_patched(exe_buffer) {
+                return Err(PatchError::AlreadyPatched);
+            }
+        }
+        Ok(())
+    }
 
-    for feature in features.iter() {
-        if !feature.enabled() {
-            continue;
+    /// Pad a checked executable to the patched size and apply the injections, reporting progress
+    /// after each feature if `progress` is set.
+    ///
+    /// Progress is reported per feature, so injections are only written in parallel when no
+    /// progress is requested.
+    fn apply_injections(
+        &self,
+        output: &mut Vec<u8>,
+        cancel: &AtomicBool,
+        mut progress: Option<&mut dyn FnMut(InstallProgress)>,
+    ) -> Result<(), PatchError> {
+        output.resize(self.output_size, 0);
+
+        if self.strict_context {
+            self.check_context(output)?;
         }
 
-        let Feature { patches, .. } = feature;
-        for Injection(addr, patch) in patches.iter() {
-            let mut addr = *addr as usize;
-            if addr > extended_buffer.len() {
-                if addr < 0x7A5000 {
-                    addr -= 0x400000;
-                } else {
-                    addr -= 0x512000;
+        #[cfg(feature = "rayon")]
+        {
+            if let (Some(plan), None) = (&self.parallel, &progress) {
+                if cancel.load(Ordering::Relaxed) {
+                    return Err(PatchError::Cancelled);
                 }
+                self.write_parallel(plan, output);
+                return Ok(());
             }
-            apply_patch(&mut extended_buffer, addr, patch);
         }
+
+        for (index, feature) in self.features.iter().enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(PatchError::Cancelled);
+            }
+            let mut bytes_written = 0;
+            for injection in &feature.injections {
+                apply_patch(output, injection.offset, &injection.bytes);
+                bytes_written += injection.bytes.len();
+            }
+            if let Some(progress) = progress.as_mut() {
+                progress(InstallProgress {
+                    feature_id: &feature.id,
+                    feature_name: &feature.name,
+                    bytes_written,
+                    features_done: index + 1,
+                    features_total: self.features.len(),
+                });
+            }
+        }
+        Ok(())
     }
-    extended_buffer
+
+    /// Write the disjoint injections in parallel, and then the overlapping ones in order.
+    #[cfg(feature = "rayon")]
+    fn write_parallel(&self, plan: &ParallelPlan, output: &mut [u8]) {
+        use rayon::prelude::*;
+
+        let injection =
+            |(feature, index): (usize, usize)| &self.features[feature].injections[index];
+        // Split the output into a separate slice for each disjoint injection, so they can be
+        // written from different threads.
+        let mut targets = Vec::with_capacity(plan.disjoint.len());
+        let mut rest = &mut output[..];
+        let mut rest_offset = 0;
+        for &index in &plan.disjoint {
+            let injection = injection(index);
+            let (_, tail) = std::mem::take(&mut rest).split_at_mut(injection.offset - rest_offset);
+            let (target, tail) = tail.split_at_mut(injection.bytes.len());
+            targets.push((target, &injection.bytes[..]));
+            rest = tail;
+            rest_offset = injection.offset + injection.bytes.len();
+        }
+        targets
+            .into_par_iter()
+            .for_each(|(target, bytes)| target.copy_from_slice(bytes));
+
+        for &index in &plan.overlapping {
+            let injection = injection(index);
+            apply_patch(output, injection.offset, &injection.bytes);
+        }
+    }
+}
+
+/// Install UserPatch 1.5 into a buffer containing a 1.0c executable.
+///
+/// Like [`Patcher`], this always produces the same output for the same input and options. It
+/// installs the built-in patch data; use
+/// [`install_into_with_table`](crate::install_into_with_table) to install other patch data.
+///
+/// # Panics
+///
+/// Panics if the executable cannot be patched. Use [`install_into_checked`] to handle errors.
+pub fn install_into(exe_buffer: &[u8], options: &InstallOptions) -> Vec<u8> {
+    install_into_checked(exe_buffer, options)
+        .unwrap_or_else(|err| panic!("could not install UserPatch: {}", err))
+}
+
+/// Install UserPatch 1.5 into a buffer containing a 1.0c executable, taking ownership of the
+/// buffer.
+///
+/// The buffer is extended in place instead of being copied, so only one executable is held in
+/// memory. See [`Patcher::patch_in_place`].
+///
+/// # Panics
+///
+/// Panics if the executable cannot be patched. Use a [`Patcher`] to handle errors.
+pub fn install_into_owned(mut exe_buffer: Vec<u8>, options: &InstallOptions) -> Vec<u8> {
+    Patcher::new(options)
+        .and_then(|patcher| patcher.patch_in_place(&mut exe_buffer))
+        .unwrap_or_else(|err| panic!("could not install UserPatch: {}", err));
+    exe_buffer
+}
+
+/// Install UserPatch 1.5 into a buffer containing a 1.0c executable, returning an error instead
+/// of panicking if the executable cannot be patched.
+///
+/// This is a shorthand for creating a [`Patcher`] and patching a single executable.
+pub fn install_into_checked(
+    exe_buffer: &[u8],
+    options: &InstallOptions,
+) -> Result<Vec<u8>, PatchError> {
+    Patcher::new(options).and_then(|patcher| patcher.patch(exe_buffer))
+}
+
+/// Install UserPatch 1.5 into a buffer containing a 1.0c executable, calling `progress` after each
+/// feature is applied.
+///
+/// # Panics
+///
+/// Panics if the executable cannot be patched. Use a [`Patcher`] to handle errors.
+pub fn install_with_progress(
+    exe_buffer: &[u8],
+    options: &InstallOptions,
+    progress: &mut dyn FnMut(InstallProgress),
+) -> Vec<u8> {
+    Patcher::new(options)
+        .and_then(|patcher| patcher.patch_with_progress(exe_buffer, progress))
+        .unwrap_or_else(|err| panic!("could not install UserPatch: {}", err))
+}
+
+/// Install UserPatch 1.5 into a buffer containing a 1.0c executable, writing the patched
+/// executable into `output`.
+///
+/// This is like [`install_into`], but reuses the allocation of `output`. See
+/// [`Patcher::patch_into`].
+///
+/// # Panics
+///
+/// Panics if the executable cannot be patched. Use a [`Patcher`] to handle errors.
+pub fn install_into_buf(exe_buffer: &[u8], options: &InstallOptions, output: &mut Vec<u8>) {
+    Patcher::new(options)
+        .and_then(|patcher| patcher.patch_into(exe_buffer, output))
+        .unwrap_or_else(|err| panic!("could not install UserPatch: {}", err))
+}
+
+/// Read a 1.0c executable from `input`, install UserPatch 1.5, and write the patched executable to
+/// `output`.
+///
+/// If the executable cannot be patched, the [`PatchError`] is returned inside an `io::Error` of
+/// kind `InvalidData`, and `output` is not written.
+pub fn install_file(input: &Path, output: &Path, options: &InstallOptions) -> io::Result<()> {
+    let patched = read_and_patch(fs::File::open(input)?, options)?;
+    fs::write(output, patched)
+}
+
+/// Read a 1.0c executable from `reader`, install UserPatch 1.5, and write the patched executable
+/// to `writer`.
+///
+/// Only a single buffer for the patched executable is allocated. If the executable cannot be
+/// patched, the [`PatchError`] is returned inside an `io::Error` of kind `InvalidData`, and nothing
+/// is written.
+pub fn install_to_writer(
+    reader: impl Read,
+    mut writer: impl Write,
+    options: &InstallOptions,
+) -> io::Result<()> {
+    let patched = read_and_patch(reader, options)?;
+    writer.write_all(&patched)?;
+    writer.flush()
+}
+
+/// Read an executable into a buffer that is large enough for the patched executable, and patch it
+/// in place.
+fn read_and_patch(mut reader: impl Read, options: &InstallOptions) -> io::Result<Vec<u8>> {
+    let invalid_data = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+    let patcher = Patcher::new(options).map_err(invalid_data)?;
+    let mut buffer = Vec::with_capacity(patcher.output_size());
+    reader.read_to_end(&mut buffer)?;
+    patcher.patch_in_place(&mut buffer).map_err(invalid_data)?;
+    Ok(buffer)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::InstallOptions;
+    use crate::{InstallOptions, UnknownFeature};
     use std::fs::{read, write};
 
     #[test]
@@ -172,6 +1154,447 @@ mod tests {
         );
     }
 
+    #[test]
+    fn find_features() {
+        let feature = get_feature("Multiple building queue").unwrap();
+        assert!(feature.optional);
+        assert_eq!(
+            feature.description.as_deref(),
+            Some("Queue units in multiple selected buildings at once.")
+        );
+        assert!(get_available_features()
+            .iter()
+            .all(|feature| feature.description.is_some()));
+        assert_eq!(feature.category, FeatureCategory::Interface);
+        assert!(get_available_features()
+            .iter()
+            .all(|feature| feature.category != FeatureCategory::Other));
+
+        let categories = features_by_category();
+        assert_eq!(
+            categories.values().map(Vec::len).sum::<usize>(),
+            get_available_features().len()
+        );
+        assert!(categories[&FeatureCategory::Graphics]
+            .iter()
+            .any(|feature| feature.name == "Darken mini-map grey"));
+        assert!(get_feature("multiple building queue").is_none());
+        assert_eq!(
+            feature.patch_len(),
+            feature.offsets().iter().map(|(_, len)| len).sum::<usize>()
+        );
+        assert_eq!(
+            feature.offsets(),
+            feature
+                .injections()
+                .map(|injection| (injection.address, injection.bytes.len()))
+                .collect::<Vec<_>>()
+        );
+        assert!(feature.patch_len() > 0);
+        assert_eq!(
+            get_feature_ignore_case("multiple BUILDING queue").map(|feature| &feature.name),
+            Some(&feature.name)
+        );
+        assert!(get_feature("Not a feature").is_none());
+    }
+
+    #[test]
+    fn set_features_by_name() {
+        let mut options = InstallOptions::bare();
+        options
+            .set_feature("Multiple building queue", true)
+            .unwrap();
+        assert!(options.multiple_queue);
+        options
+            .set_feature("Disable water movement", false)
+            .unwrap();
+        assert!(options.water_movement);
+        options
+            .set_feature("Windowed fullscreen mode", true)
+            .unwrap();
+        let features = configure_features(get_available_features(), &options);
+        let enabled = |name: &str| features.iter().any(|f| f.name == name && f.enabled());
+        assert!(enabled("Multiple building queue"));
+        assert!(!enabled("Disable water movement"));
+        assert!(enabled("Windowed fullscreen mode"));
+
+        let required = get_available_features()
+            .iter()
+            .find(|feature| !feature.optional)
+            .unwrap();
+        assert_eq!(
+            options.set_feature(&required.name, false),
+            Err(UnknownFeature(required.name.to_string()))
+        );
+        assert!(options.set_feature("Not a feature", true).is_err());
+    }
+
+    #[test]
+    fn describe_inverted_options() {
+        let descriptions = describe_options(&InstallOptions::default());
+        assert!(descriptions
+            .iter()
+            .any(|line| line
+                == "water movement: enabled (feature 'Disable water movement' disabled)"));
+        let descriptions = describe_options(&InstallOptions::bare());
+        assert!(descriptions
+            .iter()
+            .any(|line| line
+                == "water movement: disabled (feature 'Disable water movement' enabled)"));
+    }
+
+    #[test]
+    fn reuse_patcher() {
+        let options = InstallOptions::default().verify_version(false);
+        let patcher = Patcher::new(&options).unwrap();
+        let first = vec![0x90; 0x200000];
+        let second = vec![0xCC; 0x200000];
+        assert_eq!(
+            patcher.patch(&first).unwrap(),
+            install_into(&first, &options)
+        );
+        assert_eq!(
+            patcher.patch(&second).unwrap(),
+            install_into(&second, &options)
+        );
+
+        let mut output = vec![];
+        install_into_buf(&first, &options, &mut output);
+        assert_eq!(output, install_into(&first, &options));
+        let capacity = output.capacity();
+        patcher.patch_into(&second, &mut output).unwrap();
+        assert_eq!(output, install_into(&second, &options));
+        assert_eq!(output.capacity(), capacity);
+
+        let too_large = vec![0; PATCHED_SIZE + 1];
+        assert!(matches!(
+            patcher.patch(&too_large),
+            Err(PatchError::InputTooLarge { .. })
+        ));
+        assert!(matches!(
+            install_into_checked(&too_large, &options),
+            Err(PatchError::InputTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn injection_offsets() {
+        assert_eq!(injection_offset(0x1234, PATCHED_SIZE), Some(0x1234));
+        assert_eq!(injection_offset(0x401234, PATCHED_SIZE), Some(0x1234));
+        assert_eq!(injection_offset(0x7A5010, PATCHED_SIZE), Some(0x293010));
+        assert_eq!(injection_offset(0x350000, PATCHED_SIZE), None);
+
+        let data =
+            crate::load_patch_data("version 1.5\nfeature \"x\"\ninject 0x350000 90".as_bytes())
+                .unwrap();
+        assert!(matches!(
+            Patcher::with_data(&data, &InstallOptions::bare().verify_version(false)),
+            Err(PatchError::OffsetOutOfBounds {
+                offset: 0x350000,
+                len: 1
+            })
+        ));
+        let data =
+            crate::load_patch_data("version 1.5\nfeature \"x\"\ninject 0x7A5010 90".as_bytes())
+                .unwrap();
+        let patched = Patcher::with_data(&data, &InstallOptions::bare().verify_version(false))
+            .unwrap()
+            .patch(&[0u8; 0x20])
+            .unwrap();
+        assert_eq!(patched[0x293010], 0x90);
+    }
+
+    #[test]
+    fn cancel_patch() {
+        let patcher = Patcher::new(&InstallOptions::default().verify_version(false)).unwrap();
+        let cancel = AtomicBool::new(true);
+        assert_eq!(
+            patcher.patch_cancellable(&[0; 1024], &cancel),
+            Err(PatchError::Cancelled)
+        );
+    }
+
+    #[test]
+    fn report_progress() {
+        let options = InstallOptions::default().verify_version(false);
+        let exe = vec![0x90; 0x200000];
+        let mut reports = vec![];
+        let patched = install_with_progress(&exe, &options, &mut |progress| {
+            reports.push((
+                progress.feature_id.to_string(),
+                progress.feature_name.to_string(),
+                progress.bytes_written,
+                progress.features_done,
+                progress.features_total,
+            ))
+        });
+        assert_eq!(patched, install_into(&exe, &options));
+
+        let patcher = Patcher::new(&options).unwrap();
+        assert_eq!(reports.len(), patcher.features.len());
+        for (index, (id, name, bytes_written, done, total)) in reports.iter().enumerate() {
+            let feature = &patcher.features[index];
+            assert_eq!(*id, feature.id);
+            assert_eq!(*name, feature.name);
+            let expected: usize = feature.injections.iter().map(|i| i.bytes.len()).sum();
+            assert_eq!(*bytes_written, expected);
+            assert_eq!(*done, index + 1);
+            assert_eq!(*total, reports.len());
+        }
+    }
+
+    /// Get the addresses of the injections whose annotation lists a different number of bytes
+    /// than they write.
+    fn mismatched_annotations(features: &[Feature]) -> Vec<u32> {
+        features
+            .iter()
+            .flat_map(Feature::injections)
+            .filter(|injection| {
+                injection.annotation.is_some()
+                    && injection.annotated_len() != Some(injection.bytes.len())
+            })
+            .map(|injection| injection.address)
+            .collect()
+    }
+
+    #[test]
+    fn annotations_match_injections() {
+        let annotated = builtin_features()
+            .iter()
+            .flat_map(Feature::injections)
+            .filter(|injection| injection.annotation.is_some())
+            .count();
+        assert!(annotated > 0, "the built-in patch data has no annotations");
+        assert_eq!(
+            mismatched_annotations(builtin_features()),
+            Vec::<u32>::new()
+        );
+    }
+
+    #[test]
+    fn mismatched_annotation() {
+        let mut feature = builtin_features()[0].clone();
+        feature.patches = Cow::Owned(vec![
+            Injection(0x10, Cow::Borrowed(&[0x90]), None, Some("90 ; nop".into())),
+            Injection(
+                0x20,
+                Cow::Borrowed(&[0x90]),
+                None,
+                Some("90 90 ; nop".into()),
+            ),
+            Injection(0x30, Cow::Borrowed(&[0x90]), None, Some("nop".into())),
+            Injection(0x40, Cow::Borrowed(&[0x90]), None, None),
+        ]);
+        assert_eq!(mismatched_annotations(&[feature]), vec![0x20, 0x30]);
+    }
+
+    #[test]
+    fn reproducible_output() {
+        let exe = (0..0x200000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let options = InstallOptions::default().verify_version(false);
+        let first = Patcher::new(&options).unwrap().patch(&exe).unwrap();
+        let second = Patcher::new(&options).unwrap().patch(&exe).unwrap();
+        assert!(first == second, "patched output differs between runs");
+
+        let (patched, sum) = Patcher::new(&options)
+            .unwrap()
+            .patch_with_checksum(&exe)
+            .unwrap();
+        assert!(patched == first);
+        assert_eq!(sum, checksum(&first));
+        let (_, other_sum) = Patcher::new(&InstallOptions::bare().verify_version(false))
+            .unwrap()
+            .patch_with_checksum(&exe)
+            .unwrap();
+        assert_ne!(other_sum, sum);
+    }
+
+    #[test]
+    fn order_independent_options() {
+        let exe = vec![0x90; 0x200000];
+        let mut first = InstallOptions::default().verify_version(false);
+        first.set_feature("Multiple building queue", true).unwrap();
+        first.set_feature("Windowed fullscreen mode", true).unwrap();
+        first.set_feature("Darken mini-map grey", false).unwrap();
+
+        let mut second = InstallOptions::default().verify_version(false);
+        second.set_feature("Darken mini-map grey", false).unwrap();
+        second
+            .set_feature("Windowed fullscreen mode", true)
+            .unwrap();
+        second.multiple_queue = true;
+
+        assert!(install_into(&exe, &first) == install_into(&exe, &second));
+        let names = |options: &InstallOptions| {
+            let (_, report) = Patcher::new(options)
+                .unwrap()
+                .patch_with_report(&exe)
+                .unwrap();
+            report
+                .applied
+                .into_iter()
+                .map(|feature| feature.name)
+                .collect::<Vec<_>>()
+        };
+        let order = names(&first);
+        assert_eq!(order, names(&second));
+        assert_eq!(
+            enabled_features(&first)
+                .iter()
+                .map(|feature| feature.name.to_string())
+                .collect::<Vec<_>>(),
+            order
+        );
+        let available = get_available_features()
+            .iter()
+            .map(|feature| feature.name.to_string())
+            .filter(|name| order.contains(name))
+            .collect::<Vec<_>>();
+        assert_eq!(order, available);
+    }
+
+    #[test]
+    fn patch_in_parallel() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Patcher>();
+
+        let options = InstallOptions::default().verify_version(false);
+        let patcher = std::sync::Arc::new(Patcher::new(&options).unwrap());
+        let expected = patcher.patch(&[0x90; 0x200000]).unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let patcher = patcher.clone();
+                std::thread::spawn(move || patcher.patch(&[0x90; 0x200000]).unwrap())
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap() == expected);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_matches_serial() {
+        let data = crate::load_patch_data(
+            r#"
+version 1.5
+feature "First"
+inject 0x10 0102030405
+inject 0x40 0a0b
+feature "Second"
+inject 0x12 ffff
+inject 0x20 c3
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let options = InstallOptions::bare().verify_version(false);
+        let serial = Patcher::with_data(&data, &options.clone().parallel(false)).unwrap();
+        let parallel = Patcher::with_data(&data, &options).unwrap();
+        let plan = parallel.parallel.as_ref().unwrap();
+        assert_eq!(plan.disjoint, vec![(1, 1), (0, 1)]);
+        assert_eq!(plan.overlapping, vec![(0, 0), (1, 0)]);
+
+        let exe = [0x90; 0x100];
+        let expected = serial.patch(&exe).unwrap();
+        assert_eq!(&expected[0x10..0x15], &[1, 2, 0xff, 0xff, 5]);
+        assert!(parallel.patch(&exe).unwrap() == expected);
+
+        let options = InstallOptions::default().verify_version(false);
+        let serial = Patcher::new(&options.clone().parallel(false)).unwrap();
+        let parallel = Patcher::new(&options).unwrap();
+        assert!(parallel.patch(&exe).unwrap() == serial.patch(&exe).unwrap());
+    }
+
+    #[test]
+    fn patch_files() {
+        let dir = std::env::temp_dir().join(format!("aoc-userpatch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("age2_x1.exe");
+        let output = dir.join("age2_x1.up.exe");
+        let exe = vec![0x90; 0x200000];
+        write(&input, &exe).unwrap();
+
+        let options = InstallOptions::default().verify_version(false);
+        install_file(&input, &output, &options).unwrap();
+        assert_eq!(read(&output).unwrap(), install_into(&exe, &options));
+
+        let err = install_file(&input, &output, &InstallOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = install_file(&dir.join("missing.exe"), &output, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn report_applied_features() {
+        let options = InstallOptions::bare().verify_version(false);
+        let exe = vec![0x90; 0x200000];
+        let patcher = Patcher::new(&options).unwrap();
+        let (patched, report) = patcher.patch_with_report(&exe).unwrap();
+        assert_eq!(patched, install_into(&exe, &options));
+
+        assert_eq!(report.applied.len(), patcher.features.len());
+        assert_eq!(report.applied[0].name, "Pre-patch");
+        assert!(report
+            .skipped
+            .contains(&"Multiple building queue".to_string()));
+        assert!(!report
+            .applied
+            .iter()
+            .any(|feature| report.skipped.contains(&feature.name)));
+        for feature in &report.applied {
+            assert!(feature
+                .ranges
+                .iter()
+                .all(|range| range.end <= patched.len()));
+        }
+    }
+
+    #[test]
+    fn custom_output_size() {
+        let options = InstallOptions::default().verify_version(false);
+        let patcher = Patcher::new(&options).unwrap();
+        assert_eq!(patcher.output_size(), PATCHED_SIZE);
+        let required = patcher.required_size();
+        assert!(required > 0 && required <= PATCHED_SIZE);
+
+        let exe = vec![0x90; required];
+        let size = required + 0x100;
+        let patcher = Patcher::new(&options.clone().output_size(size)).unwrap();
+        let patched = patcher.patch(&exe).unwrap();
+        assert_eq!(patched.len(), size);
+        assert_eq!(patched[..], install_into(&exe, &options)[..size]);
+        assert!(matches!(
+            patcher.patch(&vec![0x90; size + 1]),
+            Err(PatchError::InputTooLarge { .. })
+        ));
+        assert!(matches!(
+            Patcher::new(&options.output_size(required - 1)),
+            Err(PatchError::OffsetOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn patch_streams() {
+        let options = InstallOptions::default().verify_version(false);
+        let exe = vec![0x90; 0x200000];
+        let mut output = vec![];
+        install_to_writer(&exe[..], &mut output, &options).unwrap();
+        assert_eq!(output, install_into(&exe, &options));
+
+        let mut buffer = exe.clone();
+        let patcher = Patcher::new(&options).unwrap();
+        patcher.patch_in_place(&mut buffer).unwrap();
+        assert_eq!(buffer, output);
+        assert_eq!(install_into_owned(exe.clone(), &options), output);
+
+        let mut output = vec![];
+        let err = install_to_writer(&exe[..], &mut output, &InstallOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(output.is_empty());
+    }
+
     #[test]
     fn produce_bare_up15() {
         use std::{env, path::PathBuf};
@@ -0,0 +1,294 @@
+use crate::patch::PreparedFeature;
+#[cfg(test)]
+use crate::patch::PATCHED_SIZE;
+use crate::{enabled_features, InstallOptions, PatchError, Patcher};
+use std::fmt::{self, Display, Formatter};
+
+/// Two features that write to the same bytes.
+///
+/// Overlapping injections are not necessarily a problem: some features deliberately replace code
+/// that another feature injected. The feature that is applied last wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    /// The feature that is applied first.
+    pub first: String,
+    /// The feature that is applied later, overwriting some of the bytes of `first`.
+    pub second: String,
+    /// File offset of the first overlapping byte.
+    pub offset: usize,
+}
+
+/// A combination of features that does not work, see [`validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeatureConflict {
+    /// Two features are enabled that cannot be installed together.
+    Conflict {
+        /// The feature that lists the conflict.
+        feature: String,
+        /// The other enabled feature.
+        other: String,
+    },
+    /// A feature is enabled, but a feature that it requires is not.
+    MissingRequirement {
+        /// The enabled feature.
+        feature: String,
+        /// The feature that it requires.
+        requires: String,
+    },
+}
+
+impl Display for FeatureConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FeatureConflict::Conflict { feature, other } => write!(
+                f,
+                "Feature \"{}\" cannot be enabled together with \"{}\"",
+                feature, other
+            ),
+            FeatureConflict::MissingRequirement { feature, requires } => write!(
+                f,
+                "Feature \"{}\" requires \"{}\" to be enabled",
+                feature, requires
+            ),
+        }
+    }
+}
+
+/// Describes what installing UserPatch would do.
+#[derive(Debug, Clone)]
+pub struct PreflightReport {
+    /// Names of the features that would be installed, in the order they are applied.
+    pub features: Vec<String>,
+    /// Number of injections that would be written.
+    pub injections: usize,
+    /// Total number of bytes that would be written.
+    pub bytes_written: usize,
+    /// Size of the patched executable.
+    pub output_size: usize,
+    /// Features whose injections overlap.
+    pub overlaps: Vec<Overlap>,
+}
+
+/// Check if UserPatch can be installed into an executable with the given options, without writing
+/// anything.
+///
+/// This runs the same validations as an install: all injections must be in bounds, the executable
+/// must fit in the patched executable, and unless [`InstallOptions::verify_version`] is disabled,
+/// it must look like an unpatched 1.0c executable (see [`crate::check_version`]). With
+/// [`InstallOptions::strict_context`], the original bytes are checked too. The first problem that
+/// would block the install is returned as an error.
+pub fn preflight(
+    exe_buffer: &[u8],
+    options: &InstallOptions,
+) -> Result<PreflightReport, PatchError> {
+    let patcher = Patcher::new(options)?;
+    patcher.check_input(exe_buffer)?;
+    if patcher.strict_context {
+        patcher.check_context(exe_buffer)?;
+    }
+    let ranges = injection_ranges(&patcher.features);
+
+    Ok(PreflightReport {
+        features: patcher
+            .features
+            .iter()
+            .map(|feature| feature.name.to_string())
+            .collect(),
+        injections: ranges.len(),
+        bytes_written: ranges.iter().map(|(start, end, _)| end - start).sum(),
+        output_size: patcher.output_size(),
+        overlaps: find_overlaps(&patcher.features),
+    })
+}
+
+/// Find the features whose injections overlap when installing with the given options.
+///
+/// See [`Overlap`]. Use [`InstallOptions::reject_overlaps`] to make an install fail if there are
+/// any.
+pub fn overlapping_injections(options: &InstallOptions) -> Result<Vec<Overlap>, PatchError> {
+    Ok(find_overlaps(&Patcher::new(options)?.features))
+}
+
+/// Check that the features enabled by the options work together.
+///
+/// The rules are listed on each feature in [`Feature::requires`](crate::Feature::requires) and
+/// [`Feature::conflicts_with`](crate::Feature::conflicts_with). Installing does not check them, so
+/// this can be used to warn about a configuration before installing it.
+pub fn validate(options: &InstallOptions) -> Result<(), Vec<FeatureConflict>> {
+    let enabled = enabled_features(options);
+    let is_enabled = |name: &str| enabled.iter().any(|feature| feature.name == name);
+    let mut conflicts = vec![];
+    for feature in &enabled {
+        for requires in feature.requires.iter() {
+            if !is_enabled(requires) {
+                conflicts.push(FeatureConflict::MissingRequirement {
+                    feature: feature.name.to_string(),
+                    requires: requires.to_string(),
+                });
+            }
+        }
+        for other in feature.conflicts_with.iter() {
+            if is_enabled(other) {
+                conflicts.push(FeatureConflict::Conflict {
+                    feature: feature.name.to_string(),
+                    other: other.to_string(),
+                });
+            }
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(conflicts)
+    }
+}
+
+/// Get the byte ranges written by each injection, with the index of their feature, sorted by
+/// offset.
+fn injection_ranges(features: &[PreparedFeature]) -> Vec<(usize, usize, usize)> {
+    let mut ranges = vec![];
+    for (index, feature) in features.iter().enumerate() {
+        for injection in &feature.injections {
+            let offset = injection.offset;
+            ranges.push((offset, offset + injection.bytes.len(), index));
+        }
+    }
+    ranges.sort();
+    ranges
+}
+
+/// Find the pairs of features whose injections overlap.
+pub(crate) fn find_overlaps(features: &[PreparedFeature]) -> Vec<Overlap> {
+    let ranges = injection_ranges(features);
+    let mut overlaps: Vec<Overlap> = vec![];
+    for (i, (_, end, index)) in ranges.iter().enumerate() {
+        for (other_start, _, other_index) in &ranges[i + 1..] {
+            if other_start >= end {
+                break;
+            }
+            if index == other_index {
+                continue;
+            }
+            let (first, second) = if index < other_index {
+                (*index, *other_index)
+            } else {
+                (*other_index, *index)
+            };
+            let first = features[first].name.to_string();
+            let second = features[second].name.to_string();
+            if !overlaps
+                .iter()
+                .any(|overlap| overlap.first == first && overlap.second == second)
+            {
+                overlaps.push(Overlap {
+                    first,
+                    second,
+                    offset: *other_start,
+                });
+            }
+        }
+    }
+    overlaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::tests::fake_exe;
+
+    #[test]
+    fn preflight_checks() {
+        assert!(matches!(
+            preflight(&[0; 0x200], &InstallOptions::bare()),
+            Err(PatchError::UnsupportedVersion { .. })
+        ));
+
+        let mut exe = fake_exe(0x14C, 0x400000);
+        let report = preflight(&exe, &InstallOptions::bare()).unwrap();
+        assert!(!report.features.is_empty());
+        assert!(report.bytes_written >= report.injections);
+
+        let patched = crate::install_into(&exe, &InstallOptions::bare());
+        assert!(matches!(
+            preflight(&patched, &InstallOptions::bare()),
+            Err(PatchError::AlreadyPatched)
+        ));
+        let options = InstallOptions::bare().verify_version(false);
+        assert!(preflight(&[0; 0x200], &options).is_ok());
+        assert!(preflight(&patched, &options).is_ok());
+
+        exe.resize(PATCHED_SIZE + 1, 0);
+        assert!(matches!(
+            preflight(&exe, &InstallOptions::bare()),
+            Err(PatchError::InputTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_overlaps() {
+        let data = crate::load_patch_data(
+            r#"
+version 1.5
+feature "First"
+inject 0x400000 909090
+feature "Second"
+inject 0x400002 c3
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let options = InstallOptions::bare().verify_version(false);
+        let overlap = Overlap {
+            first: "First".to_string(),
+            second: "Second".to_string(),
+            offset: 2,
+        };
+        let patcher = data.patcher(&options).unwrap();
+        assert_eq!(find_overlaps(&patcher.features), vec![overlap.clone()]);
+        assert_eq!(
+            data.patcher(&options.reject_overlaps(true)).err(),
+            Some(PatchError::OverlappingInjections(overlap))
+        );
+        assert!(overlapping_injections(&InstallOptions::bare()).is_ok());
+    }
+
+    #[test]
+    fn validate_combinations() {
+        assert_eq!(validate(&InstallOptions::default()), Ok(()));
+        assert_eq!(
+            validate(&InstallOptions::all_optional()).map_err(|c| c.len()),
+            Err(1)
+        );
+
+        let mut options = InstallOptions::bare();
+        options
+            .set_feature("Windowed fullscreen mode", true)
+            .unwrap();
+        options.set_feature("Windowed mode support", false).unwrap();
+        options
+            .set_feature("Snow/ice terrain removal", true)
+            .unwrap();
+        options
+            .set_feature("Disable custom terrains", true)
+            .unwrap();
+        let conflicts = validate(&options).unwrap_err();
+        assert_eq!(
+            conflicts,
+            vec![
+                FeatureConflict::Conflict {
+                    feature: "Snow/ice terrain removal".to_string(),
+                    other: "Disable custom terrains".to_string(),
+                },
+                FeatureConflict::MissingRequirement {
+                    feature: "Windowed fullscreen mode".to_string(),
+                    requires: "Windowed mode support".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            conflicts[1].to_string(),
+            "Feature \"Windowed fullscreen mode\" requires \"Windowed mode support\" to be enabled"
+        );
+    }
+}
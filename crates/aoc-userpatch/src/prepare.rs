@@ -0,0 +1,43 @@
+use crate::{check_version, InstallOptions, PatchError, Patcher};
+use sha2::{Digest, Sha256};
+
+/// Compute the SHA-256 checksum of an executable.
+pub fn checksum(exe: &[u8]) -> [u8; 32] {
+    Sha256::digest(exe).into()
+}
+
+/// Verify, check, and patch a clean 1.0c executable in one go.
+///
+/// This checks that the SHA-256 checksum of `clean_exe` matches `expected_checksum`, that it looks
+/// like a 1.0c executable (see [`check_version`]), and then installs UserPatch with the given
+/// options.
+pub fn prepare(
+    clean_exe: &[u8],
+    expected_checksum: &[u8; 32],
+    options: &InstallOptions,
+) -> Result<Vec<u8>, PatchError> {
+    let actual = checksum(clean_exe);
+    if actual != *expected_checksum {
+        return Err(PatchError::ChecksumMismatch {
+            expected: *expected_checksum,
+            actual,
+        });
+    }
+    check_version(clean_exe)?;
+    Patcher::new(options)?.patch(clean_exe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_wrong_checksum() {
+        let exe = b"MZ not really an executable";
+        let result = prepare(exe, &[0; 32], &InstallOptions::bare());
+        assert!(matches!(result, Err(PatchError::ChecksumMismatch { .. })));
+
+        let result = prepare(exe, &checksum(exe), &InstallOptions::bare());
+        assert!(matches!(result, Err(PatchError::UnsupportedVersion { .. })));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::patch::configure_features;
+use crate::{get_available_features, Feature, InstallOptions, PatchData};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display, Formatter},
+};
+
+/// The set of enabled features that affect game sync.
+///
+/// All players in a multiplayer game must have the same sync-affecting features enabled, or the
+/// game will go out of sync. A lobby host can advertise its profile, and clients can check that
+/// their own profile matches before joining.
+///
+/// Features are identified by their stable id (see [`crate::Feature::id`]). With the `serde`
+/// feature, a profile serializes as a list of feature ids.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct SyncProfile {
+    features: BTreeSet<String>,
+}
+
+impl SyncProfile {
+    /// Get the sync profile for an install of the built-in patch data with the given options.
+    pub fn from_options(options: &InstallOptions) -> Self {
+        Self::from_data(PatchData::builtin(), options)
+    }
+
+    /// Get the sync profile for an install of custom patch data with the given options.
+    pub fn from_data(data: &PatchData, options: &InstallOptions) -> Self {
+        let features = configure_features(data.features(), options)
+            .iter()
+            .filter(|feature| feature.affects_sync && feature.enabled())
+            .map(|feature| feature.id())
+            .collect();
+        Self { features }
+    }
+
+    /// Iterate over the ids of the features in this profile.
+    pub fn features(&self) -> impl Iterator<Item = &str> {
+        self.features.iter().map(String::as_str)
+    }
+
+    /// Check if two profiles can play together.
+    pub fn matches(&self, other: &SyncProfile) -> bool {
+        self.features == other.features
+    }
+
+    /// Get a SHA-256 hash of the features in this profile.
+    ///
+    /// The signature only depends on the ids of the enabled sync-affecting features, so it is
+    /// stable across builds and platforms. Players can compare signatures instead of full profiles
+    /// to check that they can play together.
+    pub fn signature(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for id in &self.features {
+            hasher.update(id.as_bytes());
+            hasher.update(b"\n");
+        }
+        hasher.finalize().into()
+    }
+
+    /// Find the features that are enabled in only one of the profiles.
+    pub fn diff(&self, other: &SyncProfile) -> SyncProfileDiff {
+        SyncProfileDiff {
+            only_self: self.features.difference(&other.features).cloned().collect(),
+            only_other: other.features.difference(&self.features).cloned().collect(),
+        }
+    }
+}
+
+/// Get the features that can be installed and that affect game sync.
+pub fn sync_affecting_features() -> Vec<&'static Feature> {
+    get_available_features()
+        .iter()
+        .filter(|feature| feature.affects_sync)
+        .collect()
+}
+
+/// Differences between two sync profiles.
+///
+/// The `Display` implementation produces a human-readable description of the mismatch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncProfileDiff {
+    /// Features that are only enabled in the profile that `diff` was called on.
+    pub only_self: Vec<String>,
+    /// Features that are only enabled in the profile that was passed to `diff`.
+    pub only_other: Vec<String>,
+}
+
+impl SyncProfileDiff {
+    /// Are the profiles the same?
+    pub fn is_empty(&self) -> bool {
+        self.only_self.is_empty() && self.only_other.is_empty()
+    }
+}
+
+impl Display for SyncProfileDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "sync profiles match");
+        }
+        if !self.only_self.is_empty() {
+            write!(f, "only enabled locally: {}", self.only_self.join(", "))?;
+        }
+        if !self.only_other.is_empty() {
+            if !self.only_self.is_empty() {
+                write!(f, "; ")?;
+            }
+            write!(f, "only enabled remotely: {}", self.only_other.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_patch_data;
+
+    const ARCHIVE: &str = r#"
+version 1.5
+feature affects_sync "Pre-patch"
+inject 0x400000 90
+feature optional affects_sync "Population caps to 1000"
+inject 0x400010 90
+feature optional "Windowed mode support"
+inject 0x400020 90
+"#;
+
+    #[test]
+    fn compare_profiles() {
+        let data = load_patch_data(ARCHIVE.as_bytes()).unwrap();
+        let bare = SyncProfile::from_data(&data, &InstallOptions::bare());
+        assert_eq!(bare.features().collect::<Vec<_>>(), vec!["pre-patch"]);
+        assert!(bare.matches(&SyncProfile::from_data(&data, &InstallOptions::bare())));
+        assert!(bare.diff(&bare).is_empty());
+
+        let default = SyncProfile::from_data(&data, &Default::default());
+        assert!(!bare.matches(&default));
+        let diff = bare.diff(&default);
+        assert_eq!(diff.only_self, Vec::<String>::new());
+        assert_eq!(diff.only_other, vec!["population-caps-to-1000"]);
+        assert_eq!(
+            diff.to_string(),
+            "only enabled remotely: population-caps-to-1000"
+        );
+    }
+
+    #[test]
+    fn signature_tracks_sync_features() {
+        let mut options = InstallOptions::bare();
+        let signature = options.sync_signature();
+        options.set_feature("Darken mini-map red", true).unwrap();
+        assert_eq!(options.sync_signature(), signature);
+        options
+            .set_feature("Disable weather system", false)
+            .unwrap();
+        assert_ne!(options.sync_signature(), signature);
+        options.set_feature("Disable weather system", true).unwrap();
+        assert_eq!(options.sync_signature(), signature);
+
+        assert!(sync_affecting_features()
+            .iter()
+            .all(|feature| feature.affects_sync));
+        assert!(sync_affecting_features()
+            .iter()
+            .any(|feature| feature.name == "Disable weather system"));
+    }
+}
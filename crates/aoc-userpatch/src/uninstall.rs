@@ -0,0 +1,387 @@
+use crate::{checksum, InstallOptions, PatchError, Patcher};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+/// Magic bytes at the start of a serialized journal.
+const JOURNAL_MAGIC: &[u8; 4] = b"UPJ1";
+
+/// Failed to uninstall UserPatch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UninstallError {
+    /// The buffer does not have the size of a patched executable.
+    SizeMismatch {
+        /// Size of the buffer in bytes.
+        size: usize,
+        /// Size of a patched executable in bytes.
+        expected: usize,
+    },
+    /// A region in the journal is outside of the patched executable.
+    OffsetOutOfBounds {
+        /// File offset of the region.
+        offset: usize,
+        /// Size of the region in bytes.
+        len: usize,
+    },
+    /// The restored executable does not match the executable that was patched, so the buffer was
+    /// not produced by the install that the journal belongs to.
+    ChecksumMismatch,
+    /// The serialized journal is malformed.
+    InvalidJournal,
+    /// The feature to revert was not installed.
+    FeatureNotInstalled(String),
+    /// The feature to revert writes to the same bytes as another installed feature, so reverting
+    /// it would break the other feature.
+    OverlappingFeature {
+        /// The feature to revert.
+        feature: String,
+        /// The other feature.
+        other: String,
+    },
+}
+
+impl Display for UninstallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            UninstallError::SizeMismatch { size, expected } => write!(
+                f,
+                "Input is not a patched executable: got {} bytes, expected {}",
+                size, expected
+            ),
+            UninstallError::OffsetOutOfBounds { offset, len } => write!(
+                f,
+                "Journal region of {} bytes at {:#x} is out of bounds",
+                len, offset
+            ),
+            UninstallError::ChecksumMismatch => {
+                write!(f, "Restored executable does not match the original")
+            }
+            UninstallError::InvalidJournal => write!(f, "Install journal is malformed"),
+            UninstallError::FeatureNotInstalled(feature) => {
+                write!(f, "Feature \"{}\" is not installed", feature)
+            }
+            UninstallError::OverlappingFeature { feature, other } => write!(
+                f,
+                "Feature \"{}\" overlaps with \"{}\" and cannot be reverted on its own",
+                feature, other
+            ),
+        }
+    }
+}
+
+impl Error for UninstallError {}
+
+/// The original bytes that an install overwrote, so the install can be undone later.
+///
+/// A journal is much smaller than the original executable. Use [`Patcher::patch_with_journal`] to
+/// get one, store it with [`InstallJournal::to_bytes`], and pass it to [`uninstall_from`] to
+/// recover the original executable from the patched one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallJournal {
+    /// Size of the original executable.
+    original_len: usize,
+    /// Size of the patched executable.
+    patched_len: usize,
+    /// SHA-256 checksum of the original executable.
+    original_checksum: [u8; 32],
+    /// The regions that were overwritten.
+    regions: Vec<JournalRegion>,
+}
+
+/// A region that was overwritten by an install.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct JournalRegion {
+    /// Name of the feature that overwrote the region.
+    feature: String,
+    /// File offset of the region.
+    offset: usize,
+    /// Original contents of the region.
+    original: Vec<u8>,
+}
+
+impl JournalRegion {
+    fn end(&self) -> usize {
+        self.offset + self.original.len()
+    }
+}
+
+impl InstallJournal {
+    /// Record the bytes that `patcher` will overwrite in `exe_buffer`.
+    ///
+    /// `exe_buffer` must fit in a patched executable.
+    pub(crate) fn record(patcher: &Patcher, exe_buffer: &[u8]) -> Self {
+        let mut padded = exe_buffer.to_vec();
+        padded.resize(patcher.output_size(), 0);
+        let regions = patcher
+            .features
+            .iter()
+            .flat_map(|feature| {
+                feature
+                    .injections
+                    .iter()
+                    .map(move |injection| (feature, injection))
+            })
+            .map(|(feature, injection)| {
+                let range = injection.offset..injection.offset + injection.bytes.len();
+                JournalRegion {
+                    feature: feature.name.to_string(),
+                    offset: injection.offset,
+                    original: padded[range].to_vec(),
+                }
+            })
+            .collect();
+        Self {
+            original_len: exe_buffer.len(),
+            patched_len: patcher.output_size(),
+            original_checksum: checksum(exe_buffer),
+            regions,
+        }
+    }
+
+    /// Size of the original executable in bytes.
+    pub fn original_len(&self) -> usize {
+        self.original_len
+    }
+
+    /// Serialize the journal, for example to store it next to the patched executable.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = JOURNAL_MAGIC.to_vec();
+        bytes.extend_from_slice(&(self.original_len as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.patched_len as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.original_checksum);
+        bytes.extend_from_slice(&(self.regions.len() as u32).to_le_bytes());
+        for region in &self.regions {
+            bytes.extend_from_slice(&(region.feature.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(region.feature.as_bytes());
+            bytes.extend_from_slice(&(region.offset as u32).to_le_bytes());
+            bytes.extend_from_slice(&(region.original.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&region.original);
+        }
+        bytes
+    }
+
+    /// Read a journal that was serialized with [`InstallJournal::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, UninstallError> {
+        let mut reader = Reader(bytes);
+        if reader.take(4)? != JOURNAL_MAGIC {
+            return Err(UninstallError::InvalidJournal);
+        }
+        let original_len = reader.read_u32()? as usize;
+        let patched_len = reader.read_u32()? as usize;
+        let mut original_checksum = [0; 32];
+        original_checksum.copy_from_slice(reader.take(32)?);
+        let count = reader.read_u32()?;
+        let mut regions = vec![];
+        for _ in 0..count {
+            let len = reader.read_u32()? as usize;
+            let feature = std::str::from_utf8(reader.take(len)?)
+                .map_err(|_| UninstallError::InvalidJournal)?
+                .to_string();
+            let offset = reader.read_u32()? as usize;
+            let len = reader.read_u32()? as usize;
+            regions.push(JournalRegion {
+                feature,
+                offset,
+                original: reader.take(len)?.to_vec(),
+            });
+        }
+        if !reader.0.is_empty() {
+            return Err(UninstallError::InvalidJournal);
+        }
+        Ok(Self {
+            original_len,
+            patched_len,
+            original_checksum,
+            regions,
+        })
+    }
+}
+
+/// Reads the fields of a serialized journal.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], UninstallError> {
+        if self.0.len() < len {
+            return Err(UninstallError::InvalidJournal);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, UninstallError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
+
+/// Recover the original executable from an executable that was patched with a journal.
+///
+/// The original bytes of each region in the journal are restored, and the buffer is truncated to
+/// the original size. The result is checked against the checksum of the original executable.
+pub fn uninstall_from(
+    patched_buffer: &[u8],
+    journal: &InstallJournal,
+) -> Result<Vec<u8>, UninstallError> {
+    check_size(patched_buffer, journal)?;
+    let mut output = patched_buffer.to_vec();
+    // All regions were recorded from the unpatched executable, so overlapping regions restore the
+    // same bytes and the order does not matter.
+    restore(&mut output, journal.regions.iter())?;
+    output.truncate(journal.original_len);
+
+    if checksum(&output) != journal.original_checksum {
+        return Err(UninstallError::ChecksumMismatch);
+    }
+    Ok(output)
+}
+
+/// Revert a single feature of an executable that was patched with a journal, leaving the other
+/// features installed.
+///
+/// The original bytes of the regions that the feature overwrote are restored. This fails if the
+/// feature overlaps with another installed feature, because the bytes of the other feature cannot
+/// be restored. Reverting a feature that was already reverted has no effect, and the journal can
+/// still be used to uninstall completely with [`uninstall_from`] afterwards.
+pub fn revert_feature(
+    patched_buffer: &[u8],
+    journal: &InstallJournal,
+    feature_name: &str,
+) -> Result<Vec<u8>, UninstallError> {
+    check_size(patched_buffer, journal)?;
+    let (regions, others): (Vec<_>, Vec<_>) = journal
+        .regions
+        .iter()
+        .partition(|region| region.feature == feature_name);
+    if regions.is_empty() {
+        return Err(UninstallError::FeatureNotInstalled(
+            feature_name.to_string(),
+        ));
+    }
+    for region in &regions {
+        if let Some(other) = others
+            .iter()
+            .find(|other| other.offset < region.end() && region.offset < other.end())
+        {
+            return Err(UninstallError::OverlappingFeature {
+                feature: feature_name.to_string(),
+                other: other.feature.clone(),
+            });
+        }
+    }
+
+    let mut output = patched_buffer.to_vec();
+    restore(&mut output, regions.into_iter())?;
+    Ok(output)
+}
+
+/// Check that a buffer has the size of the executable that the journal belongs to.
+fn check_size(patched_buffer: &[u8], journal: &InstallJournal) -> Result<(), UninstallError> {
+    if patched_buffer.len() != journal.patched_len || journal.original_len > journal.patched_len {
+        return Err(UninstallError::SizeMismatch {
+            size: patched_buffer.len(),
+            expected: journal.patched_len,
+        });
+    }
+    Ok(())
+}
+
+/// Write the original bytes of the regions back into the buffer.
+fn restore<'a>(
+    buffer: &mut [u8],
+    regions: impl Iterator<Item = &'a JournalRegion>,
+) -> Result<(), UninstallError> {
+    for region in regions {
+        let target = buffer.get_mut(region.offset..region.end()).ok_or(
+            UninstallError::OffsetOutOfBounds {
+                offset: region.offset,
+                len: region.original.len(),
+            },
+        )?;
+        target.copy_from_slice(&region.original);
+    }
+    Ok(())
+}
+
+/// Install UserPatch 1.5 into a buffer containing a 1.0c executable, and record the bytes that
+/// were overwritten so features can be reverted with [`revert_feature`], or the install undone
+/// with [`uninstall_from`].
+///
+/// This is a shorthand for creating a [`Patcher`] and calling [`Patcher::patch_with_journal`].
+pub fn install_into_with_journal(
+    exe_buffer: &[u8],
+    options: &InstallOptions,
+) -> Result<(Vec<u8>, InstallJournal), PatchError> {
+    Patcher::new(options)?.patch_with_journal(exe_buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enabled_features, overlapping_injections};
+
+    #[test]
+    fn round_trip() {
+        let original: Vec<u8> = (0..0x200000).map(|i| (i % 251) as u8).collect();
+        let patcher = Patcher::new(&InstallOptions::default().verify_version(false)).unwrap();
+        let (patched, journal) = patcher.patch_with_journal(&original).unwrap();
+        assert_eq!(patched, patcher.patch(&original).unwrap());
+        assert_eq!(journal.original_len(), original.len());
+
+        let journal = InstallJournal::from_bytes(&journal.to_bytes()).unwrap();
+        assert_eq!(uninstall_from(&patched, &journal).unwrap(), original);
+
+        let other = patcher.patch(&[0x90; 0x200000]).unwrap();
+        assert_eq!(
+            uninstall_from(&other, &journal),
+            Err(UninstallError::ChecksumMismatch)
+        );
+        assert!(matches!(
+            uninstall_from(&original, &journal),
+            Err(UninstallError::SizeMismatch { .. })
+        ));
+        assert_eq!(
+            InstallJournal::from_bytes(&journal.to_bytes()[..40]),
+            Err(UninstallError::InvalidJournal)
+        );
+    }
+
+    #[test]
+    fn revert_single_feature() {
+        let original: Vec<u8> = (0..0x200000).map(|i| (i % 251) as u8).collect();
+        let options = InstallOptions::default().verify_version(false);
+        let (patched, journal) = install_into_with_journal(&original, &options).unwrap();
+
+        let overlaps = overlapping_injections(&options).unwrap();
+        let feature = enabled_features(&options)
+            .into_iter()
+            .find(|feature| {
+                feature.optional
+                    && !overlaps.iter().any(|overlap| {
+                        overlap.first == feature.name || overlap.second == feature.name
+                    })
+            })
+            .unwrap();
+        let reverted = revert_feature(&patched, &journal, &feature.name).unwrap();
+        let mut without = options.clone();
+        without.set_feature(&feature.name, false).unwrap();
+        assert!(reverted == Patcher::new(&without).unwrap().patch(&original).unwrap());
+        assert_eq!(uninstall_from(&reverted, &journal).unwrap(), original);
+
+        assert_eq!(
+            revert_feature(&patched, &journal, "Not a feature"),
+            Err(UninstallError::FeatureNotInstalled(
+                "Not a feature".to_string()
+            ))
+        );
+        if let Some(overlap) = overlaps.first() {
+            assert!(matches!(
+                revert_feature(&patched, &journal, &overlap.first),
+                Err(UninstallError::OverlappingFeature { .. })
+            ));
+        }
+    }
+}
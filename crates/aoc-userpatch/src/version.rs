@@ -0,0 +1,243 @@
+use crate::patch::{
+    builtin_features, injection_offset, Injection, PATCHED_SIZE, UP_SECTION_ADDRESS,
+    UP_SECTION_DELTA,
+};
+use crate::PatchError;
+use std::ops::Range;
+
+/// The image base of the 1.0c executable.
+const IMAGE_BASE: u32 = 0x400000;
+/// The machine type of 32-bit x86 executables.
+const MACHINE_I386: u16 = 0x14C;
+/// The size of an entry in the section table.
+const SECTION_HEADER_SIZE: usize = 40;
+
+fn read_u16(exe: &[u8], offset: usize) -> Option<u16> {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(exe.get(offset..offset + 2)?);
+    Some(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(exe: &[u8], offset: usize) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(exe.get(offset..offset + 4)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// Check that an executable has the layout of the Age of Empires 2: The Conquerors 1.0c executable.
+///
+/// The patch data addresses the executable by file offset, so this checks the properties of the
+/// 1.0c image that those offsets rely on: a 32-bit x86 image at base address `0x400000`, with every
+/// section stored at the file offset that equals its address in memory. Other executables,
+/// including other versions of the game, store their sections at different offsets and are
+/// rejected. The section added by UserPatch is allowed, so executables that were already patched
+/// pass this check; use [`is_patched`] to detect those.
+///
+/// This does not detect modifications to the contents of the sections. Use [`crate::prepare`] with
+/// the checksum of a known clean executable to verify that an executable is unmodified.
+pub fn check_version(exe: &[u8]) -> Result<(), PatchError> {
+    let unsupported = |reason: &'static str| PatchError::UnsupportedVersion { reason };
+
+    if exe.get(0..2) != Some(b"MZ") {
+        return Err(unsupported("not an executable file"));
+    }
+    let pe_offset =
+        read_u32(exe, 0x3C).ok_or_else(|| unsupported("truncated DOS header"))? as usize;
+    if exe.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+        return Err(unsupported("missing PE header"));
+    }
+    let machine = read_u16(exe, pe_offset + 4).ok_or_else(|| unsupported("truncated PE header"))?;
+    if machine != MACHINE_I386 {
+        return Err(unsupported("not a 32-bit x86 executable"));
+    }
+    // The optional header starts after the 4 byte signature and the 20 byte file header, and
+    // contains the image base at offset 28.
+    let image_base =
+        read_u32(exe, pe_offset + 24 + 28).ok_or_else(|| unsupported("truncated PE header"))?;
+    if image_base != IMAGE_BASE {
+        return Err(unsupported("unexpected image base"));
+    }
+
+    let section_count =
+        read_u16(exe, pe_offset + 6).ok_or_else(|| unsupported("truncated PE header"))?;
+    let optional_header_size =
+        read_u16(exe, pe_offset + 20).ok_or_else(|| unsupported("truncated PE header"))?;
+    if section_count == 0 {
+        return Err(unsupported("no sections"));
+    }
+    let sections = pe_offset + 24 + optional_header_size as usize;
+    for index in 0..section_count as usize {
+        let header = sections + index * SECTION_HEADER_SIZE;
+        let (address, raw_size, raw_offset) = match (
+            read_u32(exe, header + 12),
+            read_u32(exe, header + 16),
+            read_u32(exe, header + 20),
+        ) {
+            (Some(address), Some(raw_size), Some(raw_offset)) => (address, raw_size, raw_offset),
+            _ => return Err(unsupported("truncated section table")),
+        };
+        // Sections without data in the file, like uninitialized data, have no offset to check.
+        if raw_size == 0 {
+            continue;
+        }
+        let address = IMAGE_BASE as usize + address as usize;
+        let expected_offset = if address < UP_SECTION_ADDRESS {
+            address - IMAGE_BASE as usize
+        } else if address == UP_SECTION_ADDRESS {
+            address - UP_SECTION_DELTA
+        } else {
+            return Err(unsupported("unexpected section"));
+        };
+        if raw_offset as usize != expected_offset {
+            return Err(unsupported("unexpected section layout"));
+        }
+    }
+    Ok(())
+}
+
+/// The version of an executable, as detected by [`detect_version`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExeVersion {
+    /// An unpatched 1.0c executable.
+    Vanilla,
+    /// A 1.0c executable with UserPatch 1.5 installed.
+    UserPatch15,
+}
+
+/// Check if UserPatch 1.5 is installed in an executable.
+///
+/// This checks for the bytes written by the features that are always installed. Injections that
+/// overlap with another feature's injections are skipped, because they may have been overwritten.
+/// Installing UserPatch into an executable that is already patched produces a broken executable,
+/// so installers should check this first.
+pub fn is_patched(buffer: &[u8]) -> bool {
+    let features = builtin_features();
+    let injections: Vec<(usize, Range<usize>, &[u8])> = features
+        .iter()
+        .enumerate()
+        .flat_map(|(index, feature)| {
+            feature
+                .patches
+                .iter()
+                .filter_map(move |Injection(addr, patch, ..)| {
+                    let offset = injection_offset(*addr, PATCHED_SIZE)?;
+                    Some((index, offset..offset + patch.len(), &patch[..]))
+                })
+        })
+        .collect();
+    let is_shared = |index: usize, range: &Range<usize>| {
+        injections.iter().any(|(other_index, other, _)| {
+            *other_index != index && other.start < range.end && range.start < other.end
+        })
+    };
+
+    let mut checked = 0;
+    for (index, range, bytes) in &injections {
+        if features[*index].optional || is_shared(*index, range) {
+            continue;
+        }
+        if buffer.get(range.clone()) != Some(bytes) {
+            return false;
+        }
+        checked += 1;
+    }
+    checked > 0
+}
+
+/// Detect whether an executable is a 1.0c executable, and whether UserPatch 1.5 is installed.
+///
+/// Returns `None` if the executable does not look like a 1.0c executable (see [`check_version`]).
+pub fn detect_version(exe: &[u8]) -> Option<ExeVersion> {
+    check_version(exe).ok()?;
+    if is_patched(exe) {
+        Some(ExeVersion::UserPatch15)
+    } else {
+        Some(ExeVersion::Vanilla)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build the headers of a PE file with the given machine type and image base, and a section
+    /// at `0x1000` that is stored at `raw_offset`.
+    pub(crate) fn fake_exe_with_section(machine: u16, image_base: u32, raw_offset: u32) -> Vec<u8> {
+        let mut exe = vec![0; 0x200];
+        exe[0..2].copy_from_slice(b"MZ");
+        exe[0x3C..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        exe[0x80..0x84].copy_from_slice(b"PE\0\0");
+        exe[0x84..0x86].copy_from_slice(&machine.to_le_bytes());
+        exe[0x86..0x88].copy_from_slice(&1u16.to_le_bytes());
+        exe[0x94..0x96].copy_from_slice(&0xE0u16.to_le_bytes());
+        exe[0x80 + 52..0x80 + 56].copy_from_slice(&image_base.to_le_bytes());
+        let section = 0x80 + 24 + 0xE0;
+        exe[section..section + 8].copy_from_slice(b".text\0\0\0");
+        exe[section + 12..section + 16].copy_from_slice(&0x1000u32.to_le_bytes());
+        exe[section + 16..section + 20].copy_from_slice(&0x1000u32.to_le_bytes());
+        exe[section + 20..section + 24].copy_from_slice(&raw_offset.to_le_bytes());
+        exe
+    }
+
+    /// Build the headers of a PE file with the given machine type and image base, laid out like
+    /// the 1.0c executable.
+    pub(crate) fn fake_exe(machine: u16, image_base: u32) -> Vec<u8> {
+        fake_exe_with_section(machine, image_base, 0x1000)
+    }
+
+    #[test]
+    fn detect_version() {
+        assert_eq!(check_version(&fake_exe(MACHINE_I386, IMAGE_BASE)), Ok(()));
+        assert!(check_version(&fake_exe(0x8664, IMAGE_BASE)).is_err());
+        assert!(check_version(&fake_exe(MACHINE_I386, 0x10000000)).is_err());
+        // Most executables align sections to 0x200 bytes in the file.
+        assert_eq!(
+            check_version(&fake_exe_with_section(MACHINE_I386, IMAGE_BASE, 0x400)),
+            Err(PatchError::UnsupportedVersion {
+                reason: "unexpected section layout"
+            })
+        );
+        let mut no_sections = fake_exe(MACHINE_I386, IMAGE_BASE);
+        no_sections[0x86..0x88].copy_from_slice(&0u16.to_le_bytes());
+        assert!(check_version(&no_sections).is_err());
+        assert!(check_version(b"MZ").is_err());
+        assert!(check_version(&[0; 0x200]).is_err());
+    }
+
+    #[test]
+    fn detect_patched() {
+        let exe = fake_exe(MACHINE_I386, IMAGE_BASE);
+        assert!(!is_patched(&exe));
+        assert_eq!(super::detect_version(&exe), Some(ExeVersion::Vanilla));
+
+        let patched = crate::install_into(&exe, &crate::InstallOptions::default());
+        assert!(is_patched(&patched));
+        assert!(is_patched(&crate::install_into(
+            &exe,
+            &crate::InstallOptions::bare()
+        )));
+        assert_eq!(
+            super::detect_version(&patched),
+            Some(ExeVersion::UserPatch15)
+        );
+        assert_eq!(super::detect_version(&[0; 0x200]), None);
+    }
+
+    #[test]
+    fn verify_before_patching() {
+        let options = crate::InstallOptions::bare();
+        let exe = fake_exe(MACHINE_I386, IMAGE_BASE);
+        let patched = crate::install_into_checked(&exe, &options).unwrap();
+        assert_eq!(
+            crate::install_into_checked(&patched, &options),
+            Err(PatchError::AlreadyPatched)
+        );
+        assert!(matches!(
+            crate::install_into_checked(&[0x90; 0x200], &options),
+            Err(PatchError::UnsupportedVersion { .. })
+        ));
+        assert!(
+            crate::install_into_checked(&[0x90; 0x200], &options.verify_version(false)).is_ok()
+        );
+    }
+}
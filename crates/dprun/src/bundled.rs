@@ -0,0 +1,44 @@
+use async_std::io;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// The dprun executable that was embedded at build time.
+static DPRUN_EXE: &[u8] = include_bytes!(env!(
+    "DPRUN_BUNDLE_PATH",
+    "set DPRUN_BUNDLE_PATH to the path of dprun.exe to use the bundled-dprun feature"
+));
+
+/// A copy of the embedded dprun executable in a temporary directory.
+///
+/// The directory is deleted when this is dropped.
+pub(crate) struct BundledDprun {
+    dir: TempDir,
+}
+
+impl BundledDprun {
+    /// Extract the embedded dprun executable to a new temporary directory.
+    pub(crate) fn extract() -> io::Result<Self> {
+        let dir = tempfile::Builder::new().prefix("dprun").tempdir()?;
+        std::fs::write(dir.path().join("dprun.exe"), DPRUN_EXE)?;
+        Ok(Self { dir })
+    }
+
+    /// Get the directory that contains dprun.exe.
+    pub(crate) fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_and_clean_up() {
+        let bundle = BundledDprun::extract().unwrap();
+        let dir = bundle.path().to_path_buf();
+        assert_eq!(std::fs::read(dir.join("dprun.exe")).unwrap(), DPRUN_EXE);
+        drop(bundle);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::server::ServiceProvider;
+use crate::structs::{CreatePlayerData, EnumSessionsData, OpenData, ReplyData, SendData};
+use crate::AppController;
+use async_std::io;
+use async_trait::async_trait;
+
+/// A service provider handler that accepts every message and does nothing with it.
+///
+/// This is useful to check that the host server and dprun start, before wiring up real message
+/// handling. Messages are logged at the `trace` level.
+///
+/// ```rust
+/// use dprun::{DPRunOptions, DefaultServiceProvider, GUID};
+///
+/// let options = DPRunOptions::builder()
+///     .host(None)
+///     .player_name("Player".into())
+///     .service_provider_handler(Box::new(DefaultServiceProvider::new()))
+///     .application(GUID::nil())
+///     .finish()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultServiceProvider;
+
+impl DefaultServiceProvider {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl ServiceProvider for DefaultServiceProvider {
+    async fn enum_sessions(
+        &mut self,
+        _controller: AppController,
+        _id: u32,
+        data: EnumSessionsData,
+    ) -> io::Result<()> {
+        log::trace!("[DefaultServiceProvider::enum_sessions] {:?}", data);
+        Ok(())
+    }
+
+    async fn open(
+        &mut self,
+        _controller: AppController,
+        _id: u32,
+        data: OpenData,
+    ) -> io::Result<()> {
+        log::trace!("[DefaultServiceProvider::open] {:?}", data);
+        Ok(())
+    }
+
+    async fn create_player(
+        &mut self,
+        _controller: AppController,
+        _id: u32,
+        data: CreatePlayerData,
+    ) -> io::Result<()> {
+        log::trace!("[DefaultServiceProvider::create_player] {:?}", data);
+        Ok(())
+    }
+
+    async fn reply(
+        &mut self,
+        _controller: AppController,
+        _id: u32,
+        data: ReplyData,
+    ) -> io::Result<()> {
+        log::trace!("[DefaultServiceProvider::reply] {:?}", data);
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        _controller: AppController,
+        _id: u32,
+        data: SendData,
+    ) -> io::Result<()> {
+        log::trace!("[DefaultServiceProvider::send] {:?}", data);
+        Ok(())
+    }
+}
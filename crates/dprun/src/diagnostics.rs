@@ -0,0 +1,90 @@
+use crate::REDACTED;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// Parts of the effective configuration that are not kept separately from the dprun arguments.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SessionInfo {
+    /// "host" or "join".
+    pub session_type: &'static str,
+    /// The GUID of the session, if known before dprun starts.
+    pub session: Option<String>,
+    pub service_provider: String,
+    pub service_provider_dll: Option<PathBuf>,
+    /// Address parts in the format they are passed to dprun, `Type=value`.
+    pub address: Vec<String>,
+    pub has_session_resolver: bool,
+}
+
+/// The host server ports, as far as they are known before the host server starts.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct HostServerInfo {
+    /// The address of the interface that the host server listens on.
+    pub address: IpAddr,
+    pub first_port: u16,
+    pub last_port: u16,
+    /// Whether the port that the host server binds to is passed to dprun as `INetPort`.
+    pub advertise_port: bool,
+}
+
+/// The complete effective configuration of a dprun session, for bug reports.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Diagnostics<'a> {
+    /// The program that starts dprun: Wine, or dprun itself on Windows.
+    pub launcher: PathBuf,
+    /// Arguments to the launcher before the path to dprun.
+    pub launcher_args: Vec<String>,
+    pub binary: PathBuf,
+    /// Arguments to dprun, with secrets redacted. The application is not included.
+    pub args: Vec<String>,
+    /// Environment variables that affect Wine or are set for dprun, with secrets redacted.
+    pub env: BTreeMap<String, String>,
+    /// Whether dprun starts without inheriting the environment.
+    pub env_clear: bool,
+    pub applications: Vec<String>,
+    #[serde(flatten)]
+    pub session: &'a SessionInfo,
+    pub host_server: Option<HostServerInfo>,
+}
+
+/// Check if an environment variable may contain a secret, based on its name.
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    ["PASSWORD", "SECRET", "TOKEN", "KEY"]
+        .iter()
+        .any(|secret| name.contains(secret))
+}
+
+/// Collect the environment variables that affect Wine, and the ones that are set for dprun.
+///
+/// Inherited variables are only included if they start with `WINE` and the environment is not
+/// cleared. Variables that are set explicitly are always included.
+pub(crate) fn child_env(
+    env_clear: bool,
+    vars: &[(OsString, OsString)],
+) -> BTreeMap<String, String> {
+    let inherited = std::env::vars()
+        .filter(|(name, _)| !env_clear && name.starts_with("WINE"))
+        .collect::<Vec<_>>();
+    let explicit = vars.iter().map(|(name, value)| {
+        (
+            name.to_string_lossy().into_owned(),
+            value.to_string_lossy().into_owned(),
+        )
+    });
+    inherited
+        .into_iter()
+        .chain(explicit)
+        .map(|(name, value)| {
+            let value = if is_secret(&name) {
+                REDACTED.to_string()
+            } else {
+                value
+            };
+            (name, value)
+        })
+        .collect()
+}
@@ -0,0 +1,207 @@
+use async_std::io;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// The maximum number of stderr lines that are kept for classifying a failure.
+pub(crate) const MAX_STDERR_LINES: usize = 100;
+
+/// Why dprun failed to join a session.
+///
+/// [`DPRun::start`](crate::DPRun::start) and [`SessionHandle::wait`](crate::SessionHandle::wait)
+/// return this inside an `io::Error` when a join fails. Use
+/// `err.get_ref().and_then(|err| err.downcast_ref::<JoinError>())` to get it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The service provider is not installed or could not be initialized.
+    ServiceProviderUnavailable,
+    /// The application is not registered as a DirectPlay lobbyable application.
+    ApplicationNotFound,
+    /// The session does not accept new players.
+    SessionFull,
+    /// The session could not be found or did not respond in time.
+    Timeout,
+    /// The session password is incorrect.
+    PasswordIncorrect,
+    /// Any other failure.
+    Unknown {
+        /// Exit code of dprun, if it exited normally.
+        code: Option<i32>,
+        /// The last lines that dprun wrote to stderr.
+        stderr: String,
+    },
+}
+
+/// Why dprun failed to host a session.
+///
+/// This is returned inside an `io::Error` like [`JoinError`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostError {
+    /// The service provider is not installed or could not be initialized.
+    ServiceProviderUnavailable,
+    /// The application is not registered as a DirectPlay lobbyable application.
+    ApplicationNotFound,
+    /// Any other failure.
+    Unknown {
+        /// Exit code of dprun, if it exited normally.
+        code: Option<i32>,
+        /// The last lines that dprun wrote to stderr.
+        stderr: String,
+    },
+}
+
+/// Describe a failure that could not be classified.
+fn fmt_unknown(f: &mut Formatter<'_>, code: Option<i32>, stderr: &str) -> fmt::Result {
+    match code {
+        Some(code) => write!(f, "dprun exited with status {}", code)?,
+        None => write!(f, "dprun was terminated")?,
+    }
+    match stderr.lines().last() {
+        Some(line) => write!(f, ": {}", line),
+        None => Ok(()),
+    }
+}
+
+impl Display for JoinError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::ServiceProviderUnavailable => write!(f, "service provider is unavailable"),
+            JoinError::ApplicationNotFound => write!(f, "application is not registered"),
+            JoinError::SessionFull => write!(f, "session is full"),
+            JoinError::Timeout => write!(f, "timed out joining session"),
+            JoinError::PasswordIncorrect => write!(f, "session password is incorrect"),
+            JoinError::Unknown { code, stderr } => fmt_unknown(f, *code, stderr),
+        }
+    }
+}
+
+impl Display for HostError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::ServiceProviderUnavailable => write!(f, "service provider is unavailable"),
+            HostError::ApplicationNotFound => write!(f, "application is not registered"),
+            HostError::Unknown { code, stderr } => fmt_unknown(f, *code, stderr),
+        }
+    }
+}
+
+impl Error for JoinError {}
+impl Error for HostError {}
+
+/// Known failure categories, and the DirectPlay error names that dprun prints for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Category {
+    ServiceProviderUnavailable,
+    ApplicationNotFound,
+    SessionFull,
+    Timeout,
+    PasswordIncorrect,
+}
+
+const PATTERNS: &[(&str, Category)] = &[
+    ("DPERR_UNAVAILABLE", Category::ServiceProviderUnavailable),
+    (
+        "DPERR_NOSERVICEPROVIDER",
+        Category::ServiceProviderUnavailable,
+    ),
+    ("DPERR_UNKNOWNAPPLICATION", Category::ApplicationNotFound),
+    ("DPERR_CANTCREATEPROCESS", Category::ApplicationNotFound),
+    ("DPERR_NONEWPLAYERS", Category::SessionFull),
+    ("DPERR_CANTADDPLAYER", Category::SessionFull),
+    ("DPERR_INVALIDPASSWORD", Category::PasswordIncorrect),
+    ("DPERR_TIMEOUT", Category::Timeout),
+    ("DPERR_NOSESSIONS", Category::Timeout),
+];
+
+/// A dprun process that exited unsuccessfully.
+#[derive(Debug)]
+pub(crate) struct ExitFailure {
+    pub code: Option<i32>,
+    pub stderr: String,
+}
+
+impl ExitFailure {
+    /// Find the failure category from the error names in stderr.
+    fn category(&self) -> Option<Category> {
+        PATTERNS
+            .iter()
+            .find(|(pattern, _)| self.stderr.contains(pattern))
+            .map(|(_, category)| *category)
+    }
+
+    /// Classify a failed join.
+    pub fn into_join_error(self) -> JoinError {
+        match self.category() {
+            Some(Category::ServiceProviderUnavailable) => JoinError::ServiceProviderUnavailable,
+            Some(Category::ApplicationNotFound) => JoinError::ApplicationNotFound,
+            Some(Category::SessionFull) => JoinError::SessionFull,
+            Some(Category::Timeout) => JoinError::Timeout,
+            Some(Category::PasswordIncorrect) => JoinError::PasswordIncorrect,
+            None => JoinError::Unknown {
+                code: self.code,
+                stderr: self.stderr,
+            },
+        }
+    }
+
+    /// Classify a failed host. Failures that only apply to joining are reported as unknown.
+    pub fn into_host_error(self) -> HostError {
+        match self.category() {
+            Some(Category::ServiceProviderUnavailable) => HostError::ServiceProviderUnavailable,
+            Some(Category::ApplicationNotFound) => HostError::ApplicationNotFound,
+            _ => HostError::Unknown {
+                code: self.code,
+                stderr: self.stderr,
+            },
+        }
+    }
+
+    /// Classify the failure and wrap it in an I/O error.
+    pub fn into_io_error(self, joining: bool) -> io::Error {
+        let error: Box<dyn Error + Send + Sync> = if joining {
+            Box::new(self.into_join_error())
+        } else {
+            Box::new(self.into_host_error())
+        };
+        io::Error::other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_failures() {
+        let failure = |stderr: &str| ExitFailure {
+            code: Some(1),
+            stderr: stderr.to_string(),
+        };
+        assert_eq!(
+            failure("Join failed: DPERR_INVALIDPASSWORD").into_join_error(),
+            JoinError::PasswordIncorrect
+        );
+        assert_eq!(
+            failure("Could not open session: DPERR_NONEWPLAYERS").into_join_error(),
+            JoinError::SessionFull
+        );
+        assert_eq!(
+            failure("DPERR_NONEWPLAYERS").into_host_error(),
+            HostError::Unknown {
+                code: Some(1),
+                stderr: "DPERR_NONEWPLAYERS".to_string()
+            }
+        );
+        let err = failure("something else").into_io_error(true);
+        let inner = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<JoinError>());
+        assert!(matches!(
+            inner,
+            Some(JoinError::Unknown { code: Some(1), .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "dprun exited with status 1: something else"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use crate::GUID;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// A GUID string could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuidParseError {
+    /// The GUID has the wrong number of characters, not counting braces.
+    InvalidLength(usize),
+    /// The GUID starts with a brace but does not end with one, or the other way around.
+    UnbalancedBraces,
+    /// A hyphen is missing at the given position, not counting braces.
+    InvalidSeparator(usize),
+    /// A character that is not a hexadecimal digit was found at the given position, not counting
+    /// braces.
+    InvalidCharacter(char, usize),
+}
+
+impl Display for GuidParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GuidParseError::InvalidLength(len) => {
+                write!(f, "GUID must be 36 characters long, got {}", len)
+            }
+            GuidParseError::UnbalancedBraces => write!(f, "GUID has unbalanced braces"),
+            GuidParseError::InvalidSeparator(pos) => {
+                write!(f, "expected a hyphen at position {} in GUID", pos)
+            }
+            GuidParseError::InvalidCharacter(c, pos) => {
+                write!(f, "invalid character {:?} at position {} in GUID", c, pos)
+            }
+        }
+    }
+}
+
+impl Error for GuidParseError {}
+
+/// Positions of the hyphens in a GUID string.
+const HYPHENS: [usize; 4] = [8, 13, 18, 23];
+
+/// Parse a GUID in the format DirectPlay uses, like `{5BFDB060-06A4-11D0-9C4F-00A0C905425E}`.
+///
+/// The braces are optional, and the hexadecimal digits may be upper or lower case. This accepts
+/// both the output of `GUID::to_string()` and the braced form that is passed to dprun.
+pub fn parse_guid(input: &str) -> Result<GUID, GuidParseError> {
+    let inner = match (input.starts_with('{'), input.ends_with('}')) {
+        (true, true) if input.len() >= 2 => &input[1..input.len() - 1],
+        (false, false) => input,
+        _ => return Err(GuidParseError::UnbalancedBraces),
+    };
+    let len = inner.chars().count();
+    if len != 36 {
+        return Err(GuidParseError::InvalidLength(len));
+    }
+
+    let mut digits = Vec::with_capacity(32);
+    for (pos, c) in inner.chars().enumerate() {
+        if HYPHENS.contains(&pos) {
+            if c != '-' {
+                return Err(GuidParseError::InvalidSeparator(pos));
+            }
+            continue;
+        }
+        let digit = c
+            .to_digit(16)
+            .ok_or(GuidParseError::InvalidCharacter(c, pos))?;
+        digits.push(digit as u8);
+    }
+
+    let mut bytes = [0u8; 16];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = pair[0] << 4 | pair[1];
+    }
+    Ok(GUID::from_bytes(bytes))
+}
+
+/// Swap the byte order of the `Data1`, `Data2` and `Data3` fields of a GUID.
+fn swap_fields(mut bytes: [u8; 16]) -> [u8; 16] {
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
+/// Read a GUID from the 16 bytes of a DirectPlay `GUID` struct in memory.
+///
+/// DirectPlay stores `Data1`, `Data2` and `Data3` as little-endian integers, followed by the 8
+/// bytes of `Data4` in order. `GUID::from_bytes` instead expects the bytes in the order they
+/// appear in the string form.
+pub fn guid_from_bytes(bytes: [u8; 16]) -> GUID {
+    GUID::from_bytes(swap_fields(bytes))
+}
+
+/// Get the 16 bytes of the DirectPlay `GUID` struct in memory, for example to pass a GUID as a
+/// binary address part. This is the inverse of [`guid_from_bytes`].
+pub fn guid_to_bytes(guid: &GUID) -> [u8; 16] {
+    swap_fields(*guid.as_bytes())
+}
+
+/// Generate a random (version 4) GUID, for example to host a session with a known GUID.
+///
+/// The GUID is laid out like the DirectPlay `GUID` struct in its string form: `Data1` is the first
+/// group of hex digits, `Data2` and `Data3` are the next two, and the version number is the first
+/// digit of `Data3`. The random bytes come from `rand::thread_rng`.
+#[cfg(feature = "rand")]
+pub fn random_guid() -> GUID {
+    use rand::RngCore;
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    uuid::Builder::from_bytes(bytes)
+        .set_variant(uuid::Variant::RFC4122)
+        .set_version(uuid::Version::Random)
+        .build()
+}
+
+/// Serialize GUIDs in the braced form that DirectPlay uses, for use with `#[serde(with)]`. This is
+/// only available with the `serde` feature.
+///
+/// ```rust
+/// use dprun::GUID;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Lobby {
+///     #[serde(with = "dprun::braced_guid")]
+///     application: GUID,
+/// }
+/// ```
+#[cfg(feature = "serde")]
+pub mod braced_guid {
+    use super::parse_guid;
+    use crate::{to_braced, GUID};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    /// Serialize a GUID as a braced string, like `{5BFDB060-06A4-11D0-9C4F-00A0C905425E}`.
+    pub fn serialize<S: Serializer>(guid: &GUID, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_braced(guid))
+    }
+
+    /// Deserialize a GUID from a string, with or without braces. See [`parse_guid`].
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<GUID, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        parse_guid(&string).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_braced;
+
+    #[test]
+    fn parse_guids() {
+        let guid = GUID::parse_str("5BFDB060-06A4-11D0-9C4F-00A0C905425E").unwrap();
+        assert_eq!(parse_guid(&to_braced(&guid)), Ok(guid));
+        assert_eq!(parse_guid(&guid.to_string()), Ok(guid));
+        assert_eq!(
+            parse_guid("{5bfdb060-06a4-11d0-9c4f-00a0c905425e}"),
+            Ok(guid)
+        );
+
+        assert_eq!(
+            parse_guid("{5BFDB060-06A4-11D0-9C4F-00A0C905425E"),
+            Err(GuidParseError::UnbalancedBraces)
+        );
+        assert_eq!(
+            parse_guid("5BFDB060-06A4-11D0-9C4F"),
+            Err(GuidParseError::InvalidLength(23))
+        );
+        assert_eq!(
+            parse_guid("5BFDB060_06A4-11D0-9C4F-00A0C905425E"),
+            Err(GuidParseError::InvalidSeparator(8))
+        );
+        assert_eq!(
+            parse_guid("5BFDB060-06A4-11D0-9C4F-00A0C905425G"),
+            Err(GuidParseError::InvalidCharacter('G', 35))
+        );
+    }
+
+    #[test]
+    fn memory_layout() {
+        let guid = GUID::parse_str("36E95EE0-8577-11CF-960C-0080C7534E82").unwrap();
+        let bytes = [
+            0xE0, 0x5E, 0xE9, 0x36, 0x77, 0x85, 0xCF, 0x11, 0x96, 0x0C, 0x00, 0x80, 0xC7, 0x53,
+            0x4E, 0x82,
+        ];
+        assert_eq!(guid_to_bytes(&guid), bytes);
+        assert_eq!(guid_from_bytes(bytes), guid);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Lobby {
+            #[serde(with = "braced_guid")]
+            application: GUID,
+        }
+
+        let lobby = Lobby {
+            application: GUID::parse_str("5BFDB060-06A4-11D0-9C4F-00A0C905425E").unwrap(),
+        };
+        let json = serde_json::to_string(&lobby).unwrap();
+        assert_eq!(
+            json,
+            r#"{"application":"{5BFDB060-06A4-11D0-9C4F-00A0C905425E}"}"#
+        );
+        assert_eq!(serde_json::from_str::<Lobby>(&json).unwrap(), lobby);
+        assert!(serde_json::from_str::<Lobby>(r#"{"application":"nope"}"#).is_err());
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn random_guids() {
+        let guid = random_guid();
+        assert_eq!(guid.get_version(), Some(uuid::Version::Random));
+        assert_ne!(guid, random_guid());
+        assert_eq!(parse_guid(&to_braced(&guid)), Ok(guid));
+    }
+}
@@ -0,0 +1,124 @@
+//! Well-known DirectPlay GUIDs.
+//!
+//! These can be passed anywhere a GUID is expected, for example to
+//! [`DPRunOptionsBuilder::service_provider`](crate::DPRunOptionsBuilder::service_provider) or
+//! [`DPRunOptionsBuilder::address_part`](crate::DPRunOptionsBuilder::address_part).
+
+use crate::GUID;
+
+/// The TCP/IP service provider (DPSPGUID_TCPIP).
+pub const SP_TCPIP: GUID = GUID::from_u128(0x36E95EE0_8577_11CF_960C_0080C7534E82);
+/// The IPX service provider (DPSPGUID_IPX).
+pub const SP_IPX: GUID = GUID::from_u128(0x685BC400_9D2C_11CF_A9CD_00AA006886E3);
+/// The modem service provider (DPSPGUID_MODEM).
+pub const SP_MODEM: GUID = GUID::from_u128(0x44EAA760_CB68_11CF_9C4E_00A0C905425E);
+/// The serial cable service provider (DPSPGUID_SERIAL).
+pub const SP_SERIAL: GUID = GUID::from_u128(0x0F1D6860_88D9_11CF_9C4E_00A0C905425E);
+/// The DPRun service provider, which forwards messages to a host server.
+pub const SP_DPRUN: GUID = GUID::from_u128(0xB1ED2367_609B_4C5C_8755_D2A29BB9A554);
+
+/// The DPChat sample application from the DirectX SDK.
+pub const APP_DPCHAT: GUID = GUID::from_u128(0x5BFDB060_06A4_11D0_9C4F_00A0C905425E);
+
+/// Address type for the total size of an address (DPAID_TotalSize).
+pub const ADDR_TOTAL_SIZE: GUID = GUID::from_u128(0x1318F560_912C_11D0_9DAA_00A0C90A43CB);
+/// Address type for the service provider GUID (DPAID_ServiceProvider).
+pub const ADDR_SERVICE_PROVIDER: GUID = GUID::from_u128(0x07D916C0_E0AF_11CF_9C4E_00A0C905425E);
+/// Address type for the lobby provider GUID (DPAID_LobbyProvider).
+pub const ADDR_LOBBY_PROVIDER: GUID = GUID::from_u128(0x59B95640_9667_11D0_A77D_0000F803ABFC);
+/// Address type for an ANSI phone number (DPAID_Phone).
+pub const ADDR_PHONE: GUID = GUID::from_u128(0x78EC89A0_E0AF_11CF_9C4E_00A0C905425E);
+/// Address type for a Unicode phone number (DPAID_PhoneW).
+pub const ADDR_PHONE_W: GUID = GUID::from_u128(0xBA5A7A70_9DBF_11D0_9CC1_00A0C905425E);
+/// Address type for an ANSI modem name (DPAID_Modem).
+pub const ADDR_MODEM: GUID = GUID::from_u128(0xF6DCC200_A2FE_11D0_9C4F_00A0C905425E);
+/// Address type for a Unicode modem name (DPAID_ModemW).
+pub const ADDR_MODEM_W: GUID = GUID::from_u128(0x01FD92E0_A2FF_11D0_9C4F_00A0C905425E);
+/// Address type for an ANSI host name or IP address (DPAID_INet).
+pub const ADDR_INET: GUID = GUID::from_u128(0xC4A54DA0_E0AF_11CF_9C4E_00A0C905425E);
+/// Address type for a Unicode host name or IP address (DPAID_INetW).
+pub const ADDR_INET_W: GUID = GUID::from_u128(0xE63B6381_8EBE_11D0_9CC1_00A0C905425E);
+/// Address type for a port number (DPAID_INetPort).
+pub const ADDR_INET_PORT: GUID = GUID::from_u128(0xE4524541_8EA5_11D1_8A96_006097B01411);
+/// Address type for serial port settings (DPAID_ComPort).
+pub const ADDR_COM_PORT: GUID = GUID::from_u128(0xF2F0CE00_E0AF_11CF_9C4E_00A0C905425E);
+
+/// Names and GUIDs of the service providers that dprun knows by name.
+const SERVICE_PROVIDERS: &[(&str, GUID)] = &[
+    ("DPRUN", SP_DPRUN),
+    ("TCPIP", SP_TCPIP),
+    ("IPX", SP_IPX),
+    ("MODEM", SP_MODEM),
+    ("SERIAL", SP_SERIAL),
+];
+
+/// Names and GUIDs of the standard DirectPlay address types (DPAID_* in the DirectPlay C API).
+pub(crate) const ADDRESS_TYPES: &[(&str, GUID)] = &[
+    ("TotalSize", ADDR_TOTAL_SIZE),
+    ("ServiceProvider", ADDR_SERVICE_PROVIDER),
+    ("LobbyProvider", ADDR_LOBBY_PROVIDER),
+    ("Phone", ADDR_PHONE),
+    ("PhoneW", ADDR_PHONE_W),
+    ("Modem", ADDR_MODEM),
+    ("ModemW", ADDR_MODEM_W),
+    ("INet", ADDR_INET),
+    ("INetW", ADDR_INET_W),
+    ("INetPort", ADDR_INET_PORT),
+    ("ComPort", ADDR_COM_PORT),
+];
+
+/// Get the GUID of a service provider or address type that dprun knows by name, like `"TCPIP"` or
+/// `"INetPort"`.
+///
+/// Names are matched exactly, so the `"MODEM"` service provider and the `"Modem"` address type
+/// are told apart.
+pub fn guid_for_name(name: &str) -> Option<GUID> {
+    SERVICE_PROVIDERS
+        .iter()
+        .chain(ADDRESS_TYPES)
+        .find(|(known, _)| *known == name)
+        .map(|(_, guid)| *guid)
+}
+
+/// Get the name that dprun knows a service provider or address type GUID by.
+pub fn name_for_guid(guid: GUID) -> Option<&'static str> {
+    SERVICE_PROVIDERS
+        .iter()
+        .chain(ADDRESS_TYPES)
+        .find(|(_, known)| *known == guid)
+        .map(|(name, _)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_braced;
+
+    #[test]
+    fn braced_strings() {
+        assert_eq!(
+            to_braced(&SP_TCPIP),
+            "{36E95EE0-8577-11CF-960C-0080C7534E82}"
+        );
+        assert_eq!(
+            to_braced(&APP_DPCHAT),
+            "{5BFDB060-06A4-11D0-9C4F-00A0C905425E}"
+        );
+        assert_eq!(
+            to_braced(&ADDR_INET_PORT),
+            "{E4524541-8EA5-11D1-8A96-006097B01411}"
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(guid_for_name("TCPIP"), Some(SP_TCPIP));
+        assert_eq!(guid_for_name("MODEM"), Some(SP_MODEM));
+        assert_eq!(guid_for_name("Modem"), Some(ADDR_MODEM));
+        assert_eq!(guid_for_name("INetPort"), Some(ADDR_INET_PORT));
+        assert_eq!(guid_for_name("SelfID"), None);
+        assert_eq!(name_for_guid(SP_DPRUN), Some("DPRUN"));
+        assert_eq!(name_for_guid(ADDR_INET), Some("INet"));
+        assert_eq!(name_for_guid(APP_DPCHAT), None);
+    }
+}
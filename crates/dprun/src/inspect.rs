@@ -1,7 +1,11 @@
+use crate::structs::DPID;
 use byteorder::{ReadBytesExt, LE};
 use std::io::{self, Cursor, Read};
 use uuid::Uuid;
 
+/// DirectPlay protocol version that messages built by the host server are marked with.
+const DPSP_VERSION: u16 = 0x0e;
+
 struct CmdId(u16);
 impl std::fmt::Debug for CmdId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -145,6 +149,20 @@ fn parse_message(mut message: impl Read) -> io::Result<ProtocolMessage> {
     })
 }
 
+/// Build a DirectPlay system message that tells an application that a player was deleted.
+pub fn delete_player_message(player: DPID) -> Vec<u8> {
+    let mut message = Vec::with_capacity(28);
+    message.extend_from_slice(b"play");
+    message.extend_from_slice(&0x0bu16.to_le_bytes());
+    message.extend_from_slice(&DPSP_VERSION.to_le_bytes());
+    // Receiving player: none in particular.
+    message.extend_from_slice(&0u32.to_le_bytes());
+    message.extend_from_slice(&player.to_le_bytes());
+    // Group, create offset, and password offset.
+    message.extend_from_slice(&[0; 12]);
+    message
+}
+
 pub fn print_network_message(message: &[u8]) {
     let mut message = Cursor::new(message);
     let guid = {
@@ -5,27 +5,75 @@
 //!
 //! The DPRun executable must be available separately.
 
+#[cfg(feature = "bundled-dprun")]
+mod bundled;
+mod default_provider;
+#[cfg(feature = "serde")]
+mod diagnostics;
+mod exit;
+mod guid;
+pub mod guids;
 mod inspect;
+mod preset;
+#[cfg(unix)]
+mod pty;
+mod resolver;
 mod server;
+mod stats;
 pub mod structs;
+mod wine;
 
-use crate::server::HostServer;
-use async_process::{Command, Stdio};
+#[cfg(feature = "serde")]
+use crate::diagnostics::{Diagnostics, HostServerInfo, SessionInfo};
+use crate::exit::{ExitFailure, MAX_STDERR_LINES};
+use crate::resolver::resolve_address;
+#[cfg(feature = "serde")]
+use crate::server::DEFAULT_HOST_SERVER_IP;
+use crate::server::{Backend, HostServer, ServerController};
+use async_process::{Child, Command, Stdio};
+use async_std::channel::{self, Receiver};
 use async_std::io::{self, BufReader};
 use async_std::prelude::*;
-use std::path::PathBuf;
+use futures::future::{BoxFuture, Either};
+use futures::stream::Stream;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-pub use crate::server::{AppController, ServiceProvider};
+pub use crate::default_provider::DefaultServiceProvider;
+pub use crate::exit::{HostError, JoinError};
+#[cfg(feature = "serde")]
+pub use crate::guid::braced_guid;
+#[cfg(feature = "rand")]
+pub use crate::guid::random_guid;
+pub use crate::guid::{guid_from_bytes, guid_to_bytes, parse_guid, GuidParseError};
+pub use crate::guids::{guid_for_name, name_for_guid};
+pub use crate::preset::Preset;
+#[cfg(unix)]
+pub use crate::pty::PtyMaster;
+pub use crate::resolver::{probe_reachable, SessionEndpoint, SessionResolver};
+pub use crate::server::{
+    AppController, MessageAction, PlayerInfo, ServiceProvider, MAX_RELAY_HOPS,
+};
+pub use crate::stats::ProcessStats;
 pub use crate::structs::DPID;
+pub use crate::wine::WineConfig;
 pub use uuid::Uuid as GUID;
 
-// TODO move these to consts again when parse_str is const fn
-lazy_static::lazy_static! {
-    /// The GUID of the DPRun Service Provider.
-    static ref GUID_DPRUNSP: GUID = GUID::parse_str("B1ED2367-609B-4C5C-8755-D2A29BB9A554").unwrap();
-    /// The GUID of the DirectPlay address type containing a port number.
-    static ref GUID_INETPORT: GUID = GUID::parse_str("E4524541-8EA5-11D1-8A96-006097B01411").unwrap();
-}
+/// The maximum size of the player data blob, in bytes.
+///
+/// DirectPlay itself does not limit the size, but the data is passed to dprun on the command line.
+pub const MAX_PLAYER_DATA_SIZE: usize = 4096;
+
+/// Value that is shown instead of secrets, like the session password.
+pub(crate) const REDACTED: &str = "<redacted>";
 
 /// The type of DirectPlay session to create; either joining or hosting a session.
 #[derive(Debug, Clone, Copy)]
@@ -116,6 +164,13 @@ impl From<&str> for DPAddressValue {
     }
 }
 
+/// Formats the address without brackets, as the `INet` address part expects for IPv6 addresses.
+impl From<IpAddr> for DPAddressValue {
+    fn from(ip: IpAddr) -> Self {
+        DPAddressValue::String(ip.to_string())
+    }
+}
+
 impl From<Vec<u8>> for DPAddressValue {
     fn from(bytes: Vec<u8>) -> Self {
         DPAddressValue::Binary(bytes)
@@ -128,6 +183,72 @@ impl From<&[u8]> for DPAddressValue {
     }
 }
 
+/// Shows the value in the form that is passed to dprun: `i:2197` for numbers, `b:ab12` for
+/// binary values, and strings as-is.
+impl Display for DPAddressValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DPAddressValue::Number(val) => write!(f, "i:{}", val),
+            DPAddressValue::String(val) => write!(f, "{}", val),
+            DPAddressValue::Binary(val) => write!(f, "b:{}", to_hex(val)),
+        }
+    }
+}
+
+/// A borrowed view of a [`DPAddressValue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DPAddressValueView<'a> {
+    /// A DirectPlay address part with a numeric value.
+    Number(i32),
+    /// A DirectPlay address part with a string value.
+    String(&'a str),
+    /// A DirectPlay address part with a binary value.
+    Binary(&'a [u8]),
+}
+
+impl<'a> From<&'a DPAddressValue> for DPAddressValueView<'a> {
+    fn from(value: &'a DPAddressValue) -> Self {
+        match value {
+            DPAddressValue::Number(number) => DPAddressValueView::Number(*number),
+            DPAddressValue::String(string) => DPAddressValueView::String(string),
+            DPAddressValue::Binary(bytes) => DPAddressValueView::Binary(bytes),
+        }
+    }
+}
+
+/// How much dprun should log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DprunLogLevel {
+    /// Only log errors.
+    Quiet,
+    /// dprun's default logging.
+    Normal,
+    /// Log DirectPlay lobby and session events.
+    Verbose,
+    /// Log everything, including the individual messages sent through the service provider.
+    Debug,
+}
+
+impl DprunLogLevel {
+    /// Get the dprun command line flags for this log level.
+    fn flags(self) -> &'static [&'static str] {
+        match self {
+            DprunLogLevel::Quiet => &["--quiet"],
+            DprunLogLevel::Normal => &[],
+            DprunLogLevel::Verbose => &["--verbose"],
+            DprunLogLevel::Debug => &["--verbose", "--verbose"],
+        }
+    }
+}
+
+/// Look up the GUID of a named DirectPlay address type.
+fn address_type_guid(name: &str) -> Option<GUID> {
+    guids::ADDRESS_TYPES
+        .iter()
+        .find(|(type_name, _)| type_name.eq_ignore_ascii_case(name))
+        .map(|(_, guid)| *guid)
+}
+
 /// Represents a part of a DirectPlay address, akin to DPCOMPOUNDADDRESSELEMENT in the DirectPlay
 /// C API. Each address part has a data type and a value.
 #[derive(Debug, Clone)]
@@ -139,18 +260,145 @@ struct DPAddressPart {
     value: DPAddressValue,
 }
 
+/// An address part whose value may only be known right before dprun is started.
+enum AddressPartSource {
+    /// An address part with a known value.
+    Fixed(DPAddressPart),
+    /// An address part whose value is computed by a callback when the command is built.
+    Dynamic(DPGUIDOrNamed, Box<dyn FnOnce() -> DPAddressValue + Send>),
+}
+
+impl AddressPartSource {
+    /// Get the address part, calling the callback if necessary.
+    fn resolve(self) -> DPAddressPart {
+        match self {
+            AddressPartSource::Fixed(part) => part,
+            AddressPartSource::Dynamic(data_type, callback) => DPAddressPart {
+                data_type,
+                value: callback(),
+            },
+        }
+    }
+
+    /// Get the data type of the address part.
+    fn data_type(&self) -> &DPGUIDOrNamed {
+        match self {
+            AddressPartSource::Fixed(part) => &part.data_type,
+            AddressPartSource::Dynamic(data_type, _) => data_type,
+        }
+    }
+}
+
+/// The options given to a [`DPRunOptionsBuilder`] are incomplete or invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuilderError {
+    /// Neither [`host`](DPRunOptionsBuilder::host) nor [`join`](DPRunOptionsBuilder::join) was
+    /// called.
+    MissingSessionType,
+    /// No player name was set.
+    MissingPlayerName,
+    /// No service provider was set.
+    MissingServiceProvider,
+    /// No application was set.
+    MissingApplication,
+    /// The DPRun service provider was selected, but there is no service provider handler or
+    /// upstream host server to handle its messages.
+    MissingServiceProviderHandler,
+    /// Both a service provider handler and an upstream host server were set.
+    ConflictingBackends,
+    /// The service provider DLL does not exist at the given path.
+    ServiceProviderDllNotFound(PathBuf),
+    /// The player data is larger than [`MAX_PLAYER_DATA_SIZE`]. Contains the actual size.
+    PlayerDataTooLarge(usize),
+    /// The host server port range is empty.
+    EmptyPortRange,
+    /// Both a host server port range and a host server bind port were set.
+    ConflictingHostServerPorts,
+    /// A start timeout was set, but there is no host server to detect that dprun has started.
+    StartTimeoutWithoutHostServer,
+    /// The maximum number of players is less than 2.
+    InvalidMaxPlayers(u32),
+    /// An address part data type was added more than once. Contains the data type of the
+    /// duplicate, as it was given.
+    DuplicateAddressPart(String),
+}
+
+impl Display for BuilderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BuilderError::MissingSessionType => write!(f, "must set a session type"),
+            BuilderError::MissingPlayerName => write!(f, "must set a player name"),
+            BuilderError::MissingServiceProvider => write!(f, "must set a service provider"),
+            BuilderError::MissingApplication => write!(f, "must set an application GUID to run"),
+            BuilderError::MissingServiceProviderHandler => write!(
+                f,
+                "must register a service provider handler or an upstream host server to use the \
+                 DPRun service provider"
+            ),
+            BuilderError::ConflictingBackends => write!(
+                f,
+                "cannot use both a service provider handler and an upstream host server"
+            ),
+            BuilderError::ServiceProviderDllNotFound(path) => {
+                write!(f, "service provider DLL {:?} does not exist", path)
+            }
+            BuilderError::PlayerDataTooLarge(size) => write!(
+                f,
+                "player data must be at most {} bytes, got {}",
+                MAX_PLAYER_DATA_SIZE, size
+            ),
+            BuilderError::EmptyPortRange => write!(f, "host server port range must not be empty"),
+            BuilderError::ConflictingHostServerPorts => write!(
+                f,
+                "cannot set both a host server port range and a host server bind port"
+            ),
+            BuilderError::StartTimeoutWithoutHostServer => write!(
+                f,
+                "a start timeout requires a service provider handler or an upstream host server"
+            ),
+            BuilderError::InvalidMaxPlayers(max) => {
+                write!(f, "a session must allow at least 2 players, got {}", max)
+            }
+            BuilderError::DuplicateAddressPart(data_type) => {
+                write!(f, "address part {} was added more than once", data_type)
+            }
+        }
+    }
+}
+
+impl Error for BuilderError {}
+
 /// Create a DPRunOptions struct instance.
 #[derive(Default)]
 pub struct DPRunOptionsBuilder {
     session_type: Option<SessionType>,
     player_name: Option<String>,
+    player_data: Option<Vec<u8>>,
     service_provider: Option<DPGUIDOrNamed>,
     service_provider_handler: Option<Box<dyn ServiceProvider>>,
-    application: Option<GUID>,
-    address: Vec<DPAddressPart>,
+    host_server_upstream: Option<SocketAddr>,
+    service_provider_dll: Option<PathBuf>,
+    applications: Vec<GUID>,
+    address: Vec<AddressPartSource>,
+    allow_duplicate_address_parts: bool,
     session_name: Option<String>,
     session_password: Option<String>,
+    max_players: Option<u32>,
     cwd: Option<PathBuf>,
+    binary_path: Option<PathBuf>,
+    wine: Option<WineConfig>,
+    env: Vec<(OsString, OsString)>,
+    env_clear: bool,
+    host_server_port_range: Option<RangeInclusive<u16>>,
+    host_server_bind_port: Option<u16>,
+    host_server_bind: Option<IpAddr>,
+    verbosity: Option<DprunLogLevel>,
+    session_resolver: Option<Box<dyn SessionResolver>>,
+    probe_timeout: Option<Duration>,
+    start_timeout: Option<Duration>,
+    shutdown_grace: Option<Duration>,
+    #[cfg(unix)]
+    pty: bool,
 }
 
 /// Holds options for running DPRun. DPRunOptions instances can be created using
@@ -158,13 +406,32 @@ pub struct DPRunOptionsBuilder {
 pub struct DPRunOptions {
     session_type: SessionType,
     player_name: String,
+    player_data: Option<Vec<u8>>,
     service_provider: DPGUIDOrNamed,
     service_provider_handler: Option<Box<dyn ServiceProvider>>,
-    application: GUID,
-    address: Vec<DPAddressPart>,
+    host_server_upstream: Option<SocketAddr>,
+    service_provider_dll: Option<PathBuf>,
+    applications: Vec<GUID>,
+    address: Vec<AddressPartSource>,
+    allow_duplicate_address_parts: bool,
     session_name: Option<String>,
     session_password: Option<String>,
+    max_players: Option<u32>,
     cwd: Option<PathBuf>,
+    binary_path: Option<PathBuf>,
+    wine: Option<WineConfig>,
+    env: Vec<(OsString, OsString)>,
+    env_clear: bool,
+    host_server_port_range: Option<RangeInclusive<u16>>,
+    host_server_bind_port: Option<u16>,
+    host_server_bind: Option<IpAddr>,
+    verbosity: Option<DprunLogLevel>,
+    session_resolver: Option<Box<dyn SessionResolver>>,
+    probe_timeout: Option<Duration>,
+    start_timeout: Option<Duration>,
+    shutdown_grace: Option<Duration>,
+    #[cfg(unix)]
+    pty: bool,
 }
 
 impl DPRunOptions {
@@ -172,6 +439,99 @@ impl DPRunOptions {
     pub fn builder() -> DPRunOptionsBuilder {
         DPRunOptionsBuilder::default()
     }
+
+    /// Turn the options back into a builder, to change some of them.
+    ///
+    /// All options are kept, including the service provider handler and the session resolver.
+    /// Because those are owned by the options, neither the options nor the builder can be cloned.
+    /// To host many similar sessions, write a function that returns the common builder instead:
+    ///
+    /// ```rust
+    /// use dprun::{DPRunOptions, DPRunOptionsBuilder, GUID};
+    ///
+    /// fn template() -> DPRunOptionsBuilder {
+    ///     DPRunOptions::builder()
+    ///         .named_service_provider("TCPIP")
+    ///         .application(GUID::nil())
+    /// }
+    ///
+    /// let options = template()
+    ///     .host(None)
+    ///     .player_name("Host".into())
+    ///     .finish()
+    ///     .unwrap();
+    /// let options = options
+    ///     .into_builder()
+    ///     .player_name("Another host".into())
+    ///     .finish()
+    ///     .unwrap();
+    /// ```
+    pub fn into_builder(self) -> DPRunOptionsBuilder {
+        DPRunOptionsBuilder {
+            session_type: Some(self.session_type),
+            player_name: Some(self.player_name),
+            player_data: self.player_data,
+            service_provider: Some(self.service_provider),
+            service_provider_handler: self.service_provider_handler,
+            host_server_upstream: self.host_server_upstream,
+            service_provider_dll: self.service_provider_dll,
+            applications: self.applications,
+            address: self.address,
+            allow_duplicate_address_parts: self.allow_duplicate_address_parts,
+            session_name: self.session_name,
+            session_password: self.session_password,
+            max_players: self.max_players,
+            cwd: self.cwd,
+            binary_path: self.binary_path,
+            wine: self.wine,
+            env: self.env,
+            env_clear: self.env_clear,
+            host_server_port_range: self.host_server_port_range,
+            host_server_bind_port: self.host_server_bind_port,
+            host_server_bind: self.host_server_bind,
+            verbosity: self.verbosity,
+            session_resolver: self.session_resolver,
+            probe_timeout: self.probe_timeout,
+            start_timeout: self.start_timeout,
+            shutdown_grace: self.shutdown_grace,
+            #[cfg(unix)]
+            pty: self.pty,
+        }
+    }
+
+    /// Build the command that dprun would be started with, without starting it.
+    ///
+    /// Nothing is spawned and the host server is not started. Dynamic address parts are computed
+    /// like in [`run`]. Address parts that are only known once the session starts, like the port
+    /// from a host server port range or the address from a session resolver, are left out.
+    pub fn dry_run(self) -> DPRunCommand {
+        run(self).dry_run()
+    }
+
+    /// Get the address parts grouped by their data type.
+    ///
+    /// Address parts that were added by name are grouped under the GUID of the standard DirectPlay
+    /// address type with that name. Names that are not standard DirectPlay address types, like
+    /// dprun's `SelfID`, are left out. Dynamic address parts are also left out, because their value
+    /// is not known until dprun is started.
+    pub fn address_by_key(&self) -> BTreeMap<GUID, Vec<DPAddressValueView<'_>>> {
+        let mut map: BTreeMap<GUID, Vec<DPAddressValueView<'_>>> = BTreeMap::new();
+        for part in &self.address {
+            let part = match part {
+                AddressPartSource::Fixed(part) => part,
+                AddressPartSource::Dynamic(..) => continue,
+            };
+            let key = match &part.data_type {
+                DPGUIDOrNamed::GUID(guid) => *guid,
+                DPGUIDOrNamed::Named(name) => match address_type_guid(name) {
+                    Some(guid) => guid,
+                    None => continue,
+                },
+            };
+            map.entry(key).or_default().push((&part.value).into());
+        }
+        map
+    }
 }
 
 impl DPRunOptionsBuilder {
@@ -200,6 +560,18 @@ impl DPRunOptionsBuilder {
         }
     }
 
+    /// Set custom data for the local player (optional).
+    ///
+    /// This is an arbitrary blob that the application can read back from DirectPlay, for games
+    /// that store player settings beyond the name. It is passed to dprun hex-encoded, and may be
+    /// at most [`MAX_PLAYER_DATA_SIZE`] bytes long.
+    pub fn player_data(self, player_data: Vec<u8>) -> Self {
+        Self {
+            player_data: Some(player_data),
+            ..self
+        }
+    }
+
     /// Set the service provider to use.
     pub fn service_provider(self, service_provider: GUID) -> Self {
         Self {
@@ -228,10 +600,54 @@ impl DPRunOptionsBuilder {
         self
     }
 
+    /// Forward the messages of the DPRun Service Provider to another host server, instead of
+    /// handling them with a service provider handler.
+    ///
+    /// This turns the host server into a relay, which can be chained to connect players who cannot
+    /// reach each other directly. Messages are forwarded unchanged. Each connection may pass
+    /// through at most [`MAX_RELAY_HOPS`] relays, and connections that loop back to a relay they
+    /// already passed through are dropped.
+    ///
+    /// This automatically enables the DPRUN service provider if it's not enabled already.
+    pub fn host_server_upstream(mut self, upstream: SocketAddr) -> Self {
+        if self.service_provider.is_none() {
+            self = self.named_service_provider("DPRUN");
+        }
+        self.host_server_upstream = Some(upstream);
+        self
+    }
+
+    /// Load the service provider from a DLL file, instead of the one registered in the Windows
+    /// registry (optional).
+    ///
+    /// The service provider GUID must still be set. This is useful to test service providers that
+    /// are not installed. A relative path is resolved relative to the directory dprun is in. On
+    /// Linux, the path is passed to dprun as-is, so it must be a path that Wine understands.
+    pub fn service_provider_dll(self, path: PathBuf) -> Self {
+        Self {
+            service_provider_dll: Some(path),
+            ..self
+        }
+    }
+
     /// Set the application to start.
     pub fn application(self, application: GUID) -> Self {
         Self {
-            application: Some(application),
+            applications: vec![application],
+            ..self
+        }
+    }
+
+    /// Set several applications to try, in order.
+    ///
+    /// When joining a session, dprun is restarted with the next application if the previous one
+    /// is not registered, or if dprun failed without saying why. Other failures, like
+    /// [`JoinError::PasswordIncorrect`] or [`JoinError::SessionFull`], are returned right away. The
+    /// application that ended up being used is available from
+    /// [`SessionHandle::application`]. When hosting, only the first application is used.
+    pub fn applications(self, applications: Vec<GUID>) -> Self {
+        Self {
+            applications,
             ..self
         }
     }
@@ -252,7 +668,22 @@ impl DPRunOptionsBuilder {
         }
     }
 
+    /// Limit the number of players in a hosted session (optional, must be at least 2).
+    ///
+    /// This only has an effect when hosting. Joining a session that is full fails with
+    /// [`JoinError::SessionFull`].
+    pub fn max_players(self, max_players: u32) -> Self {
+        Self {
+            max_players: Some(max_players),
+            ..self
+        }
+    }
+
     /// Set the directory dprun is in (optional, defaults to current working directory).
+    ///
+    /// With the `bundled-dprun` feature, the default is to extract the embedded dprun to a
+    /// temporary directory when the session starts. The directory is deleted when the session
+    /// ends.
     pub fn cwd(self, cwd: PathBuf) -> Self {
         Self {
             cwd: Some(cwd),
@@ -260,140 +691,1069 @@ impl DPRunOptionsBuilder {
         }
     }
 
+    /// Set the path to the dprun executable (optional, defaults to `dprun.exe` in the working
+    /// directory).
+    ///
+    /// On Windows the executable is started directly; elsewhere the path is passed to Wine. This
+    /// does not change the working directory, so relative service provider DLL paths are still
+    /// resolved from [`cwd`](Self::cwd). When this is set, the `bundled-dprun` feature does not
+    /// extract its embedded dprun.
+    pub fn binary_path(self, binary_path: PathBuf) -> Self {
+        Self {
+            binary_path: Some(binary_path),
+            ..self
+        }
+    }
+
+    /// Set how Wine is started on platforms other than Windows (optional, defaults to `wine`).
+    ///
+    /// This has no effect on Windows, where dprun is started directly.
+    pub fn wine(self, wine: WineConfig) -> Self {
+        Self {
+            wine: Some(wine),
+            ..self
+        }
+    }
+
+    /// Set an environment variable for dprun, like `WINEPREFIX` or `WINEDEBUG` (optional).
+    ///
+    /// Variables are set in order, so a later value for the same variable replaces an earlier one.
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Start dprun with an empty environment, except for the variables set with
+    /// [`env`](Self::env) (optional).
+    ///
+    /// Note that Wine usually needs at least `PATH` and `HOME` to be set.
+    pub fn env_clear(self) -> Self {
+        Self {
+            env_clear: true,
+            ..self
+        }
+    }
+
+    /// Try a range of ports for the host server, in order, until one of them is free.
+    ///
+    /// The port that ends up being used is advertised to dprun as the `INetPort` address part, and
+    /// can be read from the [`SessionHandle`] returned by [`DPRun::spawn`]. This replaces any
+    /// `INetPort` address part that was added manually.
+    ///
+    /// This only has an effect when a service provider handler or an upstream host server is set.
+    pub fn host_server_port_range(self, ports: RangeInclusive<u16>) -> Self {
+        Self {
+            host_server_port_range: Some(ports),
+            ..self
+        }
+    }
+
+    /// Let the operating system pick a free port for the host server.
+    ///
+    /// This is the same as `host_server_port_range(0..=0)`, and is useful when running many
+    /// sessions on one machine. The port is advertised to dprun and can be read from the
+    /// [`SessionHandle`] like with [`host_server_port_range`](Self::host_server_port_range).
+    pub fn host_server_any_port(self) -> Self {
+        self.host_server_port_range(0..=0)
+    }
+
+    /// Set the port that the host server binds to, separately from the `INetPort` address part
+    /// that is passed to dprun.
+    ///
+    /// By default, the host server binds to the port from the `INetPort` address part (or 2197),
+    /// so the bound port and the advertised port are the same. When the host is behind a port
+    /// forwarding rule that maps a different external port, set this to the local port and add
+    /// the external port as the `INetPort` address part. If no `INetPort` address part is set, the
+    /// bind port is advertised.
+    ///
+    /// This cannot be combined with [`host_server_port_range`](Self::host_server_port_range). It
+    /// only has an effect when a service provider handler or an upstream host server is set.
+    pub fn host_server_bind_port(self, port: u16) -> Self {
+        Self {
+            host_server_bind_port: Some(port),
+            ..self
+        }
+    }
+
+    /// Set the address of the interface that the host server listens on (optional, defaults to
+    /// `127.0.0.1`).
+    ///
+    /// This is useful on hosts with multiple interfaces, to accept connections only on one of them,
+    /// or to listen on IPv6 with `::1` or `::`. dprun connects to the host server through the
+    /// `INet` address part, so it should be set to an address that the host server is reachable at,
+    /// for example with `.named_address_part("INet", ip)`. The address is available from
+    /// [`AppController::local_addr`] for each connection. It only has an effect when a service
+    /// provider handler or an upstream host server is set.
+    pub fn host_server_bind(self, ip: IpAddr) -> Self {
+        Self {
+            host_server_bind: Some(ip),
+            ..self
+        }
+    }
+
+    /// Set how much dprun should log (optional, defaults to [`DprunLogLevel::Normal`]).
+    ///
+    /// With a log level above `Normal`, dprun's output is captured and forwarded to the `log` crate
+    /// at the `trace` level, unless dprun is attached to a pseudo-terminal.
+    pub fn verbosity(self, verbosity: DprunLogLevel) -> Self {
+        Self {
+            verbosity: Some(verbosity),
+            ..self
+        }
+    }
+
+    /// Attach dprun to a pseudo-terminal instead of inheriting or piping the standard streams.
+    ///
+    /// The master side of the terminal is available from [`SessionHandle::take_pty`]. This is
+    /// useful for debugging tools that want to display dprun's output as it would appear in a real
+    /// terminal.
+    #[cfg(unix)]
+    pub fn pty(self, pty: bool) -> Self {
+        Self { pty, ..self }
+    }
+
+    /// Look up the address of the session with a [`SessionResolver`] before joining it.
+    ///
+    /// The resolved endpoint is passed to dprun as the `INet` and `INetPort` address parts, and
+    /// replaces any `INet` or `INetPort` address parts that were added manually. This only has an
+    /// effect when joining a session.
+    pub fn session_resolver(self, resolver: Box<dyn SessionResolver>) -> Self {
+        Self {
+            session_resolver: Some(resolver),
+            ..self
+        }
+    }
+
+    /// Check that the host is reachable before joining a session, failing with a "host
+    /// unreachable" error if it cannot be connected to within `timeout` (optional).
+    ///
+    /// This only has an effect when joining with a [`session_resolver`](Self::session_resolver),
+    /// because that is when the host's TCP/IP address is known. To check other addresses, use
+    /// [`probe_reachable`].
+    pub fn probe_timeout(self, timeout: Duration) -> Self {
+        Self {
+            probe_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Fail if dprun has not started within `timeout` (optional).
+    ///
+    /// dprun counts as started once it connects to the host server, so this requires a service
+    /// provider handler or an upstream host server. If the timeout expires, dprun is killed, the
+    /// host server is stopped, and the session fails with a `TimedOut` error. The timeout only
+    /// covers startup, not the rest of the session.
+    pub fn start_timeout(self, timeout: Duration) -> Self {
+        Self {
+            start_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Stop waiting for the host server if it has not shut down within `grace` after dprun exits
+    /// (optional).
+    ///
+    /// By default, the session only ends once the host server has shut down. With a grace period,
+    /// a host server that does not shut down in time is dropped with a warning, and the session
+    /// ends with the result of dprun.
+    pub fn shutdown_grace(self, grace: Duration) -> Self {
+        Self {
+            shutdown_grace: Some(grace),
+            ..self
+        }
+    }
+
     /// Add an address part.
     pub fn address_part(mut self, data_type: GUID, value: impl Into<DPAddressValue>) -> Self {
-        self.address.push(DPAddressPart {
+        self.address.push(AddressPartSource::Fixed(DPAddressPart {
             data_type: data_type.into(),
             value: value.into(),
-        });
+        }));
         self
     }
 
     /// Add an address part.
     pub fn named_address_part(mut self, data_type: &str, value: impl Into<DPAddressValue>) -> Self {
-        self.address.push(DPAddressPart {
+        self.address.push(AddressPartSource::Fixed(DPAddressPart {
             data_type: data_type.into(),
             value: value.into(),
-        });
+        }));
         self
     }
 
+    /// Add an address part whose value is computed right before the dprun command is built, in
+    /// [`run`].
+    ///
+    /// This is useful for values that are not known when the options are created, like a public IP
+    /// address. When a host server port range is set, the `INetPort` address part is filled in
+    /// automatically once the host server has found a free port, so it does not need a callback.
+    pub fn dynamic_address_part(
+        mut self,
+        data_type: &str,
+        value: impl FnOnce() -> DPAddressValue + Send + 'static,
+    ) -> Self {
+        self.address.push(AddressPartSource::Dynamic(
+            data_type.into(),
+            Box::new(value),
+        ));
+        self
+    }
+
+    /// Allow adding the same address part data type more than once.
+    ///
+    /// By default, [`finish`](Self::finish) returns [`BuilderError::DuplicateAddressPart`] when a
+    /// data type is repeated, because dprun would silently use only one of the values. Named data
+    /// types and their GUIDs count as the same data type.
+    pub fn allow_duplicate_address_parts(self) -> Self {
+        Self {
+            allow_duplicate_address_parts: true,
+            ..self
+        }
+    }
+
     /// Check the options and build the DPRunOptions struct.
-    pub fn finish(self) -> DPRunOptions {
-        let session_type = self.session_type.expect("must set a session type");
-        let player_name = self.player_name.expect("must set a player name");
-        let service_provider = self.service_provider.expect("must set a service provider");
-        let application = self
-            .application
-            .expect("must set an application GUID to run");
-        if service_provider == DPGUIDOrNamed::GUID(*GUID_DPRUNSP)
-            || service_provider == DPGUIDOrNamed::Named("DPRUN".to_string())
+    pub fn finish(self) -> Result<DPRunOptions, BuilderError> {
+        let session_type = self.session_type.ok_or(BuilderError::MissingSessionType)?;
+        let player_name = self.player_name.ok_or(BuilderError::MissingPlayerName)?;
+        let service_provider = self
+            .service_provider
+            .ok_or(BuilderError::MissingServiceProvider)?;
+        if self.applications.is_empty() {
+            return Err(BuilderError::MissingApplication);
+        }
+        if (service_provider == DPGUIDOrNamed::GUID(guids::SP_DPRUN)
+            || service_provider == DPGUIDOrNamed::Named("DPRUN".to_string()))
+            && self.service_provider_handler.is_none()
+            && self.host_server_upstream.is_none()
         {
-            assert!(
-                self.service_provider_handler.is_some(),
-                "must register a service provider handler to use the DPRun service provider",
-            );
+            return Err(BuilderError::MissingServiceProviderHandler);
+        }
+        if self.service_provider_handler.is_some() && self.host_server_upstream.is_some() {
+            return Err(BuilderError::ConflictingBackends);
+        }
+        if let Some(dll) = &self.service_provider_dll {
+            let path = match &self.cwd {
+                Some(cwd) => cwd.join(dll),
+                None => dll.clone(),
+            };
+            if !path.is_file() {
+                return Err(BuilderError::ServiceProviderDllNotFound(path));
+            }
+        }
+        if let Some(player_data) = &self.player_data {
+            if player_data.len() > MAX_PLAYER_DATA_SIZE {
+                return Err(BuilderError::PlayerDataTooLarge(player_data.len()));
+            }
+        }
+        if let Some(ports) = &self.host_server_port_range {
+            if ports.is_empty() {
+                return Err(BuilderError::EmptyPortRange);
+            }
+            if self.host_server_bind_port.is_some() {
+                return Err(BuilderError::ConflictingHostServerPorts);
+            }
+        }
+        if !self.allow_duplicate_address_parts {
+            let mut seen = vec![];
+            for part in &self.address {
+                let data_type = part.data_type();
+                let key = match data_type {
+                    DPGUIDOrNamed::GUID(guid) => Some(*guid),
+                    DPGUIDOrNamed::Named(name) => address_type_guid(name),
+                };
+                let key = key.ok_or_else(|| data_type.to_string());
+                if seen.contains(&key) {
+                    return Err(BuilderError::DuplicateAddressPart(data_type.to_string()));
+                }
+                seen.push(key);
+            }
+        }
+        if let Some(max_players) = self.max_players {
+            if max_players < 2 {
+                return Err(BuilderError::InvalidMaxPlayers(max_players));
+            }
+        }
+        if self.start_timeout.is_some()
+            && self.service_provider_handler.is_none()
+            && self.host_server_upstream.is_none()
+        {
+            return Err(BuilderError::StartTimeoutWithoutHostServer);
         }
 
-        DPRunOptions {
+        Ok(DPRunOptions {
             session_type,
             player_name,
+            player_data: self.player_data,
             service_provider,
             service_provider_handler: self.service_provider_handler,
-            application,
+            host_server_upstream: self.host_server_upstream,
+            service_provider_dll: self.service_provider_dll,
+            applications: self.applications,
             address: self.address,
+            allow_duplicate_address_parts: self.allow_duplicate_address_parts,
             session_name: self.session_name,
             session_password: self.session_password,
+            max_players: self.max_players,
             cwd: self.cwd,
-        }
+            binary_path: self.binary_path,
+            wine: self.wine,
+            env: self.env,
+            env_clear: self.env_clear,
+            host_server_port_range: self.host_server_port_range,
+            host_server_bind_port: self.host_server_bind_port,
+            host_server_bind: self.host_server_bind,
+            verbosity: self.verbosity,
+            session_resolver: self.session_resolver,
+            probe_timeout: self.probe_timeout,
+            start_timeout: self.start_timeout,
+            shutdown_grace: self.shutdown_grace,
+            #[cfg(unix)]
+            pty: self.pty,
+        })
     }
 }
 
+/// The command that dprun would be started with, from [`DPRunOptions::dry_run`].
+///
+/// The arguments include the session password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DPRunCommand {
+    /// The program to start: Wine, or dprun itself on Windows.
+    pub program: String,
+    /// The arguments to the program, for the first application.
+    pub args: Vec<String>,
+    /// The working directory, if it is not the current one.
+    pub cwd: Option<PathBuf>,
+    /// Environment variables to set, in order.
+    pub env: Vec<(String, String)>,
+    /// Whether to start with an empty environment before setting `env`.
+    pub env_clear: bool,
+}
+
 /// Represents a dprun game session.
 pub struct DPRun {
-    command: Command,
+    cwd: Option<PathBuf>,
+    binary_path: Option<PathBuf>,
+    wine: WineConfig,
+    env: Vec<(OsString, OsString)>,
+    env_clear: bool,
+    /// Arguments for dprun, except for the application.
+    args: Vec<OsString>,
+    applications: Vec<GUID>,
+    /// Whether dprun joins a session. Failed joins are retried with the next application.
+    joining: bool,
+    /// The port from the `INetPort` address part.
     host_server_port: Option<u16>,
-    service_provider: Option<Box<dyn ServiceProvider>>,
+    host_server_port_range: Option<RangeInclusive<u16>>,
+    host_server_bind_port: Option<u16>,
+    host_server_bind: Option<IpAddr>,
+    /// Where the host server sends messages, if the session uses one.
+    backend: Option<Backend>,
+    /// The session to look up before joining, and the resolver to look it up with.
+    resolver: Option<(GUID, Box<dyn SessionResolver>)>,
+    /// How long to wait when checking that the resolved host is reachable.
+    probe_timeout: Option<Duration>,
+    /// How long to wait for dprun to connect to the host server.
+    start_timeout: Option<Duration>,
+    /// How long to wait for the host server to shut down after dprun exits.
+    shutdown_grace: Option<Duration>,
+    /// Whether to capture and log the output of dprun.
+    capture_output: bool,
+    #[cfg(unix)]
+    pty: bool,
+    /// Configuration that is only needed for [`DPRun::diagnostics_json`].
+    #[cfg(feature = "serde")]
+    info: SessionInfo,
 }
 
-impl DPRun {
-    /// Get the command that will be executed (for debugging).
-    pub fn command(&self) -> String {
-        format!("{:?}", self.command)
+/// The dprun process that is currently running for a session.
+#[derive(Debug, Clone, Copy)]
+struct Attempt {
+    pid: u32,
+    application: GUID,
+}
+
+/// Handle to a running dprun game session.
+pub struct SessionHandle {
+    attempt: Arc<Mutex<Attempt>>,
+    /// Set when dprun was stopped through the handle, so it is not restarted.
+    stopped: Arc<AtomicBool>,
+    host_server_port: Option<u16>,
+    #[cfg(unix)]
+    pty: Option<PtyMaster>,
+    session: BoxFuture<'static, Result<(), io::Error>>,
+}
+
+impl SessionHandle {
+    /// Get the port that the host server for the DPRun Service Provider is listening on, if the
+    /// session uses one.
+    pub fn host_server_port(&self) -> Option<u16> {
+        self.host_server_port
     }
 
-    /// Start a game without the host server for the DPRun Service Provider.
-    async fn start_without_server(mut self) -> Result<(), io::Error> {
-        let status = self.command.status().await?;
-        if status.success() {
-            Ok(())
-        } else {
+    /// Get the process ID of dprun.
+    ///
+    /// When dprun is started through Wine, this is the ID of the `wine` process. When dprun is
+    /// restarted with another application, this changes to the ID of the new process.
+    pub fn pid(&self) -> u32 {
+        self.attempt.lock().unwrap().pid
+    }
+
+    /// Ask dprun to exit, by sending it `SIGTERM`.
+    ///
+    /// dprun is not restarted with another application afterwards. This is only supported on Unix;
+    /// on other platforms, use [`SessionHandle::kill`] or drop the handle.
+    pub fn terminate(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            self.signal(libc::SIGTERM)
+        }
+        #[cfg(not(unix))]
+        {
             Err(io::Error::new(
                 io::ErrorKind::Other,
-                format!("dprun exited with status {}", status.code().unwrap_or(0)),
+                "terminating dprun is only supported on Unix",
             ))
         }
     }
 
-    /// Start a game that uses the host server for the DPRun Service Provider.
-    async fn start_with_server(self) -> Result<(), io::Error> {
-        let server = HostServer::new(
-            self.host_server_port.unwrap_or(2197),
-            self.service_provider.unwrap(),
-        );
-
-        let (server, mut controller) = server.start().await?;
-        let mut command = self.command;
-        let mut child = command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+    /// Kill dprun immediately.
+    ///
+    /// dprun is not restarted with another application afterwards. The session ends with an
+    /// error once dprun has exited. This is only supported on Unix; on other platforms, drop the
+    /// handle to kill dprun.
+    pub fn kill(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            self.signal(libc::SIGKILL)
+        }
+        #[cfg(not(unix))]
+        {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "killing dprun is only supported on Unix",
+            ))
+        }
+    }
 
-        let command_future = async move {
-            let mut result = Ok(());
+    /// Send a signal to the current dprun process.
+    #[cfg(unix)]
+    fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+        let attempt = self.attempt.lock().unwrap();
+        self.stopped.store(true, Ordering::SeqCst);
+        if unsafe { libc::kill(attempt.pid as libc::pid_t, signal) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
 
-            let mut stdout = BufReader::new(child.stdout.as_mut().unwrap()).lines();
-            let mut stderr = BufReader::new(child.stderr.as_mut().unwrap()).lines();
+    /// Get the application that dprun is running.
+    ///
+    /// When multiple applications were given to [`DPRunOptionsBuilder::applications`], this
+    /// changes as dprun retries with the next application. Once the session has ended, this is the
+    /// application that was last tried.
+    pub fn application(&self) -> GUID {
+        self.attempt.lock().unwrap().application
+    }
 
-            futures::join!(
-                async move {
-                    while let Some(Ok(line)) = stdout.next().await {
-                        log::trace!("out {}", line);
-                    }
-                },
-                async move {
-                    while let Some(Ok(line)) = stderr.next().await {
+    /// Sample the CPU and memory usage of the dprun process every `interval`.
+    ///
+    /// This is only supported on Linux; on other platforms, the stream yields errors. When dprun is
+    /// started through Wine, the sampled process is the `wine` process. The game itself may run in a
+    /// different process that is not included in these statistics.
+    pub fn resource_usage(
+        &self,
+        interval: Duration,
+    ) -> impl Stream<Item = io::Result<ProcessStats>> {
+        let attempt = Arc::clone(&self.attempt);
+        let mut pid = attempt.lock().unwrap().pid;
+        let mut sampler = stats::Sampler::new(pid);
+        async_std::stream::interval(interval).map(move |_| {
+            // dprun may have been restarted with a different application.
+            let current_pid = attempt.lock().unwrap().pid;
+            if current_pid != pid {
+                pid = current_pid;
+                sampler = stats::Sampler::new(pid);
+            }
+            sampler.sample()
+        })
+    }
+
+    /// Take the master side of the pseudo-terminal that dprun is attached to.
+    ///
+    /// This returns `None` if the session was not started with [`DPRunOptionsBuilder::pty`], or if
+    /// the terminal was already taken.
+    #[cfg(unix)]
+    pub fn take_pty(&mut self) -> Option<PtyMaster> {
+        self.pty.take()
+    }
+
+    /// Wait for the game session to end.
+    ///
+    /// Dropping the handle, or the future returned by this method, kills dprun and stops the host
+    /// server.
+    pub async fn wait(self) -> Result<(), io::Error> {
+        self.session.await
+    }
+}
+
+/// Creates dprun processes for each application that a session may try.
+struct Launcher {
+    cwd: Option<PathBuf>,
+    binary_path: Option<PathBuf>,
+    wine: WineConfig,
+    env: Vec<(OsString, OsString)>,
+    env_clear: bool,
+    args: Vec<OsString>,
+    /// Whether to pipe and log the output of dprun.
+    log_output: bool,
+    /// Whether to pipe stderr, so failures can be classified.
+    capture_stderr: bool,
+    /// The slave side of the pseudo-terminal that dprun is attached to.
+    #[cfg(unix)]
+    pty: Option<std::fs::File>,
+}
+
+impl Launcher {
+    /// Build the dprun command for an application.
+    fn command(&self, application: &GUID) -> Result<Command, io::Error> {
+        let (program, args) = self.program_and_args(application);
+        let mut command = Command::new(program);
+        command.args(args);
+
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        if self.env_clear {
+            command.env_clear();
+        }
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+
+        // Don't leave dprun running if the session is dropped.
+        command.kill_on_drop(true);
+
+        #[cfg(unix)]
+        {
+            if let Some(slave) = &self.pty {
+                pty::attach(&mut command, slave)?;
+            }
+        }
+        if self.log_output {
+            command.stdout(Stdio::piped());
+        }
+        if self.capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+
+        Ok(command)
+    }
+
+    /// Get the program to start and its arguments, to run dprun for an application.
+    fn program_and_args(&self, application: &GUID) -> (OsString, Vec<OsString>) {
+        let binary = self
+            .binary_path
+            .as_deref()
+            .unwrap_or_else(|| Path::new("dprun.exe"));
+        let (program, mut args) = if cfg!(target_os = "windows") {
+            (binary.into(), vec![])
+        } else {
+            let mut args = self.wine.args().to_vec();
+            args.push(binary.into());
+            (self.wine.binary().into(), args)
+        };
+        args.extend(self.args.iter().cloned());
+        args.push("--application".into());
+        args.push(to_braced(application).into());
+        (program, args)
+    }
+
+    /// Start dprun for an application.
+    fn spawn(&self, application: &GUID) -> Result<(Child, Attempt), io::Error> {
+        let child = self.command(application)?.spawn()?;
+        let attempt = Attempt {
+            pid: child.id(),
+            application: *application,
+        };
+        Ok((child, attempt))
+    }
+}
+
+/// Whether a failed join may succeed with a different application.
+///
+/// This is the case if dprun reported that the application is not registered, or if it failed
+/// without saying why. Errors that do not depend on the application, like a wrong password or a
+/// full session, are returned without trying the other applications.
+fn retry_with_next_application(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<JoinError>()),
+        Some(JoinError::ApplicationNotFound) | Some(JoinError::Unknown { .. })
+    )
+}
+
+/// Wait for a dprun process to exit, logging its output if it is piped.
+///
+/// If dprun fails, the error contains a [`JoinError`] or [`HostError`], classified from the exit
+/// code and stderr. If `startup` is given, dprun is killed if the receiver does not get a message
+/// within the timeout.
+async fn wait_for_child(
+    mut child: Child,
+    joining: bool,
+    startup: Option<(Duration, &Receiver<()>)>,
+) -> Result<(), io::Error> {
+    // When only stderr is piped, it is logged at a level that is shown by default, instead of
+    // being hidden at trace level with the rest of the output.
+    let log_output = child.stdout.is_some();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let output = futures::future::join(
+        async move {
+            if let Some(stdout) = stdout {
+                let mut stdout = BufReader::new(stdout).lines();
+                while let Some(Ok(line)) = stdout.next().await {
+                    log::trace!("out {}", line);
+                }
+            }
+        },
+        async move {
+            let mut lines = vec![];
+            if let Some(stderr) = stderr {
+                let mut stderr = BufReader::new(stderr).lines();
+                while let Some(Ok(line)) = stderr.next().await {
+                    if log_output {
                         log::trace!("err {}", line);
+                    } else {
+                        log::warn!("err {}", line);
+                    }
+                    if lines.len() == MAX_STDERR_LINES {
+                        lines.remove(0);
                     }
+                    lines.push(line);
                 }
-            );
+            }
+            lines
+        },
+    );
+
+    let result = {
+        let exit = async {
+            let ((), stderr_lines) = output.await;
+            (child.status().await, stderr_lines)
+        };
+        futures::pin_mut!(exit);
+        match startup {
+            None => Ok(exit.await),
+            Some((timeout, started)) => {
+                let started = async {
+                    io::timeout(timeout, async {
+                        started
+                            .recv()
+                            .await
+                            .map_err(|_| io::Error::other("host server stopped"))
+                    })
+                    .await
+                    .map_err(|err| match err.kind() {
+                        io::ErrorKind::TimedOut => io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("dprun did not start within {:?}", timeout),
+                        ),
+                        _ => err,
+                    })
+                };
+                futures::pin_mut!(started);
+                match futures::future::select(exit, started).await {
+                    Either::Left((exited, _)) => Ok(exited),
+                    Either::Right((Ok(()), exit)) => Ok(exit.await),
+                    Either::Right((Err(err), _)) => Err(err),
+                }
+            }
+        }
+    };
+    let (status, stderr_lines) = match result {
+        Ok(exited) => exited,
+        Err(err) => {
+            let _ = child.kill();
+            return Err(err);
+        }
+    };
 
-            let status = child.status().await?;
-            if status.success() {
-                result = Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("dprun exited with status {}", status.code().unwrap_or(0)),
-                ));
+    let status = status?;
+    if status.success() {
+        Ok(())
+    } else {
+        let failure = ExitFailure {
+            code: status.code(),
+            stderr: stderr_lines.join("\n"),
+        };
+        Err(failure.into_io_error(joining))
+    }
+}
+
+/// Get the startup timeout for the next dprun attempt, and the channel that signals when it has
+/// started. Connections from previous attempts are ignored.
+fn startup(
+    timeout: Option<Duration>,
+    connections: &Option<Receiver<()>>,
+) -> Option<(Duration, &Receiver<()>)> {
+    let connections = connections.as_ref()?;
+    while connections.try_recv().is_ok() {}
+    Some((timeout?, connections))
+}
+
+impl DPRun {
+    /// Get the dprun arguments with the session password replaced by a placeholder.
+    fn redacted_args(&self) -> Vec<OsString> {
+        let mut args = vec![];
+        let mut redact_next = false;
+        for arg in &self.args {
+            if redact_next {
+                args.push(REDACTED.into());
+            } else {
+                args.push(arg.clone());
             }
+            redact_next = arg == "--session-password";
+        }
+        args
+    }
 
-            controller.stop().await;
+    /// Get the command that will be executed (for debugging).
+    ///
+    /// The session password is redacted, so the command can be logged safely. Use
+    /// [`DPRun::command_unredacted`] to get the full command.
+    pub fn command(&self) -> String {
+        self.format_command(self.redacted_args())
+    }
+
+    /// Get the command that will be executed, including the session password.
+    pub fn command_unredacted(&self) -> String {
+        self.format_command(self.args.clone())
+    }
 
+    /// Get the program that will be executed: Wine, or dprun itself on Windows.
+    ///
+    /// Together with [`DPRun::args`], this can be used to start dprun through a different
+    /// launcher. The working directory and environment variables are not included.
+    pub fn program(&self) -> String {
+        let (program, _) = self.program_and_args();
+        program.to_string_lossy().into_owned()
+    }
+
+    /// Get the arguments that will be passed to [`DPRun::program`], for the first application.
+    ///
+    /// Unlike [`DPRun::command`], this includes the session password, so it should not be logged
+    /// as-is.
+    pub fn args(&self) -> Vec<String> {
+        let (_, args) = self.program_and_args();
+        args.iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Build the command for the first application, without starting it.
+    pub fn dry_run(&self) -> DPRunCommand {
+        let lossy = |string: &OsString| string.to_string_lossy().into_owned();
+        DPRunCommand {
+            program: self.program(),
+            args: self.args(),
+            cwd: self.cwd.clone(),
+            env: self
+                .env
+                .iter()
+                .map(|(key, value)| (lossy(key), lossy(value)))
+                .collect(),
+            env_clear: self.env_clear,
+        }
+    }
+
+    /// Get the program and arguments that the first application will be started with.
+    fn program_and_args(&self) -> (OsString, Vec<OsString>) {
+        self.launcher(self.args.clone())
+            .program_and_args(&self.applications[0])
+    }
+
+    /// Create a launcher that only builds commands, with the given dprun arguments.
+    fn launcher(&self, args: Vec<OsString>) -> Launcher {
+        Launcher {
+            cwd: self.cwd.clone(),
+            binary_path: self.binary_path.clone(),
+            wine: self.wine.clone(),
+            env: self.env.clone(),
+            env_clear: self.env_clear,
+            args,
+            log_output: false,
+            capture_stderr: false,
+            #[cfg(unix)]
+            pty: None,
+        }
+    }
+
+    /// Format the dprun command with the given arguments.
+    fn format_command(&self, args: Vec<OsString>) -> String {
+        match self.launcher(args).command(&self.applications[0]) {
+            Ok(command) => format!("{:?}", command),
+            Err(err) => format!("<{}>", err),
+        }
+    }
+
+    /// Describe the complete effective configuration as JSON, for bug reports.
+    ///
+    /// This includes the launcher, the path to dprun, the environment variables that affect Wine,
+    /// the session type, service provider, applications, address parts and host server ports.
+    /// The session password and environment variables that look like secrets are redacted. When
+    /// the host server uses a port range or a session resolver is set, the final port and address
+    /// are only known once the session is started.
+    ///
+    /// This is only available with the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn diagnostics_json(&self) -> String {
+        let args = self
+            .redacted_args()
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+
+        let binary = match (&self.binary_path, &self.cwd) {
+            (Some(binary_path), _) => binary_path.clone(),
+            (None, Some(cwd)) => cwd.join("dprun.exe"),
+            (None, None) => PathBuf::from("dprun.exe"),
+        };
+        let host_server = self.backend.as_ref().map(|_| {
+            let (ports, advertise_port) = self.host_server_ports();
+            HostServerInfo {
+                address: self.host_server_bind.unwrap_or(DEFAULT_HOST_SERVER_IP),
+                first_port: *ports.start(),
+                last_port: *ports.end(),
+                advertise_port,
+            }
+        });
+
+        let (launcher, launcher_args) = if cfg!(target_os = "windows") {
+            (binary.clone(), vec![])
+        } else {
+            let args = self.wine.args().iter();
+            (
+                self.wine.binary().to_path_buf(),
+                args.map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            )
+        };
+
+        let diagnostics = Diagnostics {
+            launcher,
+            launcher_args,
+            binary,
+            args,
+            env: diagnostics::child_env(self.env_clear, &self.env),
+            env_clear: self.env_clear,
+            applications: self.applications.iter().map(to_braced).collect(),
+            session: &self.info,
+            host_server,
+        };
+        serde_json::to_string_pretty(&diagnostics).expect("diagnostics are always serializable")
+    }
+
+    /// Get the ports that the host server may bind to, and whether the bound port must be passed
+    /// to dprun as the `INetPort` address part.
+    fn host_server_ports(&self) -> (RangeInclusive<u16>, bool) {
+        match (&self.host_server_port_range, self.host_server_bind_port) {
+            (Some(ports), _) => (ports.clone(), true),
+            (None, Some(port)) => (port..=port, self.host_server_port.is_none()),
+            (None, None) => {
+                let port = self.host_server_port.unwrap_or(2197);
+                (port..=port, false)
+            }
+        }
+    }
+
+    /// Start dprun, returning a handle to the running session.
+    ///
+    /// If the session uses a service provider handler, this also starts the host server for the
+    /// DPRun Service Provider, which keeps running until dprun exits. If a session resolver is set,
+    /// the session's address is looked up first.
+    pub async fn spawn(mut self) -> Result<SessionHandle, io::Error> {
+        #[cfg(feature = "bundled-dprun")]
+        let bundle = match (&self.cwd, &self.binary_path) {
+            (None, None) => {
+                let bundle = bundled::BundledDprun::extract()?;
+                self.cwd = Some(bundle.path().to_path_buf());
+                Some(bundle)
+            }
+            _ => None,
+        };
+
+        if let Some((session, resolver)) = self.resolver.take() {
+            let address = resolve_address(resolver.as_ref(), session, self.probe_timeout).await?;
+            self.args.extend(address);
+        }
+
+        let mut connections = None;
+        let server = match self.backend.take() {
+            Some(backend) => {
+                let (ports, advertise_port) = self.host_server_ports();
+                let mut server = HostServer::new(ports, backend);
+                if let Some(ip) = self.host_server_bind {
+                    server = server.bind_ip(ip);
+                }
+                if self.start_timeout.is_some() {
+                    connections = Some(server.connections());
+                }
+                let (server, controller, port) = server.start().await?;
+                if advertise_port {
+                    self.args.push("--address".into());
+                    self.args.push(format!("INetPort=i:{}", port).into());
+                }
+                Some((server, controller, port))
+            }
+            None => None,
+        };
+
+        #[cfg(unix)]
+        let (pty_master, pty_slave) = if self.pty {
+            let (master, slave) = pty::open()?;
+            (Some(master), Some(slave))
+        } else {
+            (None, None)
+        };
+
+        #[cfg(unix)]
+        let log_output = (server.is_some() || self.capture_output) && pty_slave.is_none();
+        #[cfg(not(unix))]
+        let log_output = server.is_some() || self.capture_output;
+
+        #[cfg(unix)]
+        let capture_stderr = pty_slave.is_none();
+        #[cfg(not(unix))]
+        let capture_stderr = true;
+
+        let launcher = Launcher {
+            cwd: self.cwd,
+            binary_path: self.binary_path,
+            wine: self.wine,
+            env: self.env,
+            env_clear: self.env_clear,
+            args: self.args,
+            log_output,
+            capture_stderr,
+            #[cfg(unix)]
+            pty: pty_slave,
+        };
+
+        let mut applications = self.applications.into_iter();
+        let first_application = applications.next().unwrap();
+        let (child, attempt) = launcher.spawn(&first_application)?;
+        let attempt = Arc::new(Mutex::new(attempt));
+        let joining = self.joining;
+        let start_timeout = self.start_timeout;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let session_attempt = Arc::clone(&attempt);
+        let session_stopped = Arc::clone(&stopped);
+        let command_future = async move {
+            let mut result =
+                wait_for_child(child, joining, startup(start_timeout, &connections)).await;
+            while joining && matches!(&result, Err(err) if retry_with_next_application(err)) {
+                let application = match applications.next() {
+                    Some(application) => application,
+                    None => break,
+                };
+                log::info!(
+                    "dprun failed with application {}, retrying with {}",
+                    to_braced(&session_attempt.lock().unwrap().application),
+                    to_braced(&application)
+                );
+                let spawned = {
+                    // Hold the lock so dprun cannot be stopped while it is being restarted.
+                    let mut current = session_attempt.lock().unwrap();
+                    if session_stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    launcher.spawn(&application).map(|(child, attempt)| {
+                        *current = attempt;
+                        child
+                    })
+                };
+                result = match spawned {
+                    Ok(child) => {
+                        wait_for_child(child, joining, startup(start_timeout, &connections)).await
+                    }
+                    Err(err) => Err(err),
+                };
+            }
+            // Keep the extracted dprun until it is no longer needed.
+            #[cfg(feature = "bundled-dprun")]
+            drop(bundle);
             result
         };
 
-        let (_, result) = futures::future::join(server, command_future).await;
-        result
+        let (session, host_server_port): (BoxFuture<'static, _>, _) = match server {
+            Some((server, controller, port)) => {
+                let session =
+                    with_host_server(server, controller, command_future, self.shutdown_grace);
+                (Box::pin(session), Some(port))
+            }
+            None => (Box::pin(command_future), None),
+        };
+
+        Ok(SessionHandle {
+            attempt,
+            stopped,
+            host_server_port,
+            #[cfg(unix)]
+            pty: pty_master,
+            session,
+        })
     }
 
-    /// Start dprun.
+    /// Start dprun and wait for the session to end.
+    ///
+    /// If dprun fails, the error contains a [`JoinError`] or [`HostError`] describing why. Dropping
+    /// the returned future kills dprun and stops the host server.
     pub async fn start(self) -> Result<(), io::Error> {
-        match self.service_provider {
-            Some(_) => self.start_with_server().await,
-            None => self.start_without_server().await,
-        }
+        self.spawn().await?.wait().await
     }
 }
 
+/// Run the host server until a session ends, and then stop it.
+///
+/// If the server has not shut down within `grace` after the session ends, it is dropped.
+async fn with_host_server<T>(
+    server: impl Future<Output = ()>,
+    mut controller: ServerController,
+    session: impl Future<Output = T>,
+    grace: Option<Duration>,
+) -> T {
+    let (abort, aborted) = channel::bounded::<()>(1);
+    let session = async move {
+        let result = session.await;
+        log::debug!(
+            target: "dprun::server",
+            "dprun exited, waiting for the host server to shut down"
+        );
+        let stopped = match grace {
+            Some(grace) => async_std::future::timeout(grace, controller.stop()).await,
+            None => Ok(controller.stop().await),
+        };
+        match stopped {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => log::warn!(target: "dprun::server", "host server failed: {}", err),
+            Err(_) => {
+                log::warn!(
+                    target: "dprun::server",
+                    "host server did not shut down within {:?}, dropping it",
+                    grace.unwrap_or_default()
+                );
+                let _ = abort.try_send(());
+            }
+        }
+        result
+    };
+    let server = async move {
+        futures::future::select(Box::pin(server), Box::pin(aborted.recv())).await;
+    };
+    let (_, result) = futures::future::join(server, session).await;
+    result
+}
+
 fn to_braced(guid: &GUID) -> String {
     let res = &mut [0u8; 38];
     res[0] = b'{';
@@ -402,36 +1762,72 @@ fn to_braced(guid: &GUID) -> String {
     String::from_utf8_lossy(res).to_string()
 }
 
-/// Run a game using DPRun. The options can be created using DPRunOptions::builder().
-pub fn run(options: DPRunOptions) -> DPRun {
-    let mut command = if cfg!(target_os = "windows") {
-        Command::new("dprun.exe")
-    } else {
-        let mut wine = Command::new("wine");
-        wine.arg("dprun.exe");
-        wine
-    };
+/// Encode bytes as a lowercase hexadecimal string.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|c| format!("{:02x}", c)).collect()
+}
 
-    if let Some(cwd) = options.cwd {
-        command.current_dir(cwd);
+/// Get the GUID of an address part data type. Names are matched case-insensitively.
+fn data_type_guid(data_type: &DPGUIDOrNamed) -> Option<GUID> {
+    match data_type {
+        DPGUIDOrNamed::GUID(guid) => Some(*guid),
+        DPGUIDOrNamed::Named(name) => address_type_guid(name),
     }
+}
+
+/// Check if an address part data type refers to the INet address type.
+fn is_inet(data_type: &DPGUIDOrNamed) -> bool {
+    data_type_guid(data_type) == Some(guids::ADDR_INET)
+}
+
+/// Check if an address part data type refers to the INetPort address type.
+fn is_inet_port(data_type: &DPGUIDOrNamed) -> bool {
+    data_type_guid(data_type) == Some(guids::ADDR_INET_PORT)
+}
+
+/// Run a game using DPRun. The options can be created using DPRunOptions::builder().
+pub fn run(options: DPRunOptions) -> DPRun {
+    let mut args: Vec<OsString> = vec![];
 
     match options.session_type {
-        SessionType::Host(Some(guid)) => command.args(&["--host", &to_braced(&guid)]),
-        SessionType::Host(None) => command.arg("--host"),
-        SessionType::Join(guid) => command.args(&["--join", &to_braced(&guid)]),
+        SessionType::Host(Some(guid)) => {
+            args.push("--host".into());
+            args.push(to_braced(&guid).into());
+        }
+        SessionType::Host(None) => args.push("--host".into()),
+        SessionType::Join(guid) => {
+            args.push("--join".into());
+            args.push(to_braced(&guid).into());
+        }
     };
+    let joining = matches!(options.session_type, SessionType::Join(_));
+    let resolver = match (options.session_type, options.session_resolver) {
+        (SessionType::Join(session), Some(resolver)) => Some((session, resolver)),
+        _ => None,
+    };
+
+    let address: Vec<DPAddressPart> = options
+        .address
+        .into_iter()
+        .map(AddressPartSource::resolve)
+        .collect();
 
-    let service_provider = options.service_provider_handler;
+    let backend = match (
+        options.service_provider_handler,
+        options.host_server_upstream,
+    ) {
+        (Some(service_provider), _) => Some(Backend::Local(service_provider)),
+        (None, Some(upstream)) => Some(Backend::Upstream(upstream)),
+        (None, None) => None,
+    };
+    let host_server_port_range = options.host_server_port_range.filter(|_| backend.is_some());
+    let host_server_bind_port = options.host_server_bind_port.filter(|_| backend.is_some());
+    let host_server_bind = options.host_server_bind.filter(|_| backend.is_some());
 
-    let host_server_port = if service_provider.is_some() {
-        options
-            .address
+    let host_server_port = if backend.is_some() {
+        address
             .iter()
-            .find(|part| {
-                part.data_type == DPGUIDOrNamed::GUID(*GUID_INETPORT)
-                    || part.data_type == DPGUIDOrNamed::Named("INetPort".to_string())
-            })
+            .find(|part| is_inet_port(&part.data_type))
             .map(|part| {
                 if let DPAddressValue::Number(val) = part.value {
                     val as u16
@@ -443,47 +1839,572 @@ pub fn run(options: DPRunOptions) -> DPRun {
         None
     };
 
-    command.args(&[
-        "--player",
-        &options.player_name,
-        "--service-provider",
-        &options.service_provider.into_string(),
-        "--application",
-        &to_braced(&options.application),
-    ]);
+    args.push("--player".into());
+    args.push(options.player_name.into());
+    let service_provider = options.service_provider.into_string();
+    args.push("--service-provider".into());
+    args.push(service_provider.clone().into());
 
-    for part in options.address {
+    if let Some(dll) = &options.service_provider_dll {
+        args.push("--service-provider-dll".into());
+        args.push(dll.into());
+    }
+
+    let mut address_args = vec![];
+
+    for part in address {
+        // The port is added when the host server starts, once we know which one is free.
+        if host_server_port_range.is_some() && is_inet_port(&part.data_type) {
+            continue;
+        }
+        // The address is added by the session resolver.
+        if resolver.is_some() && (is_inet(&part.data_type) || is_inet_port(&part.data_type)) {
+            continue;
+        }
         let key = part.data_type.into_string();
-        let value = match part.value {
-            DPAddressValue::Number(val) => format!("i:{}", val),
-            DPAddressValue::String(val) => val,
-            DPAddressValue::Binary(val) => format!(
-                "b:{}",
-                val.iter().map(|c| format!("{:02x}", c)).collect::<String>()
-            ),
-        };
-        command.args(&["--address", &format!("{}={}", key, value)]);
+        address_args.push(format!("{}={}", key, part.value));
+    }
+    for part in &address_args {
+        args.push("--address".into());
+        args.push(part.into());
+    }
+
+    if let Some(player_data) = options.player_data {
+        args.push("--player-data".into());
+        args.push(to_hex(&player_data).into());
     }
 
     if let Some(name) = options.session_name {
-        command.args(&["--session-name", &name]);
+        args.push("--session-name".into());
+        args.push(name.into());
     }
 
     if let Some(password) = options.session_password {
-        command.args(&["--session-password", &password]);
+        args.push("--session-password".into());
+        args.push(password.into());
     }
 
+    if let (SessionType::Host(_), Some(max_players)) = (options.session_type, options.max_players) {
+        args.push("--max-players".into());
+        args.push(max_players.to_string().into());
+    }
+
+    let verbosity = options.verbosity.unwrap_or(DprunLogLevel::Normal);
+    args.extend(verbosity.flags().iter().map(OsString::from));
+
+    #[cfg(feature = "serde")]
+    let info = {
+        let (session_type, session) = match options.session_type {
+            SessionType::Host(session) => ("host", session),
+            SessionType::Join(session) => ("join", Some(session)),
+        };
+        SessionInfo {
+            session_type,
+            session: session.as_ref().map(to_braced),
+            service_provider,
+            service_provider_dll: options.service_provider_dll,
+            address: address_args,
+            has_session_resolver: resolver.is_some(),
+        }
+    };
+
     DPRun {
-        command,
+        cwd: options.cwd,
+        binary_path: options.binary_path,
+        wine: options.wine.unwrap_or_default(),
+        env: options.env,
+        env_clear: options.env_clear,
+        args,
+        applications: options.applications,
+        joining,
         host_server_port,
-        service_provider,
+        host_server_port_range,
+        host_server_bind_port,
+        host_server_bind,
+        backend,
+        resolver,
+        probe_timeout: options.probe_timeout,
+        start_timeout: options.start_timeout,
+        shutdown_grace: options.shutdown_grace,
+        capture_output: verbosity > DprunLogLevel::Normal,
+        #[cfg(unix)]
+        pty: options.pty,
+        #[cfg(feature = "serde")]
+        info,
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::server::ControlMessage;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn retry_only_application_failures() {
+        let join_error = |err: JoinError| io::Error::other(err);
+        assert!(retry_with_next_application(&join_error(
+            JoinError::ApplicationNotFound
+        )));
+        assert!(retry_with_next_application(&join_error(
+            JoinError::Unknown {
+                code: Some(1),
+                stderr: String::new()
+            }
+        )));
+        assert!(!retry_with_next_application(&join_error(
+            JoinError::PasswordIncorrect
+        )));
+        assert!(!retry_with_next_application(&join_error(
+            JoinError::SessionFull
+        )));
+        assert!(!retry_with_next_application(&io::Error::new(
+            io::ErrorKind::NotFound,
+            "dprun not found"
+        )));
+    }
+
+    #[test]
+    fn inet_data_types() {
+        for name in ["INet", "inet", "INET"].iter() {
+            assert!(is_inet(&DPGUIDOrNamed::Named(name.to_string())));
+        }
+        assert!(is_inet(&DPGUIDOrNamed::GUID(guids::ADDR_INET)));
+        assert!(!is_inet(&DPGUIDOrNamed::Named("INetPort".into())));
+        for name in ["INetPort", "inetport"].iter() {
+            assert!(is_inet_port(&DPGUIDOrNamed::Named(name.to_string())));
+        }
+        assert!(is_inet_port(&DPGUIDOrNamed::GUID(guids::ADDR_INET_PORT)));
+        assert!(!is_inet_port(&DPGUIDOrNamed::Named("Port".into())));
+    }
+
+    #[test]
+    fn display_address_values() {
+        assert_eq!(DPAddressValue::from(2197).to_string(), "i:2197");
+        assert_eq!(DPAddressValue::from("127.0.0.1").to_string(), "127.0.0.1");
+        let ip = IpAddr::from(std::net::Ipv6Addr::LOCALHOST);
+        assert_eq!(DPAddressValue::from(ip).to_string(), "::1");
+        assert_eq!(DPAddressValue::from(vec![0xab, 0x12]).to_string(), "b:ab12");
+    }
+
+    #[test]
+    fn group_address_parts() {
+        let options = DPRunOptions::builder()
+            .host(None)
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .named_address_part("INetPort", 2197)
+            .address_part(guids::ADDR_INET_PORT, 2198)
+            .named_address_part("INet", "127.0.0.1")
+            .named_address_part("SelfID", vec![1, 2, 3])
+            .allow_duplicate_address_parts()
+            .finish()
+            .unwrap();
+
+        let map = options.address_by_key();
+        assert_eq!(map.len(), 2);
+        assert_eq!(
+            map[&guids::ADDR_INET_PORT],
+            vec![
+                DPAddressValueView::Number(2197),
+                DPAddressValueView::Number(2198)
+            ]
+        );
+        assert_eq!(
+            map[&address_type_guid("INet").unwrap()],
+            vec![DPAddressValueView::String("127.0.0.1")]
+        );
+    }
+
+    #[test]
+    fn first_application_in_command() {
+        let first = GUID::from_u128(1);
+        let dprun = run(DPRunOptions::builder()
+            .join(GUID::nil())
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .applications(vec![first, GUID::from_u128(2)])
+            .finish()
+            .unwrap());
+        let command = dprun.command();
+        assert!(command.contains(&to_braced(&first)));
+        assert!(!command.contains(&to_braced(&GUID::from_u128(2))));
+        assert!(dprun.joining);
+    }
+
+    #[test]
+    fn dynamic_address_parts() {
+        let options = DPRunOptions::builder()
+            .host(None)
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .dynamic_address_part("INet", || "10.0.0.1".into())
+            .finish()
+            .unwrap();
+        assert!(options.address_by_key().is_empty());
+
+        let command = run(options).command();
+        assert!(command.contains("INet=10.0.0.1"));
+    }
+
+    #[test]
+    fn resolve_session_address() {
+        struct Registry;
+        #[async_trait::async_trait]
+        impl SessionResolver for Registry {
+            async fn resolve(&self, session: GUID) -> io::Result<SessionEndpoint> {
+                assert_eq!(session, GUID::from_u128(1));
+                Ok(SessionEndpoint {
+                    host: "10.0.0.1".into(),
+                    port: 2300,
+                })
+            }
+        }
+
+        let dprun = run(DPRunOptions::builder()
+            .join(GUID::from_u128(1))
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .named_address_part("INet", "127.0.0.1")
+            .session_resolver(Box::new(Registry))
+            .finish()
+            .unwrap());
+        assert!(!dprun.command().contains("INet=127.0.0.1"));
+
+        let (session, resolver) = dprun.resolver.unwrap();
+        let args =
+            async_std::task::block_on(resolve_address(resolver.as_ref(), session, None)).unwrap();
+        assert_eq!(
+            args,
+            vec!["--address", "INet=10.0.0.1", "--address", "INetPort=i:2300"]
+        );
+    }
+
+    #[test]
+    fn redact_password() {
+        let dprun = run(DPRunOptions::builder()
+            .join(GUID::from_u128(1))
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .named_address_part("INet", "127.0.0.1")
+            .session_password("hunter2".into())
+            .finish()
+            .unwrap());
+        assert!(!dprun.command().contains("hunter2"));
+        assert!(dprun.command_unredacted().contains("hunter2"));
+        #[cfg(feature = "serde")]
+        {
+            let json = dprun.diagnostics_json();
+            assert!(!json.contains("hunter2"));
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value["session_type"], "join");
+            assert_eq!(value["session"], to_braced(&GUID::from_u128(1)));
+            assert_eq!(value["service_provider"], "TCPIP");
+            assert_eq!(value["address"][0], "INet=127.0.0.1");
+            assert!(value["host_server"].is_null());
+        }
+    }
+
+    #[test]
+    fn explicit_binary_path() {
+        let dprun = run(DPRunOptions::builder()
+            .host(None)
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .cwd(PathBuf::from("/opt/games"))
+            .binary_path(PathBuf::from("/opt/dprun/dprun.exe"))
+            .finish()
+            .unwrap());
+        assert!(dprun.command().contains("/opt/dprun/dprun.exe"));
+        #[cfg(feature = "serde")]
+        {
+            let value: serde_json::Value = serde_json::from_str(&dprun.diagnostics_json()).unwrap();
+            assert_eq!(value["binary"], "/opt/dprun/dprun.exe");
+        }
+    }
+
+    #[test]
+    fn dry_run_command() {
+        let command = DPRunOptions::builder()
+            .host(None)
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .cwd(PathBuf::from("/opt/dprun"))
+            .env("WINEDEBUG", "-all")
+            .finish()
+            .unwrap()
+            .dry_run();
+        assert_eq!(command.cwd, Some(PathBuf::from("/opt/dprun")));
+        assert_eq!(
+            command.env,
+            vec![("WINEDEBUG".to_string(), "-all".to_string())]
+        );
+        assert!(!command.env_clear);
+        assert!(command.args.contains(&"--host".to_string()));
+    }
+
+    #[test]
+    fn structured_command() {
+        let dprun = run(DPRunOptions::builder()
+            .join(GUID::nil())
+            .player_name("Player One".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .session_password("hunter2".into())
+            .wine(WineConfig::new("wine64"))
+            .finish()
+            .unwrap());
+        let args = dprun.args();
+        if cfg!(target_os = "windows") {
+            assert_eq!(dprun.program(), "dprun.exe");
+        } else {
+            assert_eq!(dprun.program(), "wine64");
+            assert_eq!(args[0], "dprun.exe");
+        }
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["--player", "Player One"]));
+        assert!(args.contains(&"hunter2".to_string()));
+        assert_eq!(
+            args[args.len() - 2..],
+            ["--application", &to_braced(&GUID::nil())]
+        );
+    }
+
+    #[test]
+    fn child_environment() {
+        let dprun = run(DPRunOptions::builder()
+            .host(None)
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .env_clear()
+            .env("WINEDEBUG", "-all")
+            .env("LOBBY_TOKEN", "abc123")
+            .finish()
+            .unwrap());
+        let command = dprun.command();
+        assert!(command.contains("env -i"));
+        assert!(command.contains(r#"WINEDEBUG="-all""#));
+        #[cfg(feature = "serde")]
+        {
+            let value: serde_json::Value = serde_json::from_str(&dprun.diagnostics_json()).unwrap();
+            assert_eq!(value["env_clear"], true);
+            assert_eq!(value["env"]["WINEDEBUG"], "-all");
+            assert_eq!(value["env"]["LOBBY_TOKEN"], REDACTED);
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn custom_wine() {
+        let dprun = run(DPRunOptions::builder()
+            .host(None)
+            .player_name("Player".into())
+            .named_service_provider("TCPIP")
+            .application(GUID::nil())
+            .wine(WineConfig::new("/opt/wine-staging/bin/wine").arg("--debug"))
+            .finish()
+            .unwrap());
+        assert!(dprun
+            .command()
+            .contains(r#""/opt/wine-staging/bin/wine" "--debug" "dprun.exe""#));
+        #[cfg(feature = "serde")]
+        {
+            let value: serde_json::Value = serde_json::from_str(&dprun.diagnostics_json()).unwrap();
+            assert_eq!(value["launcher"], "/opt/wine-staging/bin/wine");
+            assert_eq!(value["launcher_args"][0], "--debug");
+        }
+    }
+
+    #[test]
+    fn builder_errors() {
+        assert_eq!(
+            DPRunOptions::builder().finish().err(),
+            Some(BuilderError::MissingSessionType)
+        );
+        assert_eq!(
+            DPRunOptions::builder()
+                .host(None)
+                .player_name("Player".into())
+                .named_service_provider("TCPIP")
+                .finish()
+                .err(),
+            Some(BuilderError::MissingApplication)
+        );
+        let err = DPRunOptions::builder()
+            .host(None)
+            .player_name("Player".into())
+            .named_service_provider("DPRUN")
+            .application(GUID::nil())
+            .finish()
+            .err()
+            .unwrap();
+        assert_eq!(err, BuilderError::MissingServiceProviderHandler);
+        assert!(err.to_string().contains("service provider handler"));
+        assert_eq!(
+            DPRunOptions::builder()
+                .host(None)
+                .player_name("Player".into())
+                .named_service_provider("TCPIP")
+                .application(GUID::nil())
+                .max_players(1)
+                .finish()
+                .err(),
+            Some(BuilderError::InvalidMaxPlayers(1))
+        );
+    }
+
+    #[test]
+    fn duplicate_address_parts() {
+        let builder = || {
+            DPRunOptions::builder()
+                .join(GUID::nil())
+                .player_name("Player".into())
+                .named_service_provider("TCPIP")
+                .application(GUID::nil())
+                .named_address_part("INet", "127.0.0.1")
+                .named_address_part("INetPort", 2300)
+        };
+        assert_eq!(
+            builder()
+                .address_part(guids::ADDR_INET_PORT, 2301)
+                .finish()
+                .err(),
+            Some(BuilderError::DuplicateAddressPart(to_braced(
+                &guids::ADDR_INET_PORT
+            )))
+        );
+        assert_eq!(
+            builder()
+                .dynamic_address_part("inet", || "10.0.0.1".into())
+                .finish()
+                .err(),
+            Some(BuilderError::DuplicateAddressPart("inet".into()))
+        );
+        assert!(builder()
+            .named_address_part("INet", "10.0.0.1")
+            .allow_duplicate_address_parts()
+            .finish()
+            .is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn start_timeout_kills_child() {
+        async_std::task::block_on(async {
+            let (sender, receiver) = async_std::channel::unbounded();
+            let child = Command::new("sleep").arg("10").spawn().unwrap();
+            let timeout = Some(Duration::from_millis(100));
+            let err = wait_for_child(child, false, startup(timeout, &Some(receiver.clone())))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            // A connection from a previous attempt does not count.
+            sender.try_send(()).unwrap();
+            let child = Command::new("sleep").arg("10").spawn().unwrap();
+            let err = wait_for_child(child, false, startup(timeout, &Some(receiver.clone())))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg("sleep 0.1")
+                .spawn()
+                .unwrap();
+            sender.try_send(()).unwrap();
+            let result = wait_for_child(child, false, Some((Duration::from_millis(50), &receiver)));
+            assert!(result.await.is_ok());
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_through_handle() {
+        async_std::task::block_on(async {
+            let child = Command::new("sleep").arg("10").spawn().unwrap();
+            let attempt = Attempt {
+                pid: child.id(),
+                application: GUID::nil(),
+            };
+            let handle = SessionHandle {
+                attempt: Arc::new(Mutex::new(attempt)),
+                stopped: Arc::new(AtomicBool::new(false)),
+                host_server_port: None,
+                pty: None,
+                session: Box::pin(wait_for_child(child, false, None)),
+            };
+            assert_eq!(handle.pid(), attempt.pid);
+            handle.kill().unwrap();
+            assert!(handle.stopped.load(Ordering::SeqCst));
+            assert!(handle.wait().await.is_err());
+        });
+    }
+
+    #[test]
+    fn host_server_shutdown_grace() {
+        async_std::task::block_on(async {
+            // The server never stops, and the receiver keeps the stop message from failing.
+            let (controller, _receiver) = ServerController::create();
+            let session = async { 42 };
+            let grace = Some(Duration::from_millis(50));
+            let result = with_host_server(futures::future::pending(), controller, session, grace);
+            assert_eq!(result.await, 42);
+
+            let (controller, receiver) = ServerController::create();
+            let server = async move {
+                if let Ok(ControlMessage::Stop(done)) = receiver.recv().await {
+                    let _ = done.try_send(Ok(()));
+                }
+            };
+            assert_eq!(
+                with_host_server(server, controller, async { 1 }, None).await,
+                1
+            );
+        });
+    }
+
+    #[test]
+    fn separate_bind_port() {
+        let builder = || {
+            DPRunOptions::builder()
+                .host(None)
+                .player_name("Player".into())
+                .service_provider_handler(Box::new(DefaultServiceProvider::new()))
+                .application(GUID::nil())
+        };
+
+        let dprun = run(builder()
+            .named_address_part("INetPort", 2300)
+            .finish()
+            .unwrap());
+        assert_eq!(dprun.host_server_ports(), (2300..=2300, false));
+
+        let dprun = run(builder()
+            .named_address_part("INetPort", 2300)
+            .host_server_bind_port(2197)
+            .finish()
+            .unwrap());
+        assert_eq!(dprun.host_server_ports(), (2197..=2197, false));
+
+        let dprun = run(builder().host_server_bind_port(2197).finish().unwrap());
+        assert_eq!(dprun.host_server_ports(), (2197..=2197, true));
+        assert_eq!(dprun.host_server_bind, None);
+
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let dprun = run(builder().host_server_bind(ip).finish().unwrap());
+        assert_eq!(dprun.host_server_bind, Some(ip));
+    }
 }
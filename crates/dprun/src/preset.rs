@@ -0,0 +1,62 @@
+use crate::{DPRunOptions, DPRunOptionsBuilder, ServiceProvider, GUID};
+
+/// Builders that are preconfigured with a service provider and the address parts it needs.
+///
+/// Each preset returns a [`DPRunOptionsBuilder`] with the service provider and its address parts
+/// filled in. The session type, player name, and application must still be set:
+///
+/// ```rust
+/// use dprun::{Preset, GUID};
+///
+/// let options = Preset::tcpip("127.0.0.1", 2300)
+///     .host(None)
+///     .player_name("Player".into())
+///     .application(GUID::nil())
+///     .finish()
+///     .unwrap();
+/// ```
+pub struct Preset;
+
+impl Preset {
+    /// Use the TCP/IP service provider.
+    ///
+    /// `host` is the address of the session host, and `port` is the port that DirectPlay uses to
+    /// find sessions. When hosting, `host` may be empty.
+    pub fn tcpip(host: &str, port: u16) -> DPRunOptionsBuilder {
+        DPRunOptions::builder()
+            .named_service_provider("TCPIP")
+            .named_address_part("INet", host)
+            .named_address_part("INetPort", i32::from(port))
+    }
+
+    /// Use the IPX service provider. IPX does not need any address parts.
+    pub fn ipx() -> DPRunOptionsBuilder {
+        DPRunOptions::builder().named_service_provider("IPX")
+    }
+
+    /// Use the modem service provider.
+    ///
+    /// `phone_number` is the number to dial when joining. When hosting, it may be empty to wait for
+    /// incoming calls.
+    pub fn modem(phone_number: &str) -> DPRunOptionsBuilder {
+        DPRunOptions::builder()
+            .named_service_provider("MODEM")
+            .named_address_part("Phone", phone_number)
+    }
+
+    /// Use the DPRun service provider with a service provider handler.
+    ///
+    /// `player_id` identifies the local player to the handler, and is passed to dprun as the
+    /// `SelfID` address part. `port` is the port for the host server.
+    pub fn dprun(
+        handler: Box<dyn ServiceProvider>,
+        player_id: GUID,
+        port: u16,
+    ) -> DPRunOptionsBuilder {
+        DPRunOptions::builder()
+            .service_provider_handler(handler)
+            .named_address_part("INet", "127.0.0.1")
+            .named_address_part("INetPort", i32::from(port))
+            .named_address_part("SelfID", player_id.as_bytes().to_vec())
+    }
+}
@@ -0,0 +1,116 @@
+use async_process::{unix::CommandExt, Command, Stdio};
+use async_std::fs::File;
+use async_std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
+
+/// The master side of a pseudo-terminal that dprun is attached to.
+///
+/// Reading from it yields everything dprun writes to its terminal, and writing to it is like
+/// typing into the terminal. On Linux, reads fail with an I/O error instead of returning EOF once
+/// dprun has exited.
+#[derive(Debug)]
+pub struct PtyMaster {
+    file: File,
+}
+
+impl Read for PtyMaster {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_read(cx, buf)
+    }
+}
+
+impl Write for PtyMaster {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_close(cx)
+    }
+}
+
+/// Open a new pseudo-terminal, returning its master and slave sides.
+pub(crate) fn open() -> io::Result<(PtyMaster, std::fs::File)> {
+    let mut master = 0;
+    let mut slave = 0;
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let master = unsafe { std::fs::File::from_raw_fd(master) };
+    let slave = unsafe { std::fs::File::from_raw_fd(slave) };
+    Ok((
+        PtyMaster {
+            file: File::from(master),
+        },
+        slave,
+    ))
+}
+
+/// Use the slave side of a pseudo-terminal for the standard streams of `command`.
+///
+/// The child process becomes the leader of a new session with the pseudo-terminal as its
+/// controlling terminal.
+pub(crate) fn attach(command: &mut Command, slave: &std::fs::File) -> io::Result<()> {
+    command
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave.try_clone()?));
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::prelude::*;
+    use async_std::task;
+
+    #[test]
+    fn read_from_pty() {
+        task::block_on(async {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("test -t 1 && echo terminal");
+            let (mut master, slave) = open().unwrap();
+            attach(&mut command, &slave).unwrap();
+            let mut child = command.spawn().unwrap();
+            drop(command);
+            drop(slave);
+            assert!(child.status().await.unwrap().success());
+
+            let mut output = vec![0; 64];
+            let len = master.read(&mut output).await.unwrap();
+            assert_eq!(&output[..len], b"terminal\r\n");
+        });
+    }
+}
@@ -0,0 +1,86 @@
+use crate::GUID;
+use async_std::io;
+use async_std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use async_trait::async_trait;
+use std::ffi::OsString;
+use std::time::Duration;
+
+/// The network address of a session host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionEndpoint {
+    /// Host name or IP address of the host, passed to dprun as the `INet` address part.
+    pub host: String,
+    /// Port of the host, passed to dprun as the `INetPort` address part.
+    pub port: u16,
+}
+
+/// Looks up the address of a session, for example in an external matchmaking service.
+///
+/// When a resolver is set with [`DPRunOptionsBuilder::session_resolver`], it is called right
+/// before dprun is started to join a session, and the endpoint it returns is used for the `INet`
+/// and `INetPort` address parts.
+///
+/// [`DPRunOptionsBuilder::session_resolver`]: crate::DPRunOptionsBuilder::session_resolver
+#[async_trait]
+pub trait SessionResolver: Sync + Send {
+    async fn resolve(&self, session: GUID) -> io::Result<SessionEndpoint>;
+}
+
+/// Check if a TCP connection can be made to `addr` within `timeout`.
+///
+/// This is useful to fail early when joining a TCP/IP session whose host is not reachable, instead
+/// of waiting for DirectPlay to time out.
+pub async fn probe_reachable(addr: SocketAddr, timeout: Duration) -> bool {
+    io::timeout(timeout, TcpStream::connect(addr)).await.is_ok()
+}
+
+/// Resolve a session and build the dprun arguments for its address.
+///
+/// If `probe_timeout` is set, this fails if the resolved endpoint is not reachable.
+pub(crate) async fn resolve_address(
+    resolver: &dyn SessionResolver,
+    session: GUID,
+    probe_timeout: Option<Duration>,
+) -> io::Result<Vec<OsString>> {
+    let SessionEndpoint { host, port } = resolver.resolve(session).await?;
+    if let Some(timeout) = probe_timeout {
+        let mut reachable = false;
+        for addr in (host.as_str(), port).to_socket_addrs().await? {
+            if probe_reachable(addr, timeout).await {
+                reachable = true;
+                break;
+            }
+        }
+        if !reachable {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("host unreachable: {}:{}", host, port),
+            ));
+        }
+    }
+    Ok(vec![
+        "--address".into(),
+        format!("INet={}", host).into(),
+        "--address".into(),
+        format!("INetPort=i:{}", port).into(),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task;
+
+    #[test]
+    fn probe_listening_port() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            assert!(probe_reachable(addr, Duration::from_secs(1)).await);
+
+            drop(listener);
+            assert!(!probe_reachable(addr, Duration::from_secs(1)).await);
+        });
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{inspect::print_network_message, structs::*};
+use crate::inspect::{delete_player_message, print_network_message};
+use crate::{structs::*, GUID};
 use async_std::channel::{self, Receiver, Sender};
 use async_std::io;
 use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
@@ -7,18 +8,43 @@ use async_trait::async_trait;
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
 use futures_codec::{Framed, LengthCodec};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
 use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::RangeInclusive;
+
+/// The maximum number of relays that a connection may pass through.
+pub const MAX_RELAY_HOPS: usize = 8;
+
+/// Method name of the frame that a relay sends when it connects to an upstream host server.
+const HOPS_METHOD: &[u8] = b"hops";
 
 #[derive(Debug)]
 pub enum ControlMessage {
-    /// Stop the server.
-    Stop,
+    /// Stop the server, and report the result once the listener is closed.
+    Stop(Sender<io::Result<()>>),
 }
 
 #[derive(Debug)]
 pub enum AppMessage {
     /// Send a message to the DirectPlay application.
     Send(u32, u32, Vec<u8>),
+    /// Close the connection to the DirectPlay application.
+    Close,
+}
+
+/// What the host server should do with a message, as decided by
+/// [`ServiceProvider::on_message`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageAction {
+    /// Pass the message on to [`ServiceProvider::send`], including any changes that were made to
+    /// it in place.
+    Forward,
+    /// Discard the message.
+    Drop,
+    /// Pass on this data instead of the original message.
+    Replace(Vec<u8>),
 }
 
 /// Trait for custom Service Provider implementations.
@@ -44,12 +70,54 @@ pub trait ServiceProvider: Sync + Send {
         data: ReplyData,
     ) -> io::Result<()>;
     async fn send(&mut self, controller: AppController, id: u32, data: SendData) -> io::Result<()>;
+
+    /// Inspect, change, or drop a message before it is passed to [`send`](Self::send).
+    ///
+    /// `from` is the GUID of the sending player, and `to` is the GUID of the receiving player, or
+    /// `None` for messages to all players. The default implementation forwards every message
+    /// unchanged. Messages that are relayed to an upstream host server are not passed through
+    /// this hook.
+    ///
+    /// ```rust
+    /// use async_std::io;
+    /// use async_trait::async_trait;
+    /// use dprun::structs::*;
+    /// use dprun::{AppController, MessageAction, ServiceProvider, GUID};
+    ///
+    /// struct SizeLogger;
+    ///
+    /// #[async_trait]
+    /// impl ServiceProvider for SizeLogger {
+    ///     fn on_message(
+    ///         &mut self,
+    ///         from: GUID,
+    ///         to: Option<GUID>,
+    ///         data: &mut Vec<u8>,
+    ///     ) -> MessageAction {
+    ///         println!("{} -> {:?}: {} bytes", from, to, data.len());
+    ///         MessageAction::Forward
+    ///     }
+    ///
+    ///     // ...
+    /// #   async fn enum_sessions(&mut self, _: AppController, _: u32, _: EnumSessionsData) -> io::Result<()> { Ok(()) }
+    /// #   async fn open(&mut self, _: AppController, _: u32, _: OpenData) -> io::Result<()> { Ok(()) }
+    /// #   async fn create_player(&mut self, _: AppController, _: u32, _: CreatePlayerData) -> io::Result<()> { Ok(()) }
+    /// #   async fn reply(&mut self, _: AppController, _: u32, _: ReplyData) -> io::Result<()> { Ok(()) }
+    /// #   async fn send(&mut self, _: AppController, _: u32, _: SendData) -> io::Result<()> { Ok(()) }
+    /// }
+    /// ```
+    fn on_message(&mut self, from: GUID, to: Option<GUID>, data: &mut Vec<u8>) -> MessageAction {
+        let _ = (from, to, data);
+        MessageAction::Forward
+    }
 }
 
 /// Struct containing methods to control the service provider host server.
 #[derive(Clone)]
 pub struct ServerController {
     sender: Sender<ControlMessage>,
+    /// The error that stopped the server, if it stopped by itself.
+    error: Arc<std::sync::Mutex<Option<io::Error>>>,
 }
 
 impl ServerController {
@@ -60,24 +128,69 @@ impl ServerController {
         // TODO figure out appropriate buffer size
         // May only need to be oneshot
         let (sender, receiver) = channel::bounded(5);
-        let controller = ServerController { sender };
+        let controller = ServerController {
+            sender,
+            error: Default::default(),
+        };
 
         (controller, receiver)
     }
 
     /// Stop the host server.
     ///
-    /// Returns a Future, so make sure to consume it.
-    pub async fn stop(&mut self) {
-        self.sender.send(ControlMessage::Stop).await;
+    /// The returned future completes once the server has stopped listening. If the server had
+    /// already stopped because accepting a connection failed, this returns that error.
+    pub async fn stop(&mut self) -> io::Result<()> {
+        let (done, finished) = channel::bounded(1);
+        if self.sender.send(ControlMessage::Stop(done)).await.is_ok() {
+            if let Ok(result) = finished.recv().await {
+                return result;
+            }
+        }
+        // The server is no longer running.
+        match self.error.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 }
 
+/// Flag in [`CreatePlayerData::flags`] for the player that hosts the session.
+const DPLAYI_PLAYER_NAMESRVR: i32 = 2;
+
+/// A player that was created in the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerInfo {
+    /// The DirectPlay ID of the player.
+    pub id: DPID,
+    /// The GUID of the player.
+    pub guid: GUID,
+    /// Whether this player hosts the session.
+    pub is_host: bool,
+}
+
+/// The connections to a host server, and the players that were created through them.
+#[derive(Default)]
+struct SessionState {
+    next_connection: u64,
+    connections: HashMap<u64, Sender<AppMessage>>,
+    /// Players in the order they were created, with the connection they were created through.
+    players: Vec<(u64, PlayerInfo)>,
+}
+
+/// Session state that is shared between the connections to a host server.
+type Session = Arc<std::sync::Mutex<SessionState>>;
+
 /// Controller for sending messages to the game.
 #[derive(Clone)]
 pub struct AppController {
     sender: Sender<AppMessage>,
     next_message_id: u32,
+    /// The address that the host server is listening on.
+    local_addr: Option<SocketAddr>,
+    session: Session,
+    /// The ID of this connection in the session.
+    connection: u64,
 }
 
 impl AppController {
@@ -87,6 +200,9 @@ impl AppController {
         let controller = AppController {
             sender,
             next_message_id: 0,
+            local_addr: None,
+            session: Default::default(),
+            connection: 0,
         };
 
         (controller, receiver)
@@ -108,6 +224,76 @@ impl AppController {
             .send(AppMessage::Send(msg_id as u32, id, data))
             .await;
     }
+
+    /// Get the players that were created in the session so far, in the order they were created.
+    ///
+    /// DirectPlay does not pass player names to the service provider, so only the IDs are
+    /// available here.
+    pub fn players(&self) -> Vec<PlayerInfo> {
+        let session = self.session.lock().unwrap();
+        session
+            .players
+            .iter()
+            .map(|(_, player)| player.clone())
+            .collect()
+    }
+
+    /// Remove a player from the session.
+    ///
+    /// The other applications in the session are told that the player was deleted, and the
+    /// connection to the application that created the player is closed. Fails with
+    /// `ErrorKind::NotFound` if there is no player with this ID.
+    pub async fn kick(&mut self, player: DPID) -> io::Result<()> {
+        let (owner, others) = {
+            let mut session = self.session.lock().unwrap();
+            let index = session
+                .players
+                .iter()
+                .position(|(_, info)| info.id == player)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no player with ID {}", player),
+                    )
+                })?;
+            let (owner, _) = session.players.remove(index);
+            let others: Vec<_> = session
+                .connections
+                .iter()
+                .filter(|(connection, _)| **connection != owner)
+                .map(|(_, sender)| sender.clone())
+                .collect();
+            (session.connections.get(&owner).cloned(), others)
+        };
+
+        log::debug!("[AppController::kick] {}", player);
+        let message = delete_player_message(player);
+        for sender in others {
+            let msg_id = self.next_message_id;
+            self.next_message_id += 1;
+            // Connections that are already closed do not need to know.
+            let _ = sender
+                .send(AppMessage::Send(msg_id, u32::MAX, message.clone()))
+                .await;
+        }
+        if let Some(owner) = owner {
+            let _ = owner.send(AppMessage::Close).await;
+        }
+        Ok(())
+    }
+
+    /// Get the address that the host server is listening on.
+    ///
+    /// This is useful when the port was picked automatically, to advertise it to other players.
+    /// Fails if the controller is not attached to a host server connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "controller is not attached to a host server",
+            )
+        })
+    }
 }
 
 async fn handle_message(
@@ -139,6 +325,21 @@ async fn handle_message(
         }
         b"crpl" => {
             let create_player = CreatePlayerData::parse(message);
+            {
+                let mut session = controller.session.lock().unwrap();
+                if !session
+                    .players
+                    .iter()
+                    .any(|(_, player)| player.guid == create_player.player_guid)
+                {
+                    let player = PlayerInfo {
+                        id: create_player.player_id,
+                        guid: create_player.player_guid,
+                        is_host: create_player.flags & DPLAYI_PLAYER_NAMESRVR != 0,
+                    };
+                    session.players.push((controller.connection, player));
+                }
+            }
             service_provider
                 .lock()
                 .await
@@ -155,13 +356,18 @@ async fn handle_message(
                 .await
         }
         b"send" => {
-            let send = SendData::parse(message);
+            let mut send = SendData::parse(message);
             print_network_message(message);
-            service_provider
-                .lock()
-                .await
-                .send(controller.clone(), id, send)
-                .await
+            let mut service_provider = service_provider.lock().await;
+            match service_provider.on_message(send.sender_id, send.receiver_id, &mut send.message) {
+                MessageAction::Forward => (),
+                MessageAction::Drop => {
+                    log::debug!("[HostServer::process_message] Dropping message {}", id);
+                    return Ok(());
+                }
+                MessageAction::Replace(data) => send.message = data,
+            }
+            service_provider.send(controller.clone(), id, send).await
         }
         method => {
             log::debug!(
@@ -177,11 +383,25 @@ async fn handle_message(
 
 fn handle_connection(
     service_provider: Arc<Mutex<Box<dyn ServiceProvider>>>,
+    session: Session,
     sock: TcpStream,
 ) -> io::Result<()> {
     sock.set_nodelay(true)?;
+    let local_addr = sock.local_addr()?;
+    let closer = sock.clone();
     let (mut writer, mut reader) = Framed::new(sock, LengthCodec).split();
     let (mut app_controller, mut app_receiver) = AppController::create();
+    app_controller.local_addr = Some(local_addr);
+    app_controller.connection = {
+        let mut state = session.lock().unwrap();
+        let connection = state.next_connection;
+        state.next_connection += 1;
+        state
+            .connections
+            .insert(connection, app_controller.sender.clone());
+        connection
+    };
+    app_controller.session = session;
     log::debug!("[handle_connection] Connection incoming");
 
     let read_future = async move {
@@ -222,6 +442,10 @@ fn handle_connection(
             .unwrap();
         }
         log::debug!("[handle_connection] Connection finished");
+        let mut session = app_controller.session.lock().unwrap();
+        let connection = app_controller.connection;
+        session.connections.remove(&connection);
+        session.players.retain(|(owner, _)| *owner != connection);
     };
 
     let write_future = async move {
@@ -240,6 +464,11 @@ fn handle_connection(
                     (&mut message[12..]).copy_from_slice(&data);
                     writer.send(message.into()).await.unwrap();
                 }
+                AppMessage::Close => {
+                    log::debug!("[handle_connection] Closing connection");
+                    let _ = closer.shutdown(std::net::Shutdown::Both);
+                    break;
+                }
             }
         }
     };
@@ -249,42 +478,209 @@ fn handle_connection(
     Ok(())
 }
 
+/// Where the host server sends the messages it receives from dprun.
+pub enum Backend {
+    /// Handle messages with a service provider implementation.
+    Local(Box<dyn ServiceProvider>),
+    /// Forward messages to another host server.
+    Upstream(SocketAddr),
+}
+
+/// Build a frame that lists the relays a connection has passed through.
+fn hops_frame(relays: &[u64]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(12 + relays.len() * 8);
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&u32::MAX.to_be_bytes());
+    message.extend_from_slice(HOPS_METHOD);
+    for relay in relays {
+        message.extend_from_slice(&relay.to_be_bytes());
+    }
+    message
+}
+
+/// Parse a frame that lists the relays a connection has passed through. Returns `None` if the
+/// frame is some other message.
+fn parse_hops_frame(message: &[u8]) -> Option<Vec<u64>> {
+    if message.len() < 12 || &message[8..12] != HOPS_METHOD {
+        return None;
+    }
+    let chunks = message[12..].chunks_exact(8);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let relays = chunks
+        .map(|chunk| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(chunk);
+            u64::from_be_bytes(bytes)
+        })
+        .collect();
+    Some(relays)
+}
+
+/// Check that a connection that passed through `relays` may be forwarded by the relay `relay_id`.
+fn check_hops(relay_id: u64, relays: &[u64]) -> io::Result<()> {
+    if relays.contains(&relay_id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "relay loop detected",
+        ));
+    }
+    if relays.len() >= MAX_RELAY_HOPS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "connection passed through more than {} relays",
+                MAX_RELAY_HOPS
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Forward a connection to an upstream host server.
+///
+/// Frames are forwarded unchanged in both directions. Before forwarding anything, the relay sends
+/// a `hops` frame to the upstream server listing the relays that the connection has passed
+/// through, including this one. If the connection came from another relay, its `hops` frame is
+/// consumed and used to detect loops.
+async fn relay_connection(relay_id: u64, upstream: SocketAddr, sock: TcpStream) -> io::Result<()> {
+    sock.set_nodelay(true)?;
+    let (mut local_writer, mut local_reader) = Framed::new(sock, LengthCodec).split();
+
+    let first = match local_reader.next().await {
+        Some(message) => message?,
+        None => return Ok(()),
+    };
+    let (mut relays, first) = match parse_hops_frame(&first) {
+        Some(relays) => (relays, None),
+        None => (vec![], Some(first)),
+    };
+    check_hops(relay_id, &relays)?;
+    relays.push(relay_id);
+
+    let upstream = TcpStream::connect(upstream).await?;
+    upstream.set_nodelay(true)?;
+    let (mut upstream_writer, mut upstream_reader) = Framed::new(upstream, LengthCodec).split();
+    upstream_writer.send(hops_frame(&relays).into()).await?;
+    if let Some(first) = first {
+        upstream_writer.send(first).await?;
+    }
+
+    let forward_up = async move {
+        while let Some(message) = local_reader.next().await {
+            upstream_writer.send(message?).await?;
+        }
+        upstream_writer.close().await
+    };
+    let forward_down = async move {
+        while let Some(message) = upstream_reader.next().await {
+            local_writer.send(message?).await?;
+        }
+        local_writer.close().await
+    };
+    let (up, down) = futures::join!(forward_up, forward_down);
+    up.and(down)
+}
+
+/// Handles connections for a running host server.
+enum Handler {
+    Local(Arc<Mutex<Box<dyn ServiceProvider>>>),
+    Upstream { relay_id: u64, upstream: SocketAddr },
+}
+
 #[derive(Debug)]
 enum EventType {
     Socket(TcpStream),
     Control(ControlMessage),
 }
 
+/// The address that the host server listens on by default.
+pub(crate) const DEFAULT_HOST_SERVER_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
 pub struct HostServer {
-    address: SocketAddr,
+    ip: IpAddr,
+    ports: RangeInclusive<u16>,
     controller: ServerController,
     receiver: Receiver<ControlMessage>,
-    service_provider: Box<dyn ServiceProvider>,
+    backend: Backend,
+    /// Notified for every incoming connection.
+    connected: Option<Sender<()>>,
 }
 
 impl HostServer {
-    pub fn new(port: u16, service_provider: Box<dyn ServiceProvider>) -> Self {
-        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    /// Create a host server that listens on the first free port in the given range.
+    pub fn new(ports: RangeInclusive<u16>, backend: Backend) -> Self {
         let (controller, receiver) = ServerController::create();
 
         HostServer {
-            address,
+            ip: DEFAULT_HOST_SERVER_IP,
+            ports,
             controller,
             receiver,
-            service_provider,
+            backend,
+            connected: None,
         }
     }
 
-    pub async fn start(self) -> io::Result<(impl Future<Output = ()>, ServerController)> {
+    /// Listen on the interface with the given address instead of the default `127.0.0.1`.
+    ///
+    /// IPv6 addresses are supported. Binding to `::` also accepts IPv4 connections on systems
+    /// where IPv6 sockets are dual-stack by default.
+    pub fn bind_ip(self, ip: IpAddr) -> Self {
+        Self { ip, ..self }
+    }
+
+    /// Get a channel that receives a message for every connection that the server accepts.
+    pub fn connections(&mut self) -> Receiver<()> {
+        let (sender, receiver) = channel::unbounded();
+        self.connected = Some(sender);
+        receiver
+    }
+
+    /// Bind to the first port that is available.
+    async fn bind(&self) -> io::Result<(TcpListener, u16)> {
+        let mut last_error = None;
+        for port in self.ports.clone() {
+            let address = SocketAddr::new(self.ip, port);
+            match TcpListener::bind(&address).await {
+                Ok(listener) => {
+                    let port = listener.local_addr()?.port();
+                    return Ok((listener, port));
+                }
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                    log::debug!("[HostServer::bind] Port {} is in use", port);
+                    last_error = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no ports to bind to")))
+    }
+
+    /// Start the host server. Returns the server future, a controller, and the port the server
+    /// is listening on.
+    pub async fn start(self) -> io::Result<(impl Future<Output = ()>, ServerController, u16)> {
+        let (client, port) = self.bind().await?;
         log::debug!(
             "[HostServer::start] Starting HostServer on {:?}",
-            self.address
+            SocketAddr::new(self.ip, port)
         );
-        let client = TcpListener::bind(&self.address).await?;
 
-        let service_provider = Arc::new(Mutex::new(self.service_provider));
-        let _server_controller = self.controller.clone();
+        let handler = match self.backend {
+            Backend::Local(service_provider) => {
+                Handler::Local(Arc::new(Mutex::new(service_provider)))
+            }
+            Backend::Upstream(upstream) => Handler::Upstream {
+                relay_id: RandomState::new().build_hasher().finish(),
+                upstream,
+            },
+        };
+        let error = Arc::clone(&self.controller.error);
+        let session = Session::default();
         let receiver = self.receiver;
+        let connected = self.connected;
         let server = async move {
             let control_messages = receiver.map(EventType::Control).map(io::Result::Ok);
             let socket_messages = client
@@ -292,21 +688,316 @@ impl HostServer {
                 .map(|result| result.map(EventType::Socket));
 
             let mut stream = futures::stream::select(socket_messages, control_messages);
+            let mut stopped = None;
             while let Some(message) = stream.next().await {
                 log::debug!("[HostServer::start] Receiving message: {:?}", message);
                 let message = match message {
-                    Err(_) => break,
-                    Ok(EventType::Control(ControlMessage::Stop)) => break,
+                    Err(err) => {
+                        log::warn!("[HostServer::start] Accept error: {:?}", err);
+                        *error.lock().unwrap() = Some(err);
+                        break;
+                    }
+                    Ok(EventType::Control(ControlMessage::Stop(done))) => {
+                        stopped = Some(done);
+                        break;
+                    }
                     Ok(message) => message,
                 };
 
                 if let EventType::Socket(socket) = message {
+                    if let Some(connected) = &connected {
+                        let _ = connected.try_send(());
+                    }
                     log::debug!("[HostServer::start] Spawning socket handler...");
-                    handle_connection(Arc::clone(&service_provider), socket).unwrap();
+                    match &handler {
+                        Handler::Local(service_provider) => handle_connection(
+                            Arc::clone(service_provider),
+                            Arc::clone(&session),
+                            socket,
+                        )
+                        .unwrap(),
+                        Handler::Upstream { relay_id, upstream } => {
+                            let (relay_id, upstream) = (*relay_id, *upstream);
+                            async_std::task::spawn(async move {
+                                if let Err(err) = relay_connection(relay_id, upstream, socket).await
+                                {
+                                    log::warn!("[HostServer::start] Relay error: {:?}", err);
+                                }
+                            });
+                        }
+                    }
+                }
+            }
+            // Close the listener before reporting that the server has stopped.
+            drop(stream);
+            drop(client);
+            log::debug!("[HostServer::start] Shut down on port {}", port);
+            if let Some(done) = stopped {
+                let _ = done.try_send(Ok(()));
+            }
+        };
+
+        Ok((server, self.controller, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::Ipv6Addr;
+    use async_std::task;
+    use std::sync::mpsc;
+
+    /// Records the messages passed to `send`, and the controllers passed to `create_player`.
+    struct Recorder {
+        sent: mpsc::Sender<Vec<u8>>,
+        created: Sender<AppController>,
+    }
+
+    #[async_trait]
+    impl ServiceProvider for Recorder {
+        async fn enum_sessions(
+            &mut self,
+            _: AppController,
+            _: u32,
+            _: EnumSessionsData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn open(&mut self, _: AppController, _: u32, _: OpenData) -> io::Result<()> {
+            Ok(())
+        }
+        async fn create_player(
+            &mut self,
+            controller: AppController,
+            _: u32,
+            _: CreatePlayerData,
+        ) -> io::Result<()> {
+            self.created.try_send(controller).unwrap();
+            Ok(())
+        }
+        async fn reply(&mut self, _: AppController, _: u32, _: ReplyData) -> io::Result<()> {
+            Ok(())
+        }
+        async fn send(&mut self, _: AppController, _: u32, data: SendData) -> io::Result<()> {
+            self.sent.send(data.message).unwrap();
+            Ok(())
+        }
+        fn on_message(&mut self, _: GUID, _: Option<GUID>, data: &mut Vec<u8>) -> MessageAction {
+            match data.as_slice() {
+                b"drop" => MessageAction::Drop,
+                b"replace" => MessageAction::Replace(b"replaced".to_vec()),
+                _ => {
+                    data.push(b'!');
+                    MessageAction::Forward
                 }
             }
+        }
+    }
+
+    async fn connect(port: u16) -> Framed<TcpStream, LengthCodec> {
+        let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        Framed::new(client, LengthCodec)
+    }
+
+    /// Build a `crpl` frame for a player whose GUID is derived from its ID.
+    fn create_player(id: DPID, flags: i32) -> Vec<u8> {
+        let mut message = b"\0\0\0\x01\0\0\0\0crpl".to_vec();
+        message.extend_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(GUID::from_u128(id as u128).as_bytes());
+        message.extend_from_slice(&flags.to_le_bytes());
+        message
+    }
+
+    #[test]
+    fn detect_relay_loops() {
+        let frame = hops_frame(&[1, 2]);
+        assert_eq!(parse_hops_frame(&frame), Some(vec![1, 2]));
+        assert_eq!(parse_hops_frame(&frame[..16]), None);
+        assert!(check_hops(3, &[1, 2]).is_ok());
+        assert!(check_hops(2, &[1, 2]).is_err());
+        assert!(check_hops(100, &[0; MAX_RELAY_HOPS]).is_err());
+    }
+
+    #[test]
+    fn message_actions() {
+        let send_message = |data: &[u8]| {
+            let mut message = vec![0; 4 + 16 + 16 + 4];
+            message.extend_from_slice(&(data.len() as i32).to_le_bytes());
+            message.extend_from_slice(data);
+            message
         };
 
-        Ok((server, self.controller))
+        task::block_on(async {
+            let (sent, receiver) = mpsc::channel();
+            let (created, _) = channel::unbounded();
+            let service_provider: Box<dyn ServiceProvider> = Box::new(Recorder { sent, created });
+            let service_provider = Arc::new(Mutex::new(service_provider));
+            let (mut controller, _) = AppController::create();
+            for data in &[&b"hello"[..], b"drop", b"replace"] {
+                let message = send_message(data);
+                handle_message(
+                    Arc::clone(&service_provider),
+                    &mut controller,
+                    1,
+                    b"send",
+                    &message,
+                )
+                .await
+                .unwrap();
+            }
+            let sent: Vec<_> = receiver.try_iter().collect();
+            assert_eq!(sent, vec![b"hello!".to_vec(), b"replaced".to_vec()]);
+        });
+    }
+
+    #[test]
+    fn controller_state() {
+        let (controller, _) = AppController::create();
+        assert_eq!(
+            controller.local_addr().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert!(controller.players().is_empty());
+
+        task::block_on(async {
+            let (sent, _) = mpsc::channel();
+            let (created, receiver) = channel::unbounded();
+            let backend = Backend::Local(Box::new(Recorder { sent, created }));
+            let (server, mut server_controller, port) =
+                HostServer::new(0..=0, backend).start().await.unwrap();
+            let server = task::spawn(server);
+
+            let mut host = connect(port).await;
+            let mut guest = connect(port).await;
+            host.send(create_player(1, DPLAYI_PLAYER_NAMESRVR).into())
+                .await
+                .unwrap();
+            receiver.recv().await.unwrap();
+            guest.send(create_player(2, 0).into()).await.unwrap();
+            let mut controller = receiver.recv().await.unwrap();
+
+            assert_eq!(
+                controller.local_addr().unwrap(),
+                SocketAddr::from(([127, 0, 0, 1], port))
+            );
+            let player = |id: DPID, is_host| PlayerInfo {
+                id,
+                guid: GUID::from_u128(id as u128),
+                is_host,
+            };
+            assert_eq!(
+                controller.players(),
+                vec![player(1, true), player(2, false)]
+            );
+
+            controller.kick(2).await.unwrap();
+            let notice = host.next().await.unwrap().unwrap();
+            assert_eq!(&notice[12..], &delete_player_message(2)[..]);
+            assert!(guest.next().await.is_none());
+            assert_eq!(controller.players(), vec![player(1, true)]);
+            let err = controller.kick(2).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+            server_controller.stop().await.unwrap();
+            server.await;
+        });
+    }
+
+    #[test]
+    fn stop_closes_listener() {
+        task::block_on(async {
+            let upstream = "127.0.0.1:2197".parse().unwrap();
+            let server = HostServer::new(0..=0, Backend::Upstream(upstream));
+            let (server, mut controller, port) = server.start().await.unwrap();
+            let server = task::spawn(server);
+
+            controller.stop().await.unwrap();
+            TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+            // Stopping a stopped server is fine.
+            controller.stop().await.unwrap();
+            server.await;
+        });
+    }
+
+    #[test]
+    fn bind_any_port() {
+        task::block_on(async {
+            let upstream = "127.0.0.1:2197".parse().unwrap();
+            let first = HostServer::new(0..=0, Backend::Upstream(upstream));
+            let second = HostServer::new(0..=0, Backend::Upstream(upstream));
+            let (_listener, first_port) = first.bind().await.unwrap();
+            let (_listener, second_port) = second.bind().await.unwrap();
+            assert_ne!(first_port, 0);
+            assert_ne!(first_port, second_port);
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_to_interface() {
+        task::block_on(async {
+            let upstream = "127.0.0.1:2197".parse().unwrap();
+            let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+            let server = HostServer::new(0..=0, Backend::Upstream(upstream)).bind_ip(ip);
+            let (listener, port) = server.bind().await.unwrap();
+            assert_eq!(listener.local_addr().unwrap(), SocketAddr::new(ip, port));
+        });
+    }
+
+    #[test]
+    fn bind_ipv6() {
+        task::block_on(async {
+            let upstream = TcpListener::bind("[::1]:0").await.unwrap();
+            let upstream_addr = upstream.local_addr().unwrap();
+            let ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+            let server = HostServer::new(0..=0, Backend::Upstream(upstream_addr)).bind_ip(ip);
+            let (server, mut controller, port) = server.start().await.unwrap();
+            let server = task::spawn(server);
+
+            let client = TcpStream::connect((ip, port)).await.unwrap();
+            let mut client = Framed::new(client, LengthCodec);
+            let message = b"\0\0\0\x01\0\0\0\0sendpayload".to_vec();
+            client.send(message.clone().into()).await.unwrap();
+
+            let (upstream_sock, _) = upstream.accept().await.unwrap();
+            let mut upstream_sock = Framed::new(upstream_sock, LengthCodec);
+            upstream_sock.next().await.unwrap().unwrap();
+            let forwarded = upstream_sock.next().await.unwrap().unwrap();
+            assert_eq!(&forwarded[..], &message[..]);
+
+            controller.stop().await.unwrap();
+            server.await;
+        });
+    }
+
+    #[test]
+    fn forward_to_upstream() {
+        task::block_on(async {
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream_addr = upstream.local_addr().unwrap();
+            let server = HostServer::new(0..=0, Backend::Upstream(upstream_addr));
+            let (server, mut controller, port) = server.start().await.unwrap();
+            let server = task::spawn(server);
+
+            let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let mut client = Framed::new(client, LengthCodec);
+            let message = b"\0\0\0\x01\0\0\0\0sendpayload".to_vec();
+            client.send(message.clone().into()).await.unwrap();
+
+            let (upstream_sock, _) = upstream.accept().await.unwrap();
+            let mut upstream_sock = Framed::new(upstream_sock, LengthCodec);
+            let hops = upstream_sock.next().await.unwrap().unwrap();
+            assert_eq!(parse_hops_frame(&hops).map(|relays| relays.len()), Some(1));
+            let forwarded = upstream_sock.next().await.unwrap().unwrap();
+            assert_eq!(&forwarded[..], &message[..]);
+
+            upstream_sock.send(b"reply".to_vec().into()).await.unwrap();
+            let reply = client.next().await.unwrap().unwrap();
+            assert_eq!(&reply[..], b"reply");
+
+            controller.stop().await.unwrap();
+            server.await;
+        });
     }
 }
@@ -0,0 +1,122 @@
+use async_std::io;
+use std::time::Instant;
+
+/// Resource usage of a process at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProcessStats {
+    /// CPU usage since the previous sample, as a percentage of a single core. This can exceed 100
+    /// if the process uses multiple cores.
+    pub cpu_percent: f32,
+    /// Resident memory size in bytes.
+    pub rss_bytes: u64,
+}
+
+/// Takes resource usage samples for a single process.
+pub(crate) struct Sampler {
+    pid: u32,
+    /// CPU time and the time at which it was measured, for the previous sample.
+    previous: Option<(u64, Instant)>,
+}
+
+impl Sampler {
+    pub(crate) fn new(pid: u32) -> Self {
+        let previous = cpu_ticks(pid).ok().map(|ticks| (ticks, Instant::now()));
+        Self { pid, previous }
+    }
+
+    /// Take a new sample.
+    pub(crate) fn sample(&mut self) -> io::Result<ProcessStats> {
+        let ticks = cpu_ticks(self.pid)?;
+        let now = Instant::now();
+        let cpu_percent = match self.previous {
+            Some((previous_ticks, previous_time)) => {
+                let elapsed = now.duration_since(previous_time).as_secs_f32();
+                if elapsed > 0.0 {
+                    let used = ticks.saturating_sub(previous_ticks) as f32 / CLOCK_TICKS_PER_SEC;
+                    used / elapsed * 100.0
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        self.previous = Some((ticks, now));
+
+        Ok(ProcessStats {
+            cpu_percent,
+            rss_bytes: rss_bytes(self.pid)?,
+        })
+    }
+}
+
+/// The unit of the CPU times in /proc/[pid]/stat. This is 100 on all common Linux
+/// configurations.
+#[cfg(target_os = "linux")]
+const CLOCK_TICKS_PER_SEC: f32 = 100.0;
+#[cfg(not(target_os = "linux"))]
+const CLOCK_TICKS_PER_SEC: f32 = 1.0;
+
+/// Read the total CPU time used by a process, in clock ticks.
+#[cfg(target_os = "linux")]
+fn cpu_ticks(pid: u32) -> io::Result<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The process name is in parentheses and may contain spaces, so start after it.
+    let fields = stat
+        .rfind(')')
+        .map(|index| &stat[index + 1..])
+        .unwrap_or("")
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+    // utime and stime are fields 14 and 15, counting from the pid as field 1.
+    let parse = |index: usize| -> io::Result<u64> {
+        fields
+            .get(index)
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc/pid/stat"))
+    };
+    Ok(parse(11)? + parse(12)?)
+}
+
+/// Read the resident memory size of a process, in bytes.
+#[cfg(target_os = "linux")]
+fn rss_bytes(pid: u32) -> io::Result<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+    status
+        .lines()
+        .find(|line| line.starts_with("VmRSS:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kilobytes| kilobytes.parse::<u64>().ok())
+        .map(|kilobytes| kilobytes * 1024)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc/pid/status"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_ticks(_pid: u32) -> io::Result<u64> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rss_bytes(_pid: u32) -> io::Result<u64> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "process resource usage is only supported on Linux",
+    )
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_self() {
+        let mut sampler = Sampler::new(std::process::id());
+        let stats = sampler.sample().unwrap();
+        assert!(stats.rss_bytes > 0);
+        assert!(stats.cpu_percent >= 0.0);
+    }
+}
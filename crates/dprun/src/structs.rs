@@ -13,7 +13,7 @@ fn read_guid(mut read: impl Read) -> io::Result<Uuid> {
 #[derive(Debug)]
 #[repr(C)]
 pub struct CreatePlayerData {
-    // pub player_id: DPID,
+    pub player_id: DPID,
     pub player_guid: Uuid,
     pub flags: i32,
 }
@@ -22,13 +22,13 @@ impl CreatePlayerData {
     pub fn parse(bytes: &[u8]) -> Self {
         let mut cursor = Cursor::new(bytes);
 
-        let _dpid = cursor.read_u32::<LE>().unwrap();
+        let dpid = cursor.read_i32::<LE>().unwrap();
         let guid = read_guid(&mut cursor).unwrap();
 
         let flags = cursor.read_i32::<LE>().unwrap();
 
         Self {
-            // player_id: dpid,
+            player_id: dpid,
             player_guid: guid,
             flags,
         }
@@ -0,0 +1,54 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// How to start Wine, on platforms other than Windows.
+///
+/// By default, dprun is started with the `wine` program from `PATH`. Use this to run a specific
+/// Wine build, or to add arguments before the path to dprun:
+///
+/// ```rust
+/// use dprun::WineConfig;
+///
+/// let staging = WineConfig::new("/opt/wine-staging/bin/wine");
+/// // Run `env WINEPREFIX=/srv/aoc wine dprun.exe ...`
+/// let prefixed = WineConfig::new("env")
+///     .arg("WINEPREFIX=/srv/aoc")
+///     .arg("wine");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WineConfig {
+    binary: PathBuf,
+    args: Vec<OsString>,
+}
+
+impl WineConfig {
+    /// Start Wine from the given program, like `wine-staging` or an absolute path.
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            args: vec![],
+        }
+    }
+
+    /// Add an argument that is passed to Wine before the path to dprun.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Get the Wine program.
+    pub fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    /// Get the arguments that are passed to Wine before the path to dprun.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+}
+
+impl Default for WineConfig {
+    fn default() -> Self {
+        Self::new("wine")
+    }
+}
@@ -98,8 +98,8 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    let host_options = host_options.finish();
-    let join_options = join_options.finish();
+    let host_options = host_options.finish()?;
+    let join_options = join_options.finish()?;
 
     let host = run(host_options);
     let join = run(join_options);